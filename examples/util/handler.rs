@@ -1,7 +1,7 @@
 use std::convert::TryInto;
 
 use xim::{AHashMap, Client, ClientError, ClientHandler};
-use xim_parser::{AttributeName, InputStyle, Point};
+use xim_parser::{attrs::AttributeValue, AttributeName, InputStyle, Point};
 
 #[derive(Default)]
 pub struct ExampleHandler {
@@ -28,7 +28,7 @@ impl<C: Client> ClientHandler<C> for ExampleHandler {
         &mut self,
         client: &mut C,
         input_method_id: u16,
-        _attributes: AHashMap<AttributeName, Vec<u8>>,
+        _attributes: AHashMap<AttributeName, AttributeValue>,
     ) -> Result<(), ClientError> {
         let ic_attributes = client
             .build_ic_attributes()
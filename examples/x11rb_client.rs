@@ -1,7 +1,6 @@
 use x11rb::connection::Connection;
 use x11rb::protocol::{xproto::*, Event};
 use xim::{x11rb::X11rbClient, Client};
-use xim_parser::ForwardEventFlag;
 
 use self::handler::ExampleHandler;
 
@@ -52,16 +51,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             continue;
         } else {
             match e {
-                Event::KeyPress(e) | Event::KeyRelease(e) => {
-                    if handler.connected {
-                        log::trace!("Send: {:?}", e);
-                        client.forward_event(
-                            handler.im_id,
-                            handler.ic_id,
-                            ForwardEventFlag::empty(),
-                            &e,
-                        )?;
-                    }
+                Event::KeyPress(e) | Event::KeyRelease(e) if handler.connected => {
+                    log::trace!("Send: {:?}", e);
+                    client.forward_key_event(handler.im_id, handler.ic_id, &e)?;
                 }
                 _ => {}
             }
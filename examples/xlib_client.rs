@@ -1,7 +1,6 @@
 use std::{mem::MaybeUninit, ptr};
 use x11_dl::xlib;
 use xim::{xlib::XlibClient, Client};
-use xim_parser::ForwardEventFlag;
 
 use self::handler::ExampleHandler;
 
@@ -51,15 +50,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 continue;
             } else {
                 match e.get_type() {
-                    xlib::KeyPress | xlib::KeyRelease => {
-                        if handler.connected {
-                            client.forward_event(
-                                handler.im_id,
-                                handler.ic_id,
-                                ForwardEventFlag::empty(),
-                                &e.key,
-                            )?;
-                        }
+                    xlib::KeyPress | xlib::KeyRelease if handler.connected => {
+                        client.forward_key_event(handler.im_id, handler.ic_id, &e.key)?;
                     }
                     _ => {}
                 }
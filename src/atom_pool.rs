@@ -0,0 +1,139 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::hash::Hash;
+
+/// Atoms interned in `init`
+pub(crate) const INITIAL_SIZE: usize = 8;
+/// Atoms interned at most, a full pool reuses the oldest atom of a window
+pub(crate) const MAX_SIZE: usize = INITIAL_SIZE * 4;
+
+pub(crate) fn atom_name(idx: usize) -> String {
+    format!("_XIM_DATA_{}", idx)
+}
+
+/// Property atoms for requests over `TRANSPORT_MAX`
+///
+/// An atom is used again on a window after the peer deleted the property, which is
+/// reported with `PropertyNotify`. When every atom is in use and the pool is full, the
+/// oldest one is reused, requests are appended so the peer reads them in order.
+pub(crate) struct AtomPool<Atom, Window> {
+    atoms: Vec<Atom>,
+    next: usize,
+    /// Properties the peer didn't delete yet, with the serial of their last use
+    in_use: hashbrown::HashMap<(Window, Atom), u64, ahash::RandomState>,
+    serial: u64,
+}
+
+impl<Atom: Copy + Eq + Hash, Window: Copy + Eq + Hash> AtomPool<Atom, Window> {
+    pub fn new(atoms: Vec<Atom>) -> Self {
+        Self {
+            atoms,
+            next: 0,
+            in_use: hashbrown::HashMap::with_hasher(Default::default()),
+            serial: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.atoms.len()
+    }
+
+    fn mark(&mut self, window: Window, atom: Atom) {
+        self.serial += 1;
+        self.in_use.insert((window, atom), self.serial);
+    }
+
+    /// Take an atom for a property of `window`
+    ///
+    /// `None` when every atom is in use and the pool can grow, intern a new one and `push` it.
+    pub fn acquire(&mut self, window: Window) -> Option<Atom> {
+        let len = self.atoms.len();
+
+        for i in 0..len {
+            let idx = (self.next + i) % len;
+            let atom = self.atoms[idx];
+
+            if !self.in_use.contains_key(&(window, atom)) {
+                self.next = (idx + 1) % len;
+                self.mark(window, atom);
+                return Some(atom);
+            }
+        }
+
+        if len < MAX_SIZE {
+            return None;
+        }
+
+        let atom = self
+            .in_use
+            .iter()
+            .filter(|((w, _), _)| *w == window)
+            .min_by_key(|(_, serial)| **serial)
+            .map(|((_, atom), _)| *atom)?;
+        log::warn!("Every property atom is in use, reuse the oldest one");
+        self.mark(window, atom);
+        Some(atom)
+    }
+
+    /// Add a newly interned atom, it is in use for `window`
+    pub fn push(&mut self, window: Window, atom: Atom) {
+        self.atoms.push(atom);
+        self.mark(window, atom);
+    }
+
+    /// Return `false` when `atom` isn't used for `window`
+    pub fn release(&mut self, window: Window, atom: Atom) -> bool {
+        self.in_use.remove(&(window, atom)).is_some()
+    }
+
+    /// Forget properties of a window which is gone
+    pub fn release_window(&mut self, window: Window) {
+        self.in_use.retain(|(w, _), _| *w != window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AtomPool, MAX_SIZE};
+    use alloc::vec;
+
+    #[test]
+    fn rotate_and_reuse() {
+        let mut pool = AtomPool::new(vec![10u32, 11]);
+
+        assert_eq!(pool.acquire(1u32), Some(10));
+        assert_eq!(pool.acquire(1), Some(11));
+        assert_eq!(pool.acquire(1), None);
+        // Other window has its own properties
+        assert_eq!(pool.acquire(2), Some(10));
+
+        assert!(pool.release(1, 11));
+        assert!(!pool.release(1, 11));
+        assert_eq!(pool.acquire(1), Some(11));
+
+        pool.push(1, 12);
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.acquire(1), None);
+
+        pool.release_window(1);
+        assert_eq!(pool.acquire(1), Some(10));
+    }
+
+    #[test]
+    fn full_pool_reuses_oldest() {
+        let mut pool = AtomPool::new((0..MAX_SIZE as u32).collect());
+
+        for atom in 0..MAX_SIZE as u32 {
+            assert_eq!(pool.acquire(1u32), Some(atom));
+        }
+
+        // Never grows past `MAX_SIZE`
+        assert_eq!(pool.acquire(1), Some(0));
+        assert_eq!(pool.acquire(1), Some(1));
+        assert!(pool.release(1, 2));
+        assert_eq!(pool.acquire(1), Some(2));
+        assert_eq!(pool.acquire(1), Some(3));
+        assert_eq!(pool.len(), MAX_SIZE);
+    }
+}
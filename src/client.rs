@@ -3,13 +3,17 @@ mod attribute_builder;
 pub use self::attribute_builder::AttributeBuilder;
 use crate::AHashMap;
 use xim_parser::{
-    Attr, Attribute, AttributeName, CaretDirection, CaretStyle, CommitData, Extension, Feedback,
-    ForwardEventFlag, PreeditDrawStatus, Request,
+    attrs::AttributeValue, Attr, Attribute, AttributeName, CaretDirection, CaretStyle, CommitData,
+    Endian, Extension, Feedback, ForwardEventFlag, PreeditDrawStatus, Request, StatusContent,
+    TriggerKey, TriggerNotifyFlag,
 };
 
+use xim_ctext::TextEncoding;
+
+use alloc::collections::VecDeque;
 use alloc::string::String;
-use alloc::vec;
 use alloc::vec::Vec;
+use core::convert::TryFrom;
 use core::fmt;
 
 #[derive(Debug)]
@@ -20,6 +24,11 @@ pub enum ClientError {
     UnsupportedTransport,
     InvalidReply,
     NoXimServer,
+    /// String from the server can't be decoded, with its raw bytes
+    ///
+    /// Returned by the default [`ClientHandler::handle_decode_error`], override it to return
+    /// `Ok(())` to get lossy text with U+FFFD instead.
+    DecodeText(xim_ctext::DecodeError, Vec<u8>),
     #[cfg(feature = "std")]
     Other(alloc::boxed::Box<dyn std::error::Error + Send + Sync>),
 }
@@ -40,6 +49,7 @@ impl fmt::Display for ClientError {
             ClientError::UnsupportedTransport => write!(f, "Server Transport is not supported"),
             ClientError::InvalidReply => write!(f, "Invalid reply from server"),
             ClientError::NoXimServer => write!(f, "Can't connect xim server"),
+            ClientError::DecodeText(e, _) => write!(f, "Can't decode compound text: {}", e),
             #[cfg(feature = "std")]
             ClientError::Other(e) => write!(f, "Other error: {}", e),
         }
@@ -49,6 +59,170 @@ impl fmt::Display for ClientError {
 #[cfg(feature = "std")]
 impl std::error::Error for ClientError {}
 
+/// Trigger keys registered by server for dynamic event flow
+#[derive(Clone, Debug, Default)]
+pub struct TriggerKeys {
+    on_keys: Vec<TriggerKey>,
+    off_keys: Vec<TriggerKey>,
+    /// Input contexts turned on by one of `on_keys`
+    enabled_ics: Vec<u16>,
+}
+
+impl TriggerKeys {
+    pub fn new(on_keys: Vec<TriggerKey>, off_keys: Vec<TriggerKey>) -> Self {
+        Self {
+            on_keys,
+            off_keys,
+            enabled_ics: Vec::new(),
+        }
+    }
+}
+
+const KEY_PRESS_MASK: u32 = 1 << 0;
+const KEY_RELEASE_MASK: u32 = 1 << 1;
+const KEY_EVENT_MASK: u32 = KEY_PRESS_MASK | KEY_RELEASE_MASK;
+
+/// X event mask bit for `xev`
+fn event_mask(xev: &xim_parser::XEvent) -> u32 {
+    // Highest bit is set for events from SendEvent
+    match xev.response_type & 0x7f {
+        2 => KEY_PRESS_MASK,
+        3 => KEY_RELEASE_MASK,
+        _ => 0,
+    }
+}
+
+/// Event masks of an input context and its synchronous `ForwardEvent` state
+#[derive(Clone, Debug)]
+pub struct InputContextState {
+    forward_event_mask: u32,
+    synchronous_event_mask: u32,
+    /// Waiting `SyncReply` for a synchronous `ForwardEvent`
+    sync_pending: bool,
+    /// Events forwarded while `sync_pending` is set
+    queued_events: VecDeque<(ForwardEventFlag, xim_parser::XEvent)>,
+}
+
+impl Default for InputContextState {
+    fn default() -> Self {
+        // Until server sends `SetEventMask` every key event is forwarded synchronously
+        Self {
+            forward_event_mask: KEY_EVENT_MASK,
+            synchronous_event_mask: KEY_EVENT_MASK,
+            sync_pending: false,
+            queued_events: VecDeque::new(),
+        }
+    }
+}
+
+/// Input method and input context state shared by every client backend
+#[derive(Clone, Debug, Default)]
+pub struct ClientState {
+    /// Trigger keys for each input method using dynamic event flow
+    pub trigger_keys: AHashMap<u16, TriggerKeys>,
+    /// Encoding negotiated for each input method, `COMPOUND_TEXT` when missing
+    pub encodings: AHashMap<u16, TextEncoding>,
+    /// Charset offered in `EncodingNegotiation` by name and as a detailed info
+    ///
+    /// `open` sets it to the charset of its locale unless it's already set.
+    pub locale_encoding: Option<TextEncoding>,
+    /// State of each `(input_method_id, input_context_id)`
+    pub ic_states: AHashMap<(u16, u16), InputContextState>,
+}
+
+/// Decode `attributes` with types from `attr_map` that server sent in `OpenReply`
+fn read_attributes(
+    attr_map: &AHashMap<AttributeName, Attr>,
+    attributes: Vec<Attribute>,
+    endian: Endian,
+) -> Result<AHashMap<AttributeName, AttributeValue>, ClientError> {
+    let lookup = |id| attr_map.values().find(|attr| attr.id == id).cloned();
+
+    attributes
+        .into_iter()
+        .filter_map(|attribute| {
+            let attr = lookup(attribute.id)?;
+            Some(
+                AttributeValue::read(attr.ty, &attribute.value, endian, &lookup)
+                    .map(|value| (attr.name, value))
+                    .map_err(ClientError::from),
+            )
+        })
+        .collect()
+}
+
+/// Send events queued while synchronous `ForwardEvent` was waiting `SyncReply`
+fn send_queued_events<C: ClientCore>(
+    client: &mut C,
+    input_method_id: u16,
+    input_context_id: u16,
+) -> Result<(), ClientError> {
+    loop {
+        let (flag, xev) = match client
+            .state_mut()
+            .ic_states
+            .get_mut(&(input_method_id, input_context_id))
+        {
+            Some(state) if !state.sync_pending => match state.queued_events.pop_front() {
+                Some((flag, xev)) => {
+                    state.sync_pending = flag.contains(ForwardEventFlag::SYNCHRONOUS);
+                    (flag, xev)
+                }
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+
+        client.send_req(Request::ForwardEvent {
+            input_method_id,
+            input_context_id,
+            flag,
+            serial_number: xev.sequence,
+            xev,
+        })?;
+    }
+}
+
+/// Encodings sent in `EncodingNegotiation` by preference, the locale charset follows them
+const ENCODINGS: &[TextEncoding] = &[TextEncoding::Utf8String, TextEncoding::CompoundText];
+
+/// Names offered in `EncodingNegotiation`, detailed infos only have the locale charset
+fn offered_encodings<C: ClientCore>(client: &C) -> Vec<TextEncoding> {
+    let mut encodings = ENCODINGS.to_vec();
+    encodings.extend(
+        client
+            .state()
+            .locale_encoding
+            .filter(|encoding| !ENCODINGS.contains(encoding)),
+    );
+    encodings
+}
+
+/// Decode string from the server with the encoding negotiated for `input_method_id`,
+/// bad sequences become U+FFFD if `handler` accepts them
+fn decode_text<C: ClientCore>(
+    client: &mut C,
+    handler: &mut impl ClientHandler<C>,
+    input_method_id: u16,
+    bytes: &[u8],
+) -> Result<String, ClientError> {
+    let encoding = client
+        .state()
+        .encodings
+        .get(&input_method_id)
+        .copied()
+        .unwrap_or_default();
+
+    match encoding.decode(bytes) {
+        Ok(text) => Ok(text),
+        Err(e) => {
+            log::warn!("Can't decode {}: {}", encoding.name(), e);
+            handler.handle_decode_error(client, e, bytes)?;
+            Ok(encoding.decode_lossy(bytes))
+        }
+    }
+}
+
 pub fn handle_request<C: ClientCore>(
     client: &mut C,
     handler: &mut impl ClientHandler<C>,
@@ -75,16 +249,45 @@ pub fn handle_request<C: ClientCore>(
             client.set_attrs(im_attrs, ic_attrs);
             // Require for uim
             client.send_req(Request::EncodingNegotiation {
-                encodings: vec!["COMPOUND_TEXT".into()],
-                encoding_infos: vec![],
+                encodings: offered_encodings(client)
+                    .iter()
+                    .map(|e| e.name().into())
+                    .collect(),
+                encoding_infos: client
+                    .state()
+                    .locale_encoding
+                    .map(|e| e.info())
+                    .into_iter()
+                    .collect(),
                 input_method_id,
             })
         }
         Request::EncodingNegotiationReply {
             input_method_id,
-            index: _,
-            category: _,
-        } => handler.handle_open(client, input_method_id),
+            index,
+            category,
+        } => {
+            // Category 0 is an index of names and 1 of detailed infos, -1 means server's default
+            let encoding = match (category, usize::try_from(index)) {
+                (0, Ok(index)) => offered_encodings(client)
+                    .get(index)
+                    .copied()
+                    .ok_or(ClientError::InvalidReply)?,
+                (1, Ok(index)) => client
+                    .state()
+                    .locale_encoding
+                    .into_iter()
+                    .nth(index)
+                    .ok_or(ClientError::InvalidReply)?,
+                _ => TextEncoding::CompoundText,
+            };
+            log::debug!("Negotiated encoding: {}", encoding.name());
+            client
+                .state_mut()
+                .encodings
+                .insert(input_method_id, encoding);
+            handler.handle_open(client, input_method_id)
+        }
         Request::QueryExtensionReply {
             input_method_id: _,
             extensions,
@@ -92,20 +295,20 @@ pub fn handle_request<C: ClientCore>(
         Request::GetImValuesReply {
             input_method_id,
             im_attributes,
-        } => handler.handle_get_im_values(
-            client,
-            input_method_id,
-            im_attributes
-                .into_iter()
-                .filter_map(|attr| {
-                    client
-                        .im_attributes()
-                        .iter()
-                        .find(|(_, v)| **v == attr.id)
-                        .map(|(n, _)| (*n, attr.value))
-                })
-                .collect(),
-        ),
+        } => {
+            let attributes =
+                read_attributes(client.im_attributes(), im_attributes, client.endian())?;
+            handler.handle_get_im_values(client, input_method_id, attributes)
+        }
+        Request::GetIcValuesReply {
+            input_method_id,
+            input_context_id,
+            ic_attributes,
+        } => {
+            let attributes =
+                read_attributes(client.ic_attributes(), ic_attributes, client.endian())?;
+            handler.handle_get_ic_values(client, input_method_id, input_context_id, attributes)
+        }
         Request::SetIcValuesReply {
             input_method_id,
             input_context_id,
@@ -113,20 +316,66 @@ pub fn handle_request<C: ClientCore>(
         Request::CreateIcReply {
             input_method_id,
             input_context_id,
-        } => handler.handle_create_ic(client, input_method_id, input_context_id),
+        } => {
+            client
+                .state_mut()
+                .ic_states
+                .insert((input_method_id, input_context_id), Default::default());
+            handler.handle_create_ic(client, input_method_id, input_context_id)
+        }
         Request::SetEventMask {
             input_method_id,
             input_context_id,
             forward_event_mask,
             synchronous_event_mask,
-        } => handler.handle_set_event_mask(
-            client,
+        } => {
+            let state = client
+                .state_mut()
+                .ic_states
+                .entry((input_method_id, input_context_id))
+                .or_default();
+            state.forward_event_mask = forward_event_mask;
+            state.synchronous_event_mask = synchronous_event_mask;
+            handler.handle_set_event_mask(
+                client,
+                input_method_id,
+                input_context_id,
+                forward_event_mask,
+                synchronous_event_mask,
+            )
+        }
+        Request::CloseReply { input_method_id } => {
+            client.state_mut().trigger_keys.remove(&input_method_id);
+            client.state_mut().encodings.remove(&input_method_id);
+            client
+                .state_mut()
+                .ic_states
+                .retain(|(im_id, _), _| *im_id != input_method_id);
+            handler.handle_close(client, input_method_id)
+        }
+        Request::RegisterTriggerKeys {
+            input_method_id,
+            on_keys,
+            off_keys,
+        } => {
+            client
+                .state_mut()
+                .trigger_keys
+                .insert(input_method_id, TriggerKeys::new(on_keys, off_keys));
+            Ok(())
+        }
+        Request::TriggerNotifyReply { .. } => {
+            // Nothing to do
+            Ok(())
+        }
+        Request::ResetIcReply {
             input_method_id,
             input_context_id,
-            forward_event_mask,
-            synchronous_event_mask,
-        ),
-        Request::CloseReply { input_method_id } => handler.handle_close(client, input_method_id),
+            preedit_string,
+        } => {
+            let preedit_string = decode_text(client, handler, input_method_id, &preedit_string)?;
+            handler.handle_reset_ic(client, input_method_id, input_context_id, &preedit_string)
+        }
         Request::DisconnectReply {} => {
             handler.handle_disconnect();
             Ok(())
@@ -160,36 +409,51 @@ pub fn handle_request<C: ClientCore>(
             input_method_id,
             input_context_id,
             data,
-        } => match data {
-            CommitData::Keysym { keysym: _, .. } => {
-                log::warn!("Keysym commit is not supported");
-                Ok(())
-            }
-            CommitData::Chars {
-                commited,
-                syncronous,
-            } => {
-                handler.handle_commit(
-                    client,
-                    input_method_id,
-                    input_context_id,
-                    &xim_ctext::compound_text_to_utf8(&commited).expect("Encoding Error"),
-                )?;
-
-                if syncronous {
-                    client.send_req(Request::SyncReply {
+        } => {
+            let syncronous = match data {
+                CommitData::Keysym { keysym, syncronous } => {
+                    handler.handle_commit_keysym(
+                        client,
+                        input_method_id,
+                        input_context_id,
+                        keysym,
+                    )?;
+                    syncronous
+                }
+                CommitData::Chars {
+                    commited,
+                    syncronous,
+                } => {
+                    let commited = decode_text(client, handler, input_method_id, &commited)?;
+                    handler.handle_commit(client, input_method_id, input_context_id, &commited)?;
+                    syncronous
+                }
+                CommitData::Both {
+                    keysym,
+                    commited,
+                    syncronous,
+                } => {
+                    handler.handle_commit_keysym(
+                        client,
                         input_method_id,
                         input_context_id,
-                    })?;
+                        keysym,
+                    )?;
+                    let commited = decode_text(client, handler, input_method_id, &commited)?;
+                    handler.handle_commit(client, input_method_id, input_context_id, &commited)?;
+                    syncronous
                 }
+            };
 
-                Ok(())
-            }
-            CommitData::Both { .. } => {
-                log::warn!("Both commit data is not supported");
-                Ok(())
+            if syncronous {
+                client.send_req(Request::SyncReply {
+                    input_method_id,
+                    input_context_id,
+                })?;
             }
-        },
+
+            Ok(())
+        }
         Request::Sync {
             input_method_id,
             input_context_id,
@@ -197,9 +461,19 @@ pub fn handle_request<C: ClientCore>(
             input_method_id,
             input_context_id,
         }),
-        Request::SyncReply { .. } => {
-            // Nothing to do
-            Ok(())
+        Request::SyncReply {
+            input_method_id,
+            input_context_id,
+        } => {
+            if let Some(state) = client
+                .state_mut()
+                .ic_states
+                .get_mut(&(input_method_id, input_context_id))
+            {
+                state.sync_pending = false;
+            }
+
+            send_queued_events(client, input_method_id, input_context_id)
         }
         Request::PreeditStart {
             input_method_id,
@@ -219,7 +493,7 @@ pub fn handle_request<C: ClientCore>(
             status,
             feedbacks,
         } => {
-            let preedit_string = xim_ctext::compound_text_to_utf8(&preedit_string).unwrap();
+            let preedit_string = decode_text(client, handler, input_method_id, &preedit_string)?;
             handler.handle_preedit_draw(
                 client,
                 input_method_id,
@@ -256,6 +530,35 @@ pub fn handle_request<C: ClientCore>(
                 position,
             })
         }
+        Request::StatusStart {
+            input_method_id,
+            input_context_id,
+        } => handler.handle_status_start(client, input_method_id, input_context_id),
+        Request::StatusDone {
+            input_method_id,
+            input_context_id,
+        } => handler.handle_status_done(client, input_method_id, input_context_id),
+        Request::StatusDraw {
+            input_method_id,
+            input_context_id,
+            content,
+        } => match content {
+            StatusContent::Text(content) => {
+                let status_string =
+                    decode_text(client, handler, input_method_id, &content.status_string)?;
+                handler.handle_status_draw(
+                    client,
+                    input_method_id,
+                    input_context_id,
+                    content.status,
+                    &status_string,
+                    content.feedbacks,
+                )
+            }
+            StatusContent::Pixmap(pixmap) => {
+                handler.handle_status_draw_pixmap(client, input_method_id, input_context_id, pixmap)
+            }
+        },
         _ => {
             log::warn!("Unknown request {:?}", req);
             Ok(())
@@ -266,9 +569,13 @@ pub fn handle_request<C: ClientCore>(
 pub trait ClientCore {
     type XEvent;
 
+    /// Byte order used for the whole session, sent to the server in `Connect`
+    fn endian(&self) -> Endian;
     fn set_attrs(&mut self, ic_attrs: Vec<Attr>, im_attrs: Vec<Attr>);
-    fn ic_attributes(&self) -> &AHashMap<AttributeName, u16>;
-    fn im_attributes(&self) -> &AHashMap<AttributeName, u16>;
+    fn ic_attributes(&self) -> &AHashMap<AttributeName, Attr>;
+    fn im_attributes(&self) -> &AHashMap<AttributeName, Attr>;
+    fn state(&self) -> &ClientState;
+    fn state_mut(&mut self) -> &mut ClientState;
     fn serialize_event(&self, xev: &Self::XEvent) -> xim_parser::XEvent;
     fn deserialize_event(&self, xev: &xim_parser::XEvent) -> Self::XEvent;
     fn send_req(&mut self, req: Request) -> Result<(), ClientError>;
@@ -277,8 +584,8 @@ pub trait ClientCore {
 pub trait Client {
     type XEvent;
 
-    fn build_ic_attributes(&self) -> AttributeBuilder;
-    fn build_im_attributes(&self) -> AttributeBuilder;
+    fn build_ic_attributes(&self) -> AttributeBuilder<'_>;
+    fn build_im_attributes(&self) -> AttributeBuilder<'_>;
 
     fn disconnect(&mut self) -> Result<(), ClientError>;
     fn open(&mut self, locale: &str) -> Result<(), ClientError>;
@@ -293,6 +600,12 @@ pub trait Client {
         input_method_id: u16,
         names: &[AttributeName],
     ) -> Result<(), ClientError>;
+    fn get_ic_values(
+        &mut self,
+        input_method_id: u16,
+        input_context_id: u16,
+        names: &[AttributeName],
+    ) -> Result<(), ClientError>;
     fn set_ic_values(
         &mut self,
         input_method_id: u16,
@@ -316,6 +629,36 @@ pub trait Client {
         flag: ForwardEventFlag,
         xev: &Self::XEvent,
     ) -> Result<(), ClientError>;
+    /// Return `true` when server asked for `xev` with `SetEventMask`
+    fn should_forward(
+        &self,
+        input_method_id: u16,
+        input_context_id: u16,
+        xev: &Self::XEvent,
+    ) -> bool;
+    /// Forward `xev` when `should_forward` returns `true`, synchronously if server asked for it
+    ///
+    /// Return `true` when event is forwarded
+    fn forward_key_event(
+        &mut self,
+        input_method_id: u16,
+        input_context_id: u16,
+        xev: &Self::XEvent,
+    ) -> Result<bool, ClientError>;
+    /// Send `TriggerNotify` when `keysym` with modifier `state` is a trigger key
+    /// registered by server
+    ///
+    /// Return `true` when key is consumed as trigger key and shouldn't be forwarded
+    fn filter_trigger_key(
+        &mut self,
+        input_method_id: u16,
+        input_context_id: u16,
+        keysym: u32,
+        state: u32,
+    ) -> Result<bool, ClientError>;
+    /// Return `false` while server uses dynamic event flow and input context
+    /// isn't turned on by a trigger key
+    fn is_ic_enabled(&self, input_method_id: u16, input_context_id: u16) -> bool;
     fn set_focus(&mut self, input_method_id: u16, input_context_id: u16)
         -> Result<(), ClientError>;
     fn unset_focus(
@@ -323,6 +666,8 @@ pub trait Client {
         input_method_id: u16,
         input_context_id: u16,
     ) -> Result<(), ClientError>;
+    /// Ask server to reset, the preedit string is returned to `handle_reset_ic`
+    fn reset_ic(&mut self, input_method_id: u16, input_context_id: u16) -> Result<(), ClientError>;
 }
 
 impl<C> Client for C
@@ -331,15 +676,19 @@ where
 {
     type XEvent = C::XEvent;
 
-    fn build_ic_attributes(&self) -> AttributeBuilder {
-        AttributeBuilder::new(self.ic_attributes())
+    fn build_ic_attributes(&self) -> AttributeBuilder<'_> {
+        AttributeBuilder::new(self.ic_attributes(), self.endian())
     }
 
-    fn build_im_attributes(&self) -> AttributeBuilder {
-        AttributeBuilder::new(self.im_attributes())
+    fn build_im_attributes(&self) -> AttributeBuilder<'_> {
+        AttributeBuilder::new(self.im_attributes(), self.endian())
     }
 
     fn open(&mut self, locale: &str) -> Result<(), ClientError> {
+        if self.state().locale_encoding.is_none() {
+            self.state_mut().locale_encoding = TextEncoding::from_name(locale);
+        }
+
         self.send_req(Request::Open {
             locale: locale.into(),
         })
@@ -365,7 +714,23 @@ where
             input_method_id,
             im_attributes: names
                 .iter()
-                .filter_map(|name| self.im_attributes().get(name).copied())
+                .filter_map(|name| self.im_attributes().get(name).map(|attr| attr.id))
+                .collect(),
+        })
+    }
+
+    fn get_ic_values(
+        &mut self,
+        input_method_id: u16,
+        input_context_id: u16,
+        names: &[AttributeName],
+    ) -> Result<(), ClientError> {
+        self.send_req(Request::GetIcValues {
+            input_method_id,
+            input_context_id,
+            ic_attributes: names
+                .iter()
+                .filter_map(|name| self.ic_attributes().get(name).map(|attr| attr.id))
                 .collect(),
         })
     }
@@ -402,6 +767,21 @@ where
         xev: &Self::XEvent,
     ) -> Result<(), ClientError> {
         let ev = self.serialize_event(xev);
+
+        // Further events wait until server answers synchronous event
+        if let Some(state) = self
+            .state_mut()
+            .ic_states
+            .get_mut(&(input_method_id, input_context_id))
+        {
+            if state.sync_pending {
+                state.queued_events.push_back((flag, ev));
+                return Ok(());
+            }
+
+            state.sync_pending = flag.contains(ForwardEventFlag::SYNCHRONOUS);
+        }
+
         self.send_req(Request::ForwardEvent {
             input_method_id,
             input_context_id,
@@ -411,6 +791,105 @@ where
         })
     }
 
+    fn should_forward(
+        &self,
+        input_method_id: u16,
+        input_context_id: u16,
+        xev: &Self::XEvent,
+    ) -> bool {
+        if !self.is_ic_enabled(input_method_id, input_context_id) {
+            return false;
+        }
+
+        let mask = event_mask(&self.serialize_event(xev));
+
+        self.state()
+            .ic_states
+            .get(&(input_method_id, input_context_id))
+            .map_or(KEY_EVENT_MASK, |state| state.forward_event_mask)
+            & mask
+            != 0
+    }
+
+    fn forward_key_event(
+        &mut self,
+        input_method_id: u16,
+        input_context_id: u16,
+        xev: &Self::XEvent,
+    ) -> Result<bool, ClientError> {
+        if !self.should_forward(input_method_id, input_context_id, xev) {
+            return Ok(false);
+        }
+
+        let mask = event_mask(&self.serialize_event(xev));
+        let synchronous_event_mask = self
+            .state()
+            .ic_states
+            .get(&(input_method_id, input_context_id))
+            .map_or(KEY_EVENT_MASK, |state| state.synchronous_event_mask);
+
+        let flag = if synchronous_event_mask & mask != 0 {
+            ForwardEventFlag::SYNCHRONOUS
+        } else {
+            ForwardEventFlag::empty()
+        };
+
+        self.forward_event(input_method_id, input_context_id, flag, xev)?;
+
+        Ok(true)
+    }
+
+    fn filter_trigger_key(
+        &mut self,
+        input_method_id: u16,
+        input_context_id: u16,
+        keysym: u32,
+        state: u32,
+    ) -> Result<bool, ClientError> {
+        let keys = match self.state_mut().trigger_keys.get_mut(&input_method_id) {
+            Some(keys) => keys,
+            None => return Ok(false),
+        };
+
+        let enabled = keys.enabled_ics.contains(&input_context_id);
+        let (flag, list) = if enabled {
+            (TriggerNotifyFlag::OffKeyList, &keys.off_keys)
+        } else {
+            (TriggerNotifyFlag::OnKeyList, &keys.on_keys)
+        };
+
+        let index = match list
+            .iter()
+            .position(|key| key.keysym == keysym && state & key.modifier_mask == key.modifier)
+        {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+
+        if enabled {
+            keys.enabled_ics.retain(|id| *id != input_context_id);
+        } else {
+            keys.enabled_ics.push(input_context_id);
+        }
+
+        self.send_req(Request::TriggerNotify {
+            input_method_id,
+            input_context_id,
+            flag,
+            index: index as u32,
+            event_mask: KEY_EVENT_MASK,
+        })?;
+
+        Ok(true)
+    }
+
+    fn is_ic_enabled(&self, input_method_id: u16, input_context_id: u16) -> bool {
+        self.state()
+            .trigger_keys
+            .get(&input_method_id)
+            .map_or(true, |keys| keys.enabled_ics.contains(&input_context_id))
+    }
+
     fn disconnect(&mut self) -> Result<(), ClientError> {
         self.send_req(Request::Disconnect {})
     }
@@ -424,6 +903,13 @@ where
         input_method_id: u16,
         input_context_id: u16,
     ) -> Result<(), ClientError> {
+        if let Some(keys) = self.state_mut().trigger_keys.get_mut(&input_method_id) {
+            keys.enabled_ics.retain(|id| *id != input_context_id);
+        }
+        self.state_mut()
+            .ic_states
+            .remove(&(input_method_id, input_context_id));
+
         self.send_req(Request::DestroyIc {
             input_method_id,
             input_context_id,
@@ -450,6 +936,12 @@ where
             input_context_id,
         })
     }
+    fn reset_ic(&mut self, input_method_id: u16, input_context_id: u16) -> Result<(), ClientError> {
+        self.send_req(Request::ResetIc {
+            input_method_id,
+            input_context_id,
+        })
+    }
 }
 
 #[allow(unused_variables)]
@@ -475,7 +967,16 @@ pub trait ClientHandler<C: Client> {
         &mut self,
         client: &mut C,
         input_method_id: u16,
-        attributes: AHashMap<AttributeName, Vec<u8>>,
+        attributes: AHashMap<AttributeName, AttributeValue>,
+    ) -> Result<(), ClientError> {
+        Ok(())
+    }
+    fn handle_get_ic_values(
+        &mut self,
+        client: &mut C,
+        input_method_id: u16,
+        input_context_id: u16,
+        attributes: AHashMap<AttributeName, AttributeValue>,
     ) -> Result<(), ClientError> {
         Ok(())
     }
@@ -512,6 +1013,16 @@ pub trait ClientHandler<C: Client> {
     ) -> Result<(), ClientError> {
         Ok(())
     }
+    /// Called for `XLookupKeySym` commits, and before `handle_commit` for `XLookupBoth`
+    fn handle_commit_keysym(
+        &mut self,
+        client: &mut C,
+        input_method_id: u16,
+        input_context_id: u16,
+        keysym: u32,
+    ) -> Result<(), ClientError> {
+        Ok(())
+    }
     fn handle_forward_event(
         &mut self,
         client: &mut C,
@@ -573,4 +1084,61 @@ pub trait ClientHandler<C: Client> {
     ) -> Result<(), ClientError> {
         Ok(())
     }
+    fn handle_status_start(
+        &mut self,
+        client: &mut C,
+        input_method_id: u16,
+        input_context_id: u16,
+    ) -> Result<(), ClientError> {
+        Ok(())
+    }
+    fn handle_status_draw(
+        &mut self,
+        client: &mut C,
+        input_method_id: u16,
+        input_context_id: u16,
+        status: PreeditDrawStatus,
+        status_string: &str,
+        feedbacks: Vec<Feedback>,
+    ) -> Result<(), ClientError> {
+        Ok(())
+    }
+    fn handle_status_draw_pixmap(
+        &mut self,
+        client: &mut C,
+        input_method_id: u16,
+        input_context_id: u16,
+        pixmap: u32,
+    ) -> Result<(), ClientError> {
+        Ok(())
+    }
+    fn handle_status_done(
+        &mut self,
+        client: &mut C,
+        input_method_id: u16,
+        input_context_id: u16,
+    ) -> Result<(), ClientError> {
+        Ok(())
+    }
+    fn handle_reset_ic(
+        &mut self,
+        client: &mut C,
+        input_method_id: u16,
+        input_context_id: u16,
+        preedit_string: &str,
+    ) -> Result<(), ClientError> {
+        Ok(())
+    }
+    /// Called when a string from the server can't be decoded
+    ///
+    /// Fails with `ClientError::DecodeText` by default, return `Ok(())` to deliver the string
+    /// with U+FFFD in place of bad sequences instead.
+    fn handle_decode_error(
+        &mut self,
+        client: &mut C,
+        error: xim_ctext::DecodeError,
+        bytes: &[u8],
+    ) -> Result<(), ClientError> {
+        Err(ClientError::DecodeText(error, bytes.to_vec()))
+    }
 }
@@ -1,20 +1,21 @@
 use crate::AHashMap;
 use alloc::vec::Vec;
-use xim_parser::{Attribute, AttributeName, XimWrite};
+use xim_parser::{Attr, Attribute, AttributeName, Endian, XimWrite};
 
 pub struct NestedListBuilder<'a> {
-    id_map: &'a AHashMap<AttributeName, u16>,
+    id_map: &'a AHashMap<AttributeName, Attr>,
+    endian: Endian,
     out: &'a mut Vec<u8>,
 }
 
 impl<'a> NestedListBuilder<'a> {
     pub fn push<V: XimWrite>(self, name: AttributeName, value: V) -> Self {
-        if let Some(id) = self.id_map.get(&name).copied() {
+        if let Some(id) = self.id_map.get(&name).map(|attr| attr.id) {
             let attr = Attribute {
                 id,
-                value: xim_parser::write_to_vec(value),
+                value: xim_parser::write_to_vec_with_endian(value, self.endian),
             };
-            xim_parser::write_extend_vec(attr, self.out);
+            xim_parser::write_extend_vec_with_endian(attr, self.out, self.endian);
         }
 
         self
@@ -22,23 +23,25 @@ impl<'a> NestedListBuilder<'a> {
 }
 
 pub struct AttributeBuilder<'a> {
-    id_map: &'a AHashMap<AttributeName, u16>,
+    id_map: &'a AHashMap<AttributeName, Attr>,
+    endian: Endian,
     out: Vec<Attribute>,
 }
 
 impl<'a> AttributeBuilder<'a> {
-    pub(crate) fn new(id_map: &'a AHashMap<AttributeName, u16>) -> Self {
+    pub(crate) fn new(id_map: &'a AHashMap<AttributeName, Attr>, endian: Endian) -> Self {
         Self {
             id_map,
+            endian,
             out: Vec::new(),
         }
     }
 
     pub fn push<V: XimWrite>(mut self, name: AttributeName, value: V) -> Self {
-        if let Some(id) = self.id_map.get(&name).copied() {
+        if let Some(id) = self.id_map.get(&name).map(|attr| attr.id) {
             self.out.push(Attribute {
                 id,
-                value: xim_parser::write_to_vec(value),
+                value: xim_parser::write_to_vec_with_endian(value, self.endian),
             });
         }
 
@@ -46,10 +49,11 @@ impl<'a> AttributeBuilder<'a> {
     }
 
     pub fn nested_list(mut self, name: AttributeName, f: impl FnOnce(NestedListBuilder)) -> Self {
-        if let Some(id) = self.id_map.get(&name).copied() {
+        if let Some(id) = self.id_map.get(&name).map(|attr| attr.id) {
             let mut value = Vec::new();
             f(NestedListBuilder {
                 id_map: self.id_map,
+                endian: self.endian,
                 out: &mut value,
             });
             self.out.push(Attribute { id, value });
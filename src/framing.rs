@@ -0,0 +1,214 @@
+//! Framing of requests over the X transport, shared by every backend.
+//!
+//! Nothing here talks to X, backends only issue the calls.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::hash::Hash;
+use core::iter::Peekable;
+use core::slice::ChunksExact;
+use xim_parser::ReadError;
+
+use crate::atom_pool::{self, AtomPool};
+use crate::Atoms;
+
+/// Longest request joined from `_XIM_MOREDATA` fragments
+///
+/// Longer requests have to be sent with a property.
+pub(crate) const MAX_FRAGMENTS_LEN: usize = 64 * 1024;
+
+/// `_XIM_MOREDATA` fragments waiting for their `_XIM_PROTOCOL`
+#[derive(Default)]
+pub(crate) struct Fragments {
+    data: Vec<u8>,
+    overflowed: bool,
+}
+
+impl Fragments {
+    /// Append data of `_XIM_MOREDATA`, it is dropped once the request gets too long
+    pub fn push(&mut self, data: &[u8]) {
+        if self.overflowed {
+            return;
+        }
+
+        if self.data.len() + data.len() > MAX_FRAGMENTS_LEN {
+            log::warn!("Fragments over {} bytes are dropped", MAX_FRAGMENTS_LEN);
+            self.data = Vec::new();
+            self.overflowed = true;
+        } else {
+            self.data.extend_from_slice(data);
+        }
+    }
+
+    /// Join fragments with data of the last `_XIM_PROTOCOL`, fail when they were dropped
+    pub fn finish(&mut self, data: &[u8]) -> Result<Vec<u8>, ReadError> {
+        let mut ret = core::mem::take(&mut self.data);
+
+        if core::mem::take(&mut self.overflowed) {
+            return Err(ReadError::InvalidData(
+                "Request",
+                format!("longer than {} bytes", MAX_FRAGMENTS_LEN),
+            ));
+        }
+
+        ret.extend_from_slice(data);
+        Ok(ret)
+    }
+}
+
+/// Data of a format 8 `ClientMessage`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Fragment {
+    /// `_XIM_MOREDATA`, more fragments follow
+    MoreData([u8; 20]),
+    /// `_XIM_PROTOCOL`, the last fragment of a request
+    Protocol([u8; 20]),
+}
+
+#[allow(unused)]
+impl Fragment {
+    pub fn data(&self) -> [u8; 20] {
+        match self {
+            Fragment::MoreData(data) | Fragment::Protocol(data) => *data,
+        }
+    }
+
+    pub fn message_type<Atom: Copy>(&self, atoms: &Atoms<Atom>) -> Atom {
+        match self {
+            Fragment::MoreData(_) => atoms.XIM_MOREDATA,
+            Fragment::Protocol(_) => atoms.XIM_PROTOCOL,
+        }
+    }
+}
+
+/// Fragments of a request shorter than `TRANSPORT_MAX`
+pub(crate) struct ClientMessages<'a> {
+    chunks: Peekable<ChunksExact<'a, u8>>,
+}
+
+impl<'a> Iterator for ClientMessages<'a> {
+    type Item = Fragment;
+
+    fn next(&mut self) -> Option<Fragment> {
+        let data: [u8; 20] = self.chunks.next()?.try_into().unwrap();
+
+        Some(if self.chunks.peek().is_some() {
+            Fragment::MoreData(data)
+        } else {
+            Fragment::Protocol(data)
+        })
+    }
+}
+
+/// Property a request is appended to
+pub(crate) enum PropertyAtom<Atom> {
+    Acquired(Atom),
+    /// Every atom is waiting for the peer to read it, intern this name and
+    /// [`AtomPool::push`] the atom
+    Intern(String),
+}
+
+/// How a serialized request is sent
+pub(crate) enum Frames<'a, Atom> {
+    ClientMessages(ClientMessages<'a>),
+    /// Append `data` to the property, then send its length and atom in a format 32
+    /// `_XIM_PROTOCOL`
+    Property(PropertyAtom<Atom>, &'a [u8]),
+}
+
+/// Frame serialized request `buf` sent to `target`
+pub(crate) fn frame<'a, Atom: Copy + Eq + Hash, Window: Copy + Eq + Hash>(
+    buf: &'a mut Vec<u8>,
+    transport_max: usize,
+    atom_pool: &mut AtomPool<Atom, Window>,
+    target: Window,
+) -> Frames<'a, Atom> {
+    if buf.len() < transport_max {
+        buf.resize((buf.len() + 19) / 20 * 20, 0);
+        Frames::ClientMessages(ClientMessages {
+            chunks: buf.chunks_exact(20).peekable(),
+        })
+    } else {
+        let atom = match atom_pool.acquire(target) {
+            Some(atom) => PropertyAtom::Acquired(atom),
+            None => PropertyAtom::Intern(atom_pool::atom_name(atom_pool.len())),
+        };
+        Frames::Property(atom, buf)
+    }
+}
+
+/// Split a property read with delete after the request of `length` bytes
+///
+/// Requests appended to a reused atom follow it, they are returned so the reader can put
+/// them back in the property.
+pub(crate) fn split_property(data: &mut Vec<u8>, length: u32) -> Option<Vec<u8>> {
+    let length = length as usize;
+
+    if data.len() > length {
+        Some(data.split_off(length))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        frame, split_property, Fragment, Fragments, Frames, PropertyAtom, MAX_FRAGMENTS_LEN,
+    };
+    use crate::atom_pool::AtomPool;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn fragments_limit() {
+        let mut fragments = Fragments::default();
+
+        fragments.push(&[1; 20]);
+        assert_eq!(fragments.finish(&[2; 20]).unwrap().len(), 40);
+
+        for _ in 0..MAX_FRAGMENTS_LEN / 20 + 1 {
+            fragments.push(&[1; 20]);
+        }
+        assert!(fragments.finish(&[2; 20]).is_err());
+
+        // Next request starts clean
+        assert_eq!(fragments.finish(&[2; 20]).unwrap(), [2; 20]);
+    }
+
+    #[test]
+    fn frames() {
+        let mut pool = AtomPool::new(alloc::vec![10u32]);
+        let mut buf = (0..30).collect::<Vec<u8>>();
+
+        match frame(&mut buf, 40, &mut pool, 1) {
+            Frames::ClientMessages(messages) => {
+                let messages = messages.collect::<Vec<_>>();
+                assert_eq!(messages.len(), 2);
+                assert!(matches!(messages[0], Fragment::MoreData(_)));
+                assert!(matches!(messages[1], Fragment::Protocol(_)));
+                assert_eq!(messages[1].data()[..10], (20..30).collect::<Vec<u8>>()[..]);
+            }
+            _ => panic!("Expected ClientMessages"),
+        }
+
+        let mut buf = alloc::vec![0; 40];
+        assert!(matches!(
+            frame(&mut buf, 40, &mut pool, 1),
+            Frames::Property(PropertyAtom::Acquired(10), data) if data.len() == 40
+        ));
+        assert!(matches!(
+            frame(&mut buf, 40, &mut pool, 1),
+            Frames::Property(PropertyAtom::Intern(_), _)
+        ));
+    }
+
+    #[test]
+    fn split_appended() {
+        let mut data = alloc::vec![1, 2, 3, 4];
+        assert_eq!(split_property(&mut data, 4), None);
+        assert_eq!(split_property(&mut data, 2), Some(alloc::vec![3, 4]));
+        assert_eq!(data, [1, 2]);
+    }
+}
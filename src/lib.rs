@@ -14,8 +14,15 @@
 //!   feature).
 //! - A wrapper around [`x11rb`](x11rb-library), the X rust bindings. See the [`x11rb`] module
 //!   for more information (requires the `x11rb-client` or `x11rb-server` feature).
+//! - An async variant of the [`x11rb`] wrapper on top of `x11rb-async`. See the [`x11rb_async`]
+//!   module for more information (requires the `x11rb-async-client` or `x11rb-async-server`
+//!   feature).
 //! - A wrapper around [`x11-dl`](x11dl-library), the standard X11 library. See the [`xlib`]
 //!   module for more information (requires the `xlib-client` feature).
+//! - TCP and Unix domain socket transports. See the [`socket`] module for more information
+//!   (requires the `socket-client` or `socket-server` feature).
+//! - An in-memory transport connecting a client and a server in one process, for testing.
+//!   See the [`memory`] module for more information (requires the `memory` feature).
 //!
 //! [x11rb-library]: https://crates.io/crates/x11rb
 //! [x11dl-library]: https://crates.io/crates/x11-dl
@@ -30,27 +37,56 @@ extern crate alloc;
 #[cfg(feature = "std")]
 extern crate std;
 
+#[cfg(any(
+    feature = "x11rb-client",
+    feature = "x11rb-server",
+    feature = "xlib-client",
+    feature = "memory"
+))]
+mod atom_pool;
 #[cfg(feature = "client")]
 mod client;
+#[cfg(any(
+    feature = "x11rb-client",
+    feature = "x11rb-server",
+    feature = "xlib-client",
+    feature = "memory"
+))]
+mod framing;
 #[cfg(feature = "server")]
 mod server;
+#[cfg(any(feature = "client", feature = "server"))]
+mod transport;
 
+#[cfg(feature = "memory")]
+pub mod memory;
+#[cfg(any(feature = "socket-server", feature = "socket-client"))]
+pub mod socket;
 #[cfg(any(feature = "x11rb-server", feature = "x11rb-client"))]
 pub mod x11rb;
+#[cfg(any(feature = "x11rb-async-server", feature = "x11rb-async-client"))]
+pub mod x11rb_async;
 #[cfg(feature = "xlib-client")]
 pub mod xlib;
 
 #[cfg(feature = "client")]
 pub use crate::client::{Client, ClientError, ClientHandler};
+#[cfg(feature = "client")]
+pub use xim_ctext::DecodeError;
+
+#[cfg(any(feature = "client", feature = "server"))]
+pub use crate::transport::Transport;
 
 #[cfg(feature = "server")]
 pub const ALL_LOCALES: &str = include_str!("./all_locales.txt");
 
 #[cfg(feature = "server")]
 pub use crate::server::{
-    InputContext, InputMethod, Server, ServerCore, ServerError, ServerHandler, UserInputContext,
-    XimConnection, XimConnections,
+    InputContext, InputMethod, NestedAttributes, Server, ServerCore, ServerError, ServerHandler,
+    UserInputContext, XimConnection, XimConnections,
 };
+#[cfg(feature = "server")]
+pub use xim_ctext::{CjkCharset, EncodeStrategy, TextEncoding};
 pub type AHashMap<K, V> = hashbrown::HashMap<K, V, ahash::RandomState>;
 pub use xim_parser::*;
 
@@ -61,9 +97,21 @@ struct Atoms<Atom> {
     TRANSPORT: Atom,
     XIM_XCONNECT: Atom,
     XIM_PROTOCOL: Atom,
+    XIM_MOREDATA: Atom,
 }
 
 impl<Atom> Atoms<Atom> {
+    /// Names in the order `new` interns them
+    #[allow(unused)]
+    pub const NAMES: [&'static str; 6] = [
+        "XIM_SERVERS",
+        "LOCALES",
+        "TRANSPORT",
+        "_XIM_XCONNECT",
+        "_XIM_PROTOCOL",
+        "_XIM_MOREDATA",
+    ];
+
     #[allow(unused)]
     pub fn new<E, F>(f: F) -> Result<Self, E>
     where
@@ -75,6 +123,7 @@ impl<Atom> Atoms<Atom> {
             TRANSPORT: f("TRANSPORT")?,
             XIM_XCONNECT: f("_XIM_XCONNECT")?,
             XIM_PROTOCOL: f("_XIM_PROTOCOL")?,
+            XIM_MOREDATA: f("_XIM_MOREDATA")?,
         })
     }
 
@@ -89,6 +138,7 @@ impl<Atom> Atoms<Atom> {
             TRANSPORT: f("TRANSPORT\0")?,
             XIM_XCONNECT: f("_XIM_XCONNECT\0")?,
             XIM_PROTOCOL: f("_XIM_PROTOCOL\0")?,
+            XIM_MOREDATA: f("_XIM_MOREDATA\0")?,
         })
     }
 }
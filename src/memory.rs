@@ -0,0 +1,1094 @@
+//! Provides an in-memory transport connecting a client and a server in the same process.
+//!
+//! Requests are serialized and framed like the X transport does it: requests shorter than
+//! `TRANSPORT_MAX` are split into 20 bytes `ClientMessage`s, longer ones are stored in a
+//! property. It is meant for testing whole sessions without an X display.
+//!
+//! Call [`MemoryServer::connect`] for every client, then call `dispatch` on both sides
+//! until neither of them has pending messages.
+
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use crate::atom_pool::{self, AtomPool};
+use crate::client::{
+    handle_request as client_handle_request, ClientCore, ClientError, ClientHandler, ClientState,
+};
+use crate::framing::{self, Fragment, Fragments, Frames, PropertyAtom};
+use crate::server::{ServerCore, ServerError, ServerHandler, XimConnections};
+use crate::AHashMap;
+use xim_parser::{Attr, AttributeName, Endian, ReadError, Request, XEvent, XimWrite};
+
+/// Same `TRANSPORT_MAX` the x11rb server uses
+pub const DEFAULT_TRANSPORT_MAX: usize = 20;
+
+/// A `ClientMessage` of the X transport
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Message {
+    /// `_XIM_MOREDATA` or `_XIM_PROTOCOL` with format 8
+    Fragment(Fragment),
+    /// `_XIM_PROTOCOL` with format 32, the request is stored in the property `atom`
+    Property { length: u32, atom: u32 },
+}
+
+/// Messages and properties sent to one side of a connection
+struct Queue {
+    messages: VecDeque<Message>,
+    properties: AHashMap<u32, Vec<u8>>,
+    fragments: Fragments,
+    atom_pool: AtomPool<u32, u32>,
+}
+
+impl Queue {
+    /// Properties are all on the receiving window
+    const WINDOW: u32 = 0;
+
+    fn new() -> Self {
+        Self {
+            messages: VecDeque::new(),
+            properties: AHashMap::with_hasher(Default::default()),
+            fragments: Fragments::default(),
+            atom_pool: AtomPool::new((0..atom_pool::INITIAL_SIZE as u32).collect()),
+        }
+    }
+
+    fn push(&mut self, buf: &mut Vec<u8>, transport_max: usize) {
+        match framing::frame(buf, transport_max, &mut self.atom_pool, Self::WINDOW) {
+            Frames::ClientMessages(messages) => {
+                self.messages.extend(messages.map(Message::Fragment));
+            }
+            Frames::Property(atom, data) => {
+                let atom = match atom {
+                    PropertyAtom::Acquired(atom) => atom,
+                    PropertyAtom::Intern(name) => {
+                        log::debug!("Intern {}", name);
+                        let atom = self.atom_pool.len() as u32;
+                        self.atom_pool.push(Self::WINDOW, atom);
+                        atom
+                    }
+                };
+                self.properties
+                    .entry(atom)
+                    .or_default()
+                    .extend_from_slice(data);
+                self.messages.push_back(Message::Property {
+                    length: data.len() as u32,
+                    atom,
+                });
+            }
+        }
+        buf.clear();
+    }
+
+    /// Pop messages until a whole request is received
+    fn pop(&mut self) -> Option<Result<Vec<u8>, ReadError>> {
+        loop {
+            match self.messages.pop_front()? {
+                Message::Fragment(Fragment::MoreData(data)) => self.fragments.push(&data),
+                Message::Fragment(Fragment::Protocol(data)) => {
+                    return Some(self.fragments.finish(&data));
+                }
+                Message::Property { length, atom } => {
+                    // Reading deletes the property like `GetProperty` with `delete`,
+                    // requests appended to a reused atom are put back
+                    let mut ret = self.properties.remove(&atom).unwrap_or_default();
+                    self.atom_pool.release(Self::WINDOW, atom);
+                    if let Some(rest) = framing::split_property(&mut ret, length) {
+                        self.properties.insert(atom, rest);
+                    }
+                    return Some(Ok(ret));
+                }
+            }
+        }
+    }
+}
+
+struct Link {
+    client_win: u32,
+    com_win: u32,
+    transport_max: usize,
+    to_server: Queue,
+    to_client: Queue,
+}
+
+fn serialize(req: &Request, endian: Endian, buf: &mut Vec<u8>) {
+    if log::log_enabled!(log::Level::Trace) {
+        log::trace!("->: {:?}", req);
+    } else {
+        log::debug!("->: {}", req.name());
+    }
+    buf.resize(req.size(), 0);
+    xim_parser::write_with_endian(req, buf, endian);
+}
+
+pub struct MemoryServer {
+    links: Vec<Rc<RefCell<Link>>>,
+    next_window: u32,
+    transport_max: usize,
+    buf: Vec<u8>,
+}
+
+impl Default for MemoryServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryServer {
+    pub fn new() -> Self {
+        Self::with_transport_max(DEFAULT_TRANSPORT_MAX)
+    }
+
+    /// Requests shorter than `transport_max` are sent with `ClientMessage`s, others with property
+    pub fn with_transport_max(transport_max: usize) -> Self {
+        Self {
+            links: Vec::new(),
+            next_window: 1,
+            transport_max,
+            buf: Vec::with_capacity(1024),
+        }
+    }
+
+    fn new_window(&mut self) -> u32 {
+        let ret = self.next_window;
+        self.next_window += 1;
+        ret
+    }
+
+    /// Do what `_XIM_XCONNECT` does, the returned client still has to call [`MemoryClient::connect`]
+    pub fn connect<T>(&mut self, connections: &mut XimConnections<T>) -> MemoryClient {
+        let client_win = self.new_window();
+        let com_win = self.new_window();
+        let link = Rc::new(RefCell::new(Link {
+            client_win,
+            com_win,
+            transport_max: self.transport_max,
+            to_server: Queue::new(),
+            to_client: Queue::new(),
+        }));
+
+        connections.new_connection(com_win, client_win);
+        self.links.push(link.clone());
+
+        MemoryClient::new(link)
+    }
+
+    /// Handle every pending request, return `false` when there was nothing to handle
+    ///
+    /// Connections of dropped clients are removed like the client window was destroyed
+    pub fn dispatch<T>(
+        &mut self,
+        connections: &mut XimConnections<T>,
+        handler: &mut impl ServerHandler<Self, InputContextData = T>,
+    ) -> Result<bool, ServerError> {
+        let mut handled = false;
+
+        for link in self.links.clone() {
+            loop {
+                let (com_win, data) = {
+                    let mut link = link.borrow_mut();
+                    match link.to_server.pop() {
+                        Some(data) => (link.com_win, data),
+                        None => break,
+                    }
+                };
+                handled = true;
+
+                let connection = match connections.get_connection(com_win) {
+                    Some(connection) => connection,
+                    None => {
+                        log::warn!("Unknown connection");
+                        break;
+                    }
+                };
+
+                let req =
+                    data.and_then(|data| xim_parser::read_with_endian(&data, connection.endian()));
+                connection.handle_read(self, req, handler)?;
+
+                if connection.disconnected {
+                    self.remove_connection(com_win, connections, handler)?;
+                    break;
+                }
+            }
+        }
+
+        // Only this server holds the link after client is dropped
+        let dropped = self
+            .links
+            .iter()
+            .filter(|link| Rc::strong_count(link) == 1)
+            .map(|link| link.borrow().com_win)
+            .collect::<Vec<_>>();
+
+        for com_win in dropped {
+            log::info!("Client of {} dropped", com_win);
+            self.remove_connection(com_win, connections, handler)?;
+            handled = true;
+        }
+
+        Ok(handled)
+    }
+
+    fn remove_connection<T>(
+        &mut self,
+        com_win: u32,
+        connections: &mut XimConnections<T>,
+        handler: &mut impl ServerHandler<Self, InputContextData = T>,
+    ) -> Result<(), ServerError> {
+        if let Some(mut connection) = connections.remove_connection(com_win) {
+            if !connection.disconnected {
+                connection.disconnect(self, handler)?;
+            }
+        }
+
+        self.links.retain(|link| link.borrow().com_win != com_win);
+
+        Ok(())
+    }
+}
+
+impl ServerCore for MemoryServer {
+    type XEvent = XEvent;
+
+    fn send_req(
+        &mut self,
+        client_win: u32,
+        endian: Endian,
+        req: Request,
+    ) -> Result<(), ServerError> {
+        let link = self
+            .links
+            .iter()
+            .find(|link| link.borrow().client_win == client_win)
+            .ok_or(ServerError::ClientNotExists)?;
+        let mut link = link.borrow_mut();
+        let transport_max = link.transport_max;
+
+        serialize(&req, endian, &mut self.buf);
+        link.to_client.push(&mut self.buf, transport_max);
+
+        Ok(())
+    }
+
+    #[inline]
+    fn deserialize_event(&self, ev: &XEvent) -> Self::XEvent {
+        ev.clone()
+    }
+}
+
+pub struct MemoryClient {
+    link: Rc<RefCell<Link>>,
+    endian: Endian,
+    im_attributes: AHashMap<AttributeName, Attr>,
+    ic_attributes: AHashMap<AttributeName, Attr>,
+    state: ClientState,
+    buf: Vec<u8>,
+}
+
+impl MemoryClient {
+    fn new(link: Rc<RefCell<Link>>) -> Self {
+        Self {
+            link,
+            endian: Endian::NATIVE,
+            im_attributes: AHashMap::with_hasher(Default::default()),
+            ic_attributes: AHashMap::with_hasher(Default::default()),
+            state: ClientState::default(),
+            buf: Vec::with_capacity(1024),
+        }
+    }
+
+    /// Window server sends requests to, use it for `ClientWindow`
+    pub fn client_window(&self) -> u32 {
+        self.link.borrow().client_win
+    }
+
+    /// Send `Connect` with `endian`, call it before any other request
+    pub fn connect(&mut self, endian: Endian) -> Result<(), ClientError> {
+        self.endian = endian;
+        self.send_req(Request::Connect {
+            client_major_protocol_version: 1,
+            client_minor_protocol_version: 0,
+            endian,
+            client_auth_protocol_names: Vec::new(),
+        })
+    }
+
+    /// Handle every pending request, return `false` when there was nothing to handle
+    pub fn dispatch(
+        &mut self,
+        handler: &mut impl ClientHandler<Self>,
+    ) -> Result<bool, ClientError> {
+        let mut handled = false;
+
+        loop {
+            let data = match self.link.borrow_mut().to_client.pop() {
+                Some(data) => data,
+                None => break,
+            };
+            handled = true;
+
+            let req = xim_parser::read_with_endian(&data?, self.endian)?;
+            if let Request::DisconnectReply {} = req {
+                self.link
+                    .borrow_mut()
+                    .to_server
+                    .atom_pool
+                    .release_window(Queue::WINDOW);
+            }
+            client_handle_request(self, handler, req)?;
+        }
+
+        Ok(handled)
+    }
+}
+
+impl ClientCore for MemoryClient {
+    type XEvent = XEvent;
+
+    fn set_attrs(&mut self, im_attrs: Vec<Attr>, ic_attrs: Vec<Attr>) {
+        for im_attr in im_attrs {
+            self.im_attributes.insert(im_attr.name, im_attr);
+        }
+
+        for ic_attr in ic_attrs {
+            self.ic_attributes.insert(ic_attr.name, ic_attr);
+        }
+    }
+
+    #[inline]
+    fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    #[inline]
+    fn ic_attributes(&self) -> &AHashMap<AttributeName, Attr> {
+        &self.ic_attributes
+    }
+
+    #[inline]
+    fn im_attributes(&self) -> &AHashMap<AttributeName, Attr> {
+        &self.im_attributes
+    }
+
+    #[inline]
+    fn state(&self) -> &ClientState {
+        &self.state
+    }
+
+    #[inline]
+    fn state_mut(&mut self) -> &mut ClientState {
+        &mut self.state
+    }
+
+    #[inline]
+    fn serialize_event(&self, xev: &Self::XEvent) -> XEvent {
+        xev.clone()
+    }
+
+    #[inline]
+    fn deserialize_event(&self, xev: &XEvent) -> Self::XEvent {
+        xev.clone()
+    }
+
+    fn send_req(&mut self, req: Request) -> Result<(), ClientError> {
+        let mut link = self.link.borrow_mut();
+        let transport_max = link.transport_max;
+
+        serialize(&req, self.endian, &mut self.buf);
+        link.to_server.push(&mut self.buf, transport_max);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fragment, MemoryClient, MemoryServer, Message, Queue, DEFAULT_TRANSPORT_MAX};
+    use crate::server::ServerCore;
+    use crate::{
+        client::ClientCore, CjkCharset, Client, ClientError, ClientHandler, EncodeStrategy, Server,
+        ServerError, ServerHandler, TextEncoding, UserInputContext, XimConnection, XimConnections,
+    };
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use xim_parser::{
+        Attribute, AttributeName, CommitData, Endian, ErrorCode, ErrorFlag, ForwardEventFlag,
+        InputStyle, Request, XEvent,
+    };
+
+    const KEY_A: u8 = 38;
+
+    #[derive(Default)]
+    struct TestServerHandler {
+        created: u32,
+        destroyed: u32,
+        /// Only encoding accepted in `EncodingNegotiation`, every one when `None`
+        encoding: Option<TextEncoding>,
+        /// Committed on every forwarded key instead of Hangul
+        commit_text: Option<&'static str>,
+    }
+
+    impl<S: Server> ServerHandler<S> for TestServerHandler {
+        type InputStyleArray = [InputStyle; 1];
+        type InputContextData = ();
+
+        fn new_ic_data(
+            &mut self,
+            _server: &mut S,
+            _input_style: InputStyle,
+        ) -> Result<Self::InputContextData, ServerError> {
+            Ok(())
+        }
+
+        fn input_styles(&self) -> Self::InputStyleArray {
+            [InputStyle::PREEDIT_NOTHING | InputStyle::STATUS_NOTHING]
+        }
+
+        fn filter_events(&self) -> u32 {
+            1
+        }
+
+        fn accept_encoding(&self, encoding: TextEncoding) -> bool {
+            self.encoding.map_or(true, |only| only == encoding)
+        }
+
+        fn handle_connect(&mut self, _server: &mut S) -> Result<(), ServerError> {
+            Ok(())
+        }
+
+        fn handle_create_ic(
+            &mut self,
+            _server: &mut S,
+            _user_ic: &mut UserInputContext<()>,
+        ) -> Result<(), ServerError> {
+            self.created += 1;
+            Ok(())
+        }
+
+        fn handle_destroy_ic(
+            &mut self,
+            _server: &mut S,
+            _user_ic: UserInputContext<()>,
+        ) -> Result<(), ServerError> {
+            self.destroyed += 1;
+            Ok(())
+        }
+
+        fn handle_reset_ic(
+            &mut self,
+            _server: &mut S,
+            _user_ic: &mut UserInputContext<()>,
+        ) -> Result<String, ServerError> {
+            Ok("가나".into())
+        }
+
+        fn handle_set_focus(
+            &mut self,
+            _server: &mut S,
+            _user_ic: &mut UserInputContext<()>,
+        ) -> Result<(), ServerError> {
+            Ok(())
+        }
+
+        fn handle_unset_focus(
+            &mut self,
+            _server: &mut S,
+            _user_ic: &mut UserInputContext<()>,
+        ) -> Result<(), ServerError> {
+            Ok(())
+        }
+
+        fn handle_set_ic_values(
+            &mut self,
+            _server: &mut S,
+            _user_ic: &mut UserInputContext<()>,
+        ) -> Result<(), ServerError> {
+            Ok(())
+        }
+
+        fn handle_forward_event(
+            &mut self,
+            server: &mut S,
+            user_ic: &mut UserInputContext<()>,
+            _xev: &S::XEvent,
+        ) -> Result<bool, ServerError> {
+            let text = self.commit_text.unwrap_or("가나다라마바사");
+            server.commit_sync(&mut user_ic.ic, text)?;
+            Ok(true)
+        }
+    }
+
+    #[derive(Default)]
+    struct TestClientHandler {
+        im_id: u16,
+        ic_id: u16,
+        commits: Vec<String>,
+        resets: Vec<String>,
+        forwarded: Vec<u8>,
+        disconnected: bool,
+    }
+
+    impl ClientHandler<MemoryClient> for TestClientHandler {
+        fn handle_connect(&mut self, client: &mut MemoryClient) -> Result<(), ClientError> {
+            client.open("en_US")
+        }
+
+        fn handle_open(
+            &mut self,
+            client: &mut MemoryClient,
+            input_method_id: u16,
+        ) -> Result<(), ClientError> {
+            self.im_id = input_method_id;
+            let window = client.client_window();
+            let ic_attributes = client
+                .build_ic_attributes()
+                .push(
+                    AttributeName::InputStyle,
+                    InputStyle::PREEDIT_NOTHING | InputStyle::STATUS_NOTHING,
+                )
+                .push(AttributeName::ClientWindow, window)
+                .push(AttributeName::FocusWindow, window)
+                .build();
+            client.create_ic(input_method_id, ic_attributes)
+        }
+
+        fn handle_create_ic(
+            &mut self,
+            _client: &mut MemoryClient,
+            _input_method_id: u16,
+            input_context_id: u16,
+        ) -> Result<(), ClientError> {
+            self.ic_id = input_context_id;
+            Ok(())
+        }
+
+        fn handle_commit(
+            &mut self,
+            _client: &mut MemoryClient,
+            _input_method_id: u16,
+            _input_context_id: u16,
+            text: &str,
+        ) -> Result<(), ClientError> {
+            self.commits.push(text.into());
+            Ok(())
+        }
+
+        fn handle_reset_ic(
+            &mut self,
+            _client: &mut MemoryClient,
+            _input_method_id: u16,
+            _input_context_id: u16,
+            preedit_string: &str,
+        ) -> Result<(), ClientError> {
+            self.resets.push(preedit_string.into());
+            Ok(())
+        }
+
+        fn handle_forward_event(
+            &mut self,
+            _client: &mut MemoryClient,
+            _input_method_id: u16,
+            _input_context_id: u16,
+            _flag: ForwardEventFlag,
+            xev: XEvent,
+        ) -> Result<(), ClientError> {
+            self.forwarded.push(xev.detail);
+            Ok(())
+        }
+
+        fn handle_disconnect(&mut self) {
+            self.disconnected = true;
+        }
+
+        fn handle_decode_error(
+            &mut self,
+            _client: &mut MemoryClient,
+            _error: xim_ctext::DecodeError,
+            _bytes: &[u8],
+        ) -> Result<(), ClientError> {
+            Ok(())
+        }
+    }
+
+    /// Keeps every default of `ClientHandler`
+    struct DefaultClientHandler;
+
+    impl ClientHandler<MemoryClient> for DefaultClientHandler {}
+
+    fn key_press(detail: u8) -> XEvent {
+        XEvent {
+            response_type: 2,
+            detail,
+            sequence: 0,
+            time: 0,
+            root: 0,
+            event: 0,
+            child: 0,
+            root_x: 0,
+            root_y: 0,
+            event_x: 0,
+            event_y: 0,
+            state: 0,
+            same_screen: true,
+        }
+    }
+
+    /// Server and client linked in memory
+    struct Session<H = TestClientHandler> {
+        server: MemoryServer,
+        connections: XimConnections<()>,
+        server_handler: TestServerHandler,
+        client: MemoryClient,
+        client_handler: H,
+    }
+
+    impl Session {
+        /// Session connected with default handlers, the client has an input context
+        fn new() -> Self {
+            let mut session = Self::with(
+                MemoryServer::new(),
+                TestServerHandler::default(),
+                TestClientHandler::default(),
+            );
+            session.connect(Endian::NATIVE);
+            session
+        }
+
+        fn forward_key(&mut self, detail: u8) {
+            self.client
+                .forward_key_event(
+                    self.client_handler.im_id,
+                    self.client_handler.ic_id,
+                    &key_press(detail),
+                )
+                .unwrap();
+            self.pump();
+        }
+    }
+
+    impl<H: ClientHandler<MemoryClient>> Session<H> {
+        /// Session which isn't connected yet
+        fn with(
+            mut server: MemoryServer,
+            server_handler: TestServerHandler,
+            client_handler: H,
+        ) -> Self {
+            let mut connections = XimConnections::new();
+            let client = server.connect(&mut connections);
+
+            Self {
+                server,
+                connections,
+                server_handler,
+                client,
+                client_handler,
+            }
+        }
+
+        fn connect(&mut self, endian: Endian) {
+            self.client.connect(endian).unwrap();
+            self.pump();
+        }
+
+        /// Dispatch on both sides until nothing is left
+        fn pump(&mut self) {
+            while self
+                .server
+                .dispatch(&mut self.connections, &mut self.server_handler)
+                .unwrap()
+                | self.client.dispatch(&mut self.client_handler).unwrap()
+            {}
+        }
+
+        /// Let server handle `req` and return its reply
+        fn server_reply(&mut self, req: &[u8]) -> Request {
+            self.server.links[0]
+                .borrow_mut()
+                .to_server
+                .push(&mut req.to_vec(), DEFAULT_TRANSPORT_MAX);
+            self.server
+                .dispatch(&mut self.connections, &mut self.server_handler)
+                .unwrap();
+            let data = self.server.links[0]
+                .borrow_mut()
+                .to_client
+                .pop()
+                .unwrap()
+                .unwrap();
+            xim_parser::read(&data).unwrap()
+        }
+
+        fn connection(&mut self) -> &mut XimConnection<()> {
+            let com_win = self
+                .connections
+                .find_com_win(self.client.client_window())
+                .unwrap();
+            self.connections.get_connection(com_win).unwrap()
+        }
+    }
+
+    fn session(transport_max: usize, endian: Endian) {
+        let mut s = Session::with(
+            MemoryServer::with_transport_max(transport_max),
+            TestServerHandler::default(),
+            TestClientHandler::default(),
+        );
+        s.connect(endian);
+
+        assert_eq!(s.server_handler.created, 1);
+        assert_ne!(s.client_handler.ic_id, 0);
+
+        s.forward_key(KEY_A);
+
+        assert_eq!(s.client_handler.commits, ["가나다라마바사"]);
+        assert!(s.client_handler.forwarded.is_empty());
+        assert_eq!(
+            s.client.state().encodings[&s.client_handler.im_id],
+            TextEncoding::Utf8String
+        );
+
+        s.client
+            .reset_ic(s.client_handler.im_id, s.client_handler.ic_id)
+            .unwrap();
+        s.pump();
+
+        assert_eq!(s.client_handler.resets, ["가나"]);
+
+        s.client.disconnect().unwrap();
+        s.pump();
+
+        assert!(s.client_handler.disconnected);
+        assert_eq!(s.server_handler.destroyed, 1);
+        assert!(s.server.links.is_empty());
+    }
+
+    #[test]
+    fn session_property() {
+        session(DEFAULT_TRANSPORT_MAX, Endian::NATIVE);
+    }
+
+    #[test]
+    fn session_client_message() {
+        session(usize::MAX, Endian::NATIVE);
+    }
+
+    #[test]
+    fn session_big_endian() {
+        session(DEFAULT_TRANSPORT_MAX, Endian::Big);
+    }
+
+    #[test]
+    fn legacy_commit() {
+        let mut s = Session::with(
+            MemoryServer::new(),
+            TestServerHandler {
+                encoding: Some(TextEncoding::CompoundText),
+                ..Default::default()
+            },
+            TestClientHandler::default(),
+        );
+        s.connection()
+            .set_encode_strategy(EncodeStrategy::Legacy(CjkCharset::Ksc5601));
+        s.connect(Endian::NATIVE);
+        s.forward_key(KEY_A);
+
+        assert_eq!(s.client_handler.commits, ["가나다라마바사"]);
+        assert_eq!(
+            s.client.state().encodings[&s.client_handler.im_id],
+            TextEncoding::CompoundText
+        );
+    }
+
+    #[test]
+    fn locale_encoding_commit() {
+        let euc_jp = TextEncoding::from_name("EUC-JP").unwrap();
+        let mut s = Session::with(
+            MemoryServer::new(),
+            TestServerHandler {
+                encoding: Some(euc_jp),
+                commit_text: Some("東京"),
+                ..Default::default()
+            },
+            TestClientHandler::default(),
+        );
+        s.client.state_mut().locale_encoding = TextEncoding::from_name("ja_JP.eucJP");
+        s.connect(Endian::NATIVE);
+        s.forward_key(KEY_A);
+
+        let im_id = s.client_handler.im_id;
+        assert_eq!(s.client.state().encodings[&im_id], euc_jp);
+        assert_eq!(s.client_handler.commits, ["東京"]);
+
+        // Server picks a detailed info when no name is accepted
+        let req = xim_parser::write_to_vec(Request::EncodingNegotiation {
+            input_method_id: im_id,
+            encodings: alloc::vec!["UTF8_STRING".into()],
+            encoding_infos: alloc::vec![euc_jp.info()],
+        });
+        match s.server_reply(&req) {
+            Request::EncodingNegotiationReply {
+                category, index, ..
+            } => assert_eq!((category, index), (1, 0)),
+            other => panic!("Expected EncodingNegotiationReply, got {:?}", other),
+        }
+
+        // Client maps it to its own detailed infos
+        s.client.state_mut().encodings.clear();
+        crate::client::handle_request(
+            &mut s.client,
+            &mut s.client_handler,
+            Request::EncodingNegotiationReply {
+                input_method_id: im_id,
+                category: 1,
+                index: 0,
+            },
+        )
+        .unwrap();
+        assert_eq!(s.client.state().encodings[&im_id], euc_jp);
+    }
+
+    fn invalid_commit(im_id: u16, ic_id: u16) -> Request {
+        Request::Commit {
+            input_method_id: im_id,
+            input_context_id: ic_id,
+            data: CommitData::Chars {
+                commited: alloc::vec![0x1b, 0x25, 0x47, 0xff],
+                syncronous: false,
+            },
+        }
+    }
+
+    #[test]
+    fn decode_error_by_default() {
+        let mut s = Session::with(
+            MemoryServer::new(),
+            TestServerHandler::default(),
+            DefaultClientHandler,
+        );
+        s.connect(Endian::NATIVE);
+        s.server
+            .send_req(
+                s.client.client_window(),
+                Endian::NATIVE,
+                invalid_commit(1, 1),
+            )
+            .unwrap();
+
+        match s.client.dispatch(&mut s.client_handler) {
+            Err(ClientError::DecodeText(_, bytes)) => assert_eq!(bytes, [0x1b, 0x25, 0x47, 0xff]),
+            other => panic!("Expected DecodeText, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_error_lossy() {
+        let mut s = Session::with(
+            MemoryServer::new(),
+            TestServerHandler {
+                encoding: Some(TextEncoding::CompoundText),
+                ..Default::default()
+            },
+            TestClientHandler::default(),
+        );
+        s.connect(Endian::NATIVE);
+        s.server
+            .send_req(
+                s.client.client_window(),
+                Endian::NATIVE,
+                invalid_commit(s.client_handler.im_id, s.client_handler.ic_id),
+            )
+            .unwrap();
+        s.pump();
+
+        assert_eq!(s.client_handler.commits, ["\u{FFFD}"]);
+    }
+
+    #[test]
+    fn error_ids() {
+        let mut s = Session::new();
+        let im_id = s.client_handler.im_id;
+
+        let cases = [
+            // Unknown input method
+            (
+                Request::GetImValues {
+                    input_method_id: 99,
+                    im_attributes: Vec::new(),
+                },
+                ErrorCode::BadSomething,
+                ErrorFlag::empty(),
+            ),
+            // Unknown attribute id of a known input method
+            (
+                Request::GetImValues {
+                    input_method_id: im_id,
+                    im_attributes: alloc::vec![999],
+                },
+                ErrorCode::BadName,
+                ErrorFlag::INPUT_METHOD_ID_VALID,
+            ),
+            // Unknown input context of a known input method
+            (
+                Request::ResetIc {
+                    input_method_id: im_id,
+                    input_context_id: 99,
+                },
+                ErrorCode::BadSomething,
+                ErrorFlag::INPUT_METHOD_ID_VALID,
+            ),
+            // Unreadable attribute of a known input method
+            (
+                Request::SetImValues {
+                    input_method_id: im_id,
+                    attributes: alloc::vec![Attribute {
+                        id: xim_parser::attrs::QUERY_INPUT_STYLE.id,
+                        value: alloc::vec![0],
+                    }],
+                },
+                ErrorCode::BadProtocol,
+                ErrorFlag::INPUT_METHOD_ID_VALID,
+            ),
+        ];
+
+        for (req, expected_code, expected_flag) in cases {
+            let req = xim_parser::write_to_vec(req);
+            match s.server_reply(&req) {
+                Request::Error {
+                    input_method_id,
+                    input_context_id,
+                    flag,
+                    code,
+                    ..
+                } => {
+                    assert_eq!(code, expected_code);
+                    assert_eq!(flag, expected_flag);
+                    assert_eq!(
+                        input_method_id != 0,
+                        flag.contains(ErrorFlag::INPUT_METHOD_ID_VALID)
+                    );
+                    assert_eq!(input_context_id, 0);
+                }
+                other => panic!("Expected Error, got {:?}", other),
+            }
+        }
+
+        // Attributes the input method has no value for are skipped
+        let req = xim_parser::write_to_vec(Request::GetImValues {
+            input_method_id: im_id,
+            im_attributes: alloc::vec![
+                xim_parser::attrs::CLIENTWIN.id,
+                xim_parser::attrs::QUERY_INPUT_STYLE.id,
+            ],
+        });
+        match s.server_reply(&req) {
+            Request::GetImValuesReply { im_attributes, .. } => {
+                assert_eq!(im_attributes.len(), 1);
+                assert_eq!(im_attributes[0].id, xim_parser::attrs::QUERY_INPUT_STYLE.id);
+            }
+            other => panic!("Expected GetImValuesReply, got {:?}", other),
+        }
+
+        // Unparseable request
+        match s.server_reply(&[200, 0, 0, 0]) {
+            Request::Error { code, flag, .. } => {
+                assert_eq!(code, ErrorCode::BadProtocol);
+                assert_eq!(flag, ErrorFlag::empty());
+            }
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejected_ic_values() {
+        let mut s = Session::new();
+
+        let resource_name = Attribute {
+            id: xim_parser::attrs::RESOURCE_NAME.id,
+            value: b"xim".to_vec(),
+        };
+        let cases = [
+            (
+                Attribute {
+                    id: xim_parser::attrs::INPUT_STYLE.id,
+                    value: xim_parser::write_to_vec(
+                        InputStyle::PREEDIT_NOTHING | InputStyle::STATUS_NOTHING,
+                    ),
+                },
+                ErrorCode::BadStyle,
+            ),
+            (
+                Attribute {
+                    id: xim_parser::attrs::FOCUSWIN.id,
+                    value: xim_parser::write_to_vec(0u32),
+                },
+                ErrorCode::BadFocusWindow,
+            ),
+        ];
+
+        for (bad, expected_code) in cases {
+            let req = xim_parser::write_to_vec(Request::SetIcValues {
+                input_method_id: s.client_handler.im_id,
+                input_context_id: s.client_handler.ic_id,
+                ic_attributes: alloc::vec![resource_name.clone(), bad],
+            });
+            match s.server_reply(&req) {
+                Request::Error { code, .. } => assert_eq!(code, expected_code),
+                other => panic!("Expected Error, got {:?}", other),
+            }
+        }
+
+        let (im_id, ic_id) = (s.client_handler.im_id, s.client_handler.ic_id);
+        let ic = &s
+            .connection()
+            .input_methods
+            .get_item(im_id)
+            .unwrap()
+            .get_input_context(ic_id)
+            .unwrap()
+            .ic;
+        assert_eq!(ic.resource_name(), None);
+    }
+
+    #[test]
+    fn dropped_client() {
+        let Session {
+            mut server,
+            mut connections,
+            mut server_handler,
+            client,
+            ..
+        } = Session::new();
+        drop(client);
+
+        assert!(server
+            .dispatch(&mut connections, &mut server_handler)
+            .unwrap());
+        assert_eq!(server_handler.destroyed, 1);
+        assert!(server.links.is_empty());
+    }
+
+    #[test]
+    fn fragments() {
+        let mut queue = Queue::new();
+        let mut buf = (0..30).collect::<Vec<u8>>();
+
+        queue.push(&mut buf, usize::MAX);
+
+        assert!(matches!(
+            queue.messages[0],
+            Message::Fragment(Fragment::MoreData(_))
+        ));
+        assert!(matches!(
+            queue.messages[1],
+            Message::Fragment(Fragment::Protocol(_))
+        ));
+
+        let data = queue.pop().unwrap().unwrap();
+        assert_eq!(data.len(), 40);
+        assert_eq!(data[..30], (0..30).collect::<Vec<u8>>()[..]);
+        assert!(queue.pop().is_none());
+    }
+}
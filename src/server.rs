@@ -1,17 +1,21 @@
 mod connection;
 
 use alloc::string::String;
-use alloc::vec;
 use alloc::vec::Vec;
 use core::fmt;
 use core::num::NonZeroU16;
+use core::ops::Range;
+
+use xim_ctext::TextEncoding;
 
 use xim_parser::{
-    CommitData, ErrorCode, ErrorFlag, Feedback, InputStyle, PreeditDrawStatus, Request,
+    attrs::AttributeValue, AttributeName, CaretDirection, CaretStyle, CommitData, Endian,
+    ErrorCode, ErrorFlag, Feedback, InputStyle, PreeditDrawStatus, Request, StatusContent,
+    StatusTextContent, TriggerKey, TriggerNotifyFlag,
 };
 
 pub use self::connection::{
-    InputContext, InputMethod, UserInputContext, XimConnection, XimConnections,
+    InputContext, InputMethod, NestedAttributes, UserInputContext, XimConnection, XimConnections,
 };
 
 #[derive(Debug)]
@@ -64,8 +68,45 @@ pub trait ServerHandler<S: Server> {
     fn input_styles(&self) -> Self::InputStyleArray;
     fn filter_events(&self) -> u32;
 
+    /// `ResourceName` IM value
+    fn resource_name(&self) -> &str {
+        ""
+    }
+
+    /// `ResourceClass` IM value
+    fn resource_class(&self) -> &str {
+        ""
+    }
+
+    /// `VisiblePosition` IM value, `true` when preedit is drawn at the spot location
+    fn visible_position(&self) -> bool {
+        false
+    }
+
+    /// On and off keys registered to client for dynamic event flow,
+    /// when both are empty static event flow is used
+    fn trigger_keys(&self) -> (Vec<TriggerKey>, Vec<TriggerKey>) {
+        (Vec::new(), Vec::new())
+    }
+
+    /// Return `false` to refuse `encoding` in `EncodingNegotiation`,
+    /// `COMPOUND_TEXT` is used when nothing is accepted
+    fn accept_encoding(&self, _encoding: TextEncoding) -> bool {
+        true
+    }
+
     fn handle_connect(&mut self, server: &mut S) -> Result<(), ServerError>;
 
+    /// Called with decoded values of `SetImValues`
+    fn handle_set_im_values(
+        &mut self,
+        _server: &mut S,
+        _input_method: &mut InputMethod<Self::InputContextData>,
+        _values: Vec<(AttributeName, AttributeValue)>,
+    ) -> Result<(), ServerError> {
+        Ok(())
+    }
+
     fn handle_create_ic(
         &mut self,
         server: &mut S,
@@ -109,6 +150,48 @@ pub trait ServerHandler<S: Server> {
         user_ic: &mut UserInputContext<Self::InputContextData>,
         xev: &S::XEvent,
     ) -> Result<bool, ServerError>;
+
+    /// Called when client answers a synchronous request like synchronous commit
+    fn handle_sync_reply(
+        &mut self,
+        _server: &mut S,
+        _user_ic: &mut UserInputContext<Self::InputContextData>,
+    ) -> Result<(), ServerError> {
+        Ok(())
+    }
+
+    /// Called when client pressed one of `trigger_keys`, `index` points into the list `flag` names
+    ///
+    /// Use `Server::set_event_mask` to make client forward key events after turned on
+    fn handle_trigger_notify(
+        &mut self,
+        _server: &mut S,
+        _user_ic: &mut UserInputContext<Self::InputContextData>,
+        _flag: TriggerNotifyFlag,
+        _index: u32,
+    ) -> Result<(), ServerError> {
+        Ok(())
+    }
+
+    /// Called after a request of `client_win` failed with `error` and client got XIM `Error`
+    fn handle_request_error(
+        &mut self,
+        _server: &mut S,
+        _client_win: u32,
+        _error: &ServerError,
+    ) -> Result<(), ServerError> {
+        Ok(())
+    }
+
+    /// Called with the caret position client returned for `Server::preedit_caret`
+    fn handle_preedit_caret_reply(
+        &mut self,
+        _server: &mut S,
+        _user_ic: &mut UserInputContext<Self::InputContextData>,
+        _position: i32,
+    ) -> Result<(), ServerError> {
+        Ok(())
+    }
 }
 
 pub trait Server {
@@ -117,6 +200,7 @@ pub trait Server {
     fn error(
         &mut self,
         client_win: u32,
+        endian: Endian,
         code: ErrorCode,
         detail: String,
         input_method_id: Option<NonZeroU16>,
@@ -124,7 +208,51 @@ pub trait Server {
     ) -> Result<(), ServerError>;
 
     fn preedit_draw(&mut self, ic: &mut InputContext, s: &str) -> Result<(), ServerError>;
+    /// Draw preedit with `feedbacks` for ranges of character index and caret at `caret`
+    ///
+    /// Characters not covered by `feedbacks` are drawn with `Feedback::Underline`,
+    /// only the changed part since the previous draw is sent.
+    fn preedit_draw_with_feedbacks(
+        &mut self,
+        ic: &mut InputContext,
+        s: &str,
+        feedbacks: &[(Range<usize>, Feedback)],
+        caret: usize,
+    ) -> Result<(), ServerError>;
+    /// Draw status text for `STATUS_CALLBACKS` input contexts, empty `s` ends status drawing
+    fn status_draw(
+        &mut self,
+        ic: &mut InputContext,
+        s: &str,
+        feedbacks: &[Feedback],
+    ) -> Result<(), ServerError>;
+    /// Move caret in client drawn preedit, `position` is only used with
+    /// `CaretDirection::AbsolutePosition`
+    fn preedit_caret(
+        &mut self,
+        ic: &InputContext,
+        position: i32,
+        direction: CaretDirection,
+        style: CaretStyle,
+    ) -> Result<(), ServerError>;
     fn commit(&mut self, ic: &InputContext, s: &str) -> Result<(), ServerError>;
+    /// Commit string, client will send `SyncReply`
+    fn commit_sync(&mut self, ic: &mut InputContext, s: &str) -> Result<(), ServerError>;
+    /// Commit keysym, if `synchronous` is `true` client will send `SyncReply`
+    fn commit_keysym(
+        &mut self,
+        ic: &mut InputContext,
+        keysym: u32,
+        synchronous: bool,
+    ) -> Result<(), ServerError>;
+    /// Commit keysym with string, if `synchronous` is `true` client will send `SyncReply`
+    fn commit_both(
+        &mut self,
+        ic: &mut InputContext,
+        keysym: u32,
+        s: &str,
+        synchronous: bool,
+    ) -> Result<(), ServerError>;
 
     fn set_event_mask(
         &mut self,
@@ -140,6 +268,7 @@ impl<S: ServerCore> Server for S {
     fn error(
         &mut self,
         client_win: u32,
+        endian: Endian,
         code: ErrorCode,
         detail: String,
         input_method_id: Option<NonZeroU16>,
@@ -163,6 +292,7 @@ impl<S: ServerCore> Server for S {
 
         self.send_req(
             client_win,
+            endian,
             Request::Error {
                 input_method_id,
                 input_context_id,
@@ -174,18 +304,40 @@ impl<S: ServerCore> Server for S {
     }
 
     fn preedit_draw(&mut self, ic: &mut InputContext, s: &str) -> Result<(), ServerError> {
-        let preedit_length = s.chars().count();
+        self.preedit_draw_with_feedbacks(ic, s, &[], s.chars().count())
+    }
 
-        if preedit_length == 0 {
+    fn preedit_draw_with_feedbacks(
+        &mut self,
+        ic: &mut InputContext,
+        s: &str,
+        feedbacks: &[(Range<usize>, Feedback)],
+        caret: usize,
+    ) -> Result<(), ServerError> {
+        let preedit: Vec<(char, Feedback)> = s
+            .chars()
+            .enumerate()
+            .map(|(idx, c)| {
+                let feedback = feedbacks
+                    .iter()
+                    .rev()
+                    .find(|(range, _)| range.contains(&idx))
+                    .map_or(Feedback::Underline, |(_, feedback)| *feedback);
+                (c, feedback)
+            })
+            .collect();
+
+        if preedit.is_empty() {
             if ic.preedit_started {
                 self.send_req(
                     ic.client_win(),
+                    ic.endian(),
                     Request::PreeditDraw {
                         input_method_id: ic.input_method_id().get(),
                         input_context_id: ic.input_context_id().get(),
                         chg_first: 0,
-                        chg_length: ic.prev_preedit_length as _,
-                        caret: preedit_length as _,
+                        chg_length: ic.prev_preedit.len() as _,
+                        caret: 0,
                         preedit_string: Vec::new(),
                         feedbacks: Vec::new(),
                         status: PreeditDrawStatus::NO_FEEDBACK | PreeditDrawStatus::NO_STRING,
@@ -193,60 +345,246 @@ impl<S: ServerCore> Server for S {
                 )?;
                 self.send_req(
                     ic.client_win(),
+                    ic.endian(),
                     Request::PreeditDone {
                         input_method_id: ic.input_method_id().get(),
                         input_context_id: ic.input_context_id().get(),
                     },
                 )?;
                 ic.preedit_started = false;
-                ic.prev_preedit_length = 0;
+                ic.prev_preedit.clear();
+                ic.prev_preedit_caret = 0;
+            }
+
+            return Ok(());
+        }
+
+        let caret = caret.min(preedit.len());
+
+        if !ic.preedit_started {
+            self.send_req(
+                ic.client_win(),
+                ic.endian(),
+                Request::PreeditStart {
+                    input_method_id: ic.input_method_id().get(),
+                    input_context_id: ic.input_context_id().get(),
+                },
+            )?;
+            ic.preedit_started = true;
+        } else if ic.prev_preedit == preedit && ic.prev_preedit_caret == caret {
+            return Ok(());
+        }
+
+        // Only send the changed part between common prefix and suffix
+        let prev = &ic.prev_preedit;
+        let prefix = prev
+            .iter()
+            .zip(preedit.iter())
+            .take_while(|(l, r)| l == r)
+            .count();
+        let suffix = prev[prefix..]
+            .iter()
+            .rev()
+            .zip(preedit[prefix..].iter().rev())
+            .take_while(|(l, r)| l == r)
+            .count();
+        let changed = &preedit[prefix..preedit.len() - suffix];
+
+        let status = if changed.is_empty() {
+            PreeditDrawStatus::NO_FEEDBACK | PreeditDrawStatus::NO_STRING
+        } else {
+            PreeditDrawStatus::empty()
+        };
+        let changed_string: String = changed.iter().map(|(c, _)| c).collect();
+
+        self.send_req(
+            ic.client_win(),
+            ic.endian(),
+            Request::PreeditDraw {
+                input_method_id: ic.input_method_id().get(),
+                input_context_id: ic.input_context_id().get(),
+                chg_first: prefix as _,
+                chg_length: (prev.len() - prefix - suffix) as _,
+                caret: caret as _,
+                preedit_string: ic.encoding().encode(&changed_string, ic.encode_strategy()),
+                feedbacks: changed.iter().map(|(_, f)| *f).collect(),
+                status,
+            },
+        )?;
+
+        ic.prev_preedit = preedit;
+        ic.prev_preedit_caret = caret;
+
+        Ok(())
+    }
+
+    fn status_draw(
+        &mut self,
+        ic: &mut InputContext,
+        s: &str,
+        feedbacks: &[Feedback],
+    ) -> Result<(), ServerError> {
+        if !ic.input_style().contains(InputStyle::STATUS_CALLBACKS) {
+            return Ok(());
+        }
+
+        if s.is_empty() {
+            if ic.status_started {
+                self.send_req(
+                    ic.client_win(),
+                    ic.endian(),
+                    Request::StatusDone {
+                        input_method_id: ic.input_method_id().get(),
+                        input_context_id: ic.input_context_id().get(),
+                    },
+                )?;
+                ic.status_started = false;
             }
         } else {
-            if !ic.preedit_started {
+            if !ic.status_started {
                 self.send_req(
                     ic.client_win(),
-                    Request::PreeditStart {
+                    ic.endian(),
+                    Request::StatusStart {
                         input_method_id: ic.input_method_id().get(),
                         input_context_id: ic.input_context_id().get(),
                     },
                 )?;
-                ic.preedit_started = true;
+                ic.status_started = true;
             }
 
+            let status = if feedbacks.is_empty() {
+                PreeditDrawStatus::NO_FEEDBACK
+            } else {
+                PreeditDrawStatus::empty()
+            };
+
             self.send_req(
                 ic.client_win(),
-                Request::PreeditDraw {
+                ic.endian(),
+                Request::StatusDraw {
                     input_method_id: ic.input_method_id().get(),
                     input_context_id: ic.input_context_id().get(),
-                    chg_first: 0,
-                    chg_length: ic.prev_preedit_length as _,
-                    caret: preedit_length as _,
-                    preedit_string: xim_ctext::utf8_to_compound_text(s),
-                    feedbacks: vec![Feedback::Underline; preedit_length],
-                    status: PreeditDrawStatus::empty(),
+                    content: StatusContent::Text(StatusTextContent {
+                        status,
+                        status_string: ic.encoding().encode(s, ic.encode_strategy()),
+                        feedbacks: feedbacks.to_vec(),
+                    }),
                 },
             )?;
-
-            ic.prev_preedit_length = preedit_length;
         }
 
         Ok(())
     }
 
+    fn preedit_caret(
+        &mut self,
+        ic: &InputContext,
+        position: i32,
+        direction: CaretDirection,
+        style: CaretStyle,
+    ) -> Result<(), ServerError> {
+        self.send_req(
+            ic.client_win(),
+            ic.endian(),
+            Request::PreeditCaret {
+                input_method_id: ic.input_method_id().get(),
+                input_context_id: ic.input_context_id().get(),
+                position,
+                direction,
+                style,
+            },
+        )
+    }
+
     fn commit(&mut self, ic: &InputContext, s: &str) -> Result<(), ServerError> {
         self.send_req(
             ic.client_win(),
+            ic.endian(),
             Request::Commit {
                 input_method_id: ic.input_method_id().get(),
                 input_context_id: ic.input_context_id().get(),
                 data: CommitData::Chars {
-                    commited: xim_ctext::utf8_to_compound_text(s),
+                    commited: ic.encoding().encode(s, ic.encode_strategy()),
                     syncronous: false,
                 },
             },
         )
     }
 
+    fn commit_sync(&mut self, ic: &mut InputContext, s: &str) -> Result<(), ServerError> {
+        self.send_req(
+            ic.client_win(),
+            ic.endian(),
+            Request::Commit {
+                input_method_id: ic.input_method_id().get(),
+                input_context_id: ic.input_context_id().get(),
+                data: CommitData::Chars {
+                    commited: ic.encoding().encode(s, ic.encode_strategy()),
+                    syncronous: true,
+                },
+            },
+        )?;
+
+        ic.outstanding_syncs += 1;
+
+        Ok(())
+    }
+
+    fn commit_keysym(
+        &mut self,
+        ic: &mut InputContext,
+        keysym: u32,
+        synchronous: bool,
+    ) -> Result<(), ServerError> {
+        self.send_req(
+            ic.client_win(),
+            ic.endian(),
+            Request::Commit {
+                input_method_id: ic.input_method_id().get(),
+                input_context_id: ic.input_context_id().get(),
+                data: CommitData::Keysym {
+                    keysym,
+                    syncronous: synchronous,
+                },
+            },
+        )?;
+
+        if synchronous {
+            ic.outstanding_syncs += 1;
+        }
+
+        Ok(())
+    }
+
+    fn commit_both(
+        &mut self,
+        ic: &mut InputContext,
+        keysym: u32,
+        s: &str,
+        synchronous: bool,
+    ) -> Result<(), ServerError> {
+        self.send_req(
+            ic.client_win(),
+            ic.endian(),
+            Request::Commit {
+                input_method_id: ic.input_method_id().get(),
+                input_context_id: ic.input_context_id().get(),
+                data: CommitData::Both {
+                    keysym,
+                    commited: ic.encoding().encode(s, ic.encode_strategy()),
+                    syncronous: synchronous,
+                },
+            },
+        )?;
+
+        if synchronous {
+            ic.outstanding_syncs += 1;
+        }
+
+        Ok(())
+    }
+
     fn set_event_mask(
         &mut self,
         ic: &InputContext,
@@ -255,6 +593,7 @@ impl<S: ServerCore> Server for S {
     ) -> Result<(), ServerError> {
         self.send_req(
             ic.client_win(),
+            ic.endian(),
             Request::SetEventMask {
                 input_method_id: ic.input_method_id().get(),
                 input_context_id: ic.input_context_id().get(),
@@ -269,5 +608,15 @@ pub trait ServerCore {
     type XEvent;
 
     fn deserialize_event(&self, ev: &xim_parser::XEvent) -> Self::XEvent;
-    fn send_req(&mut self, client_win: u32, req: Request) -> Result<(), ServerError>;
+    /// Check window ids in IC attributes, every window is accepted by default
+    fn window_exists(&self, _window: u32) -> bool {
+        true
+    }
+    /// Send `req` to `client_win` encoded with the byte order negotiated by that client
+    fn send_req(
+        &mut self,
+        client_win: u32,
+        endian: Endian,
+        req: Request,
+    ) -> Result<(), ServerError>;
 }
@@ -1,49 +1,132 @@
+mod ic_attributes;
 mod im_vec;
 
 use crate::AHashMap;
+use alloc::format;
 use alloc::string::String;
-use alloc::vec;
 use alloc::vec::Vec;
 use core::num::{NonZeroU16, NonZeroU32};
+use xim_ctext::{EncodeStrategy, TextEncoding};
 use xim_parser::{
-    attrs, Attribute, AttributeName, ErrorCode, ForwardEventFlag, InputStyle, InputStyleList,
-    Point, Request, XimWrite,
+    attrs::{self, AttributeValue},
+    Attr, AttrType, Attribute, AttributeName, Endian, ErrorCode, Feedback, ForwardEventFlag,
+    HotKeyState, HotKeyTriggers, InputStyle, InputStyleList, Point, ReadError, Request, ValuesList,
 };
 
+pub use self::ic_attributes::NestedAttributes;
+
+use self::ic_attributes::{get_ic_attr, set_ic_attrs};
 use self::im_vec::ImVec;
 use crate::server::{Server, ServerCore, ServerError, ServerHandler};
 
+/// IM attributes sent in `OpenReply`
+const IM_ATTRS: &[Attr] = &[
+    attrs::QUERY_INPUT_STYLE,
+    attrs::QUERY_IM_VALUES_LIST,
+    attrs::QUERY_IC_VALUES_LIST,
+    attrs::RESOURCE_NAME,
+    attrs::RESOURCE_CLASS,
+    attrs::DESTROY_CALLBACK,
+    attrs::VISIBLE_POSITION,
+];
+
+/// IC attributes sent in `OpenReply`
+const IC_ATTRS: &[Attr] = &[
+    attrs::INPUT_STYLE,
+    attrs::CLIENTWIN,
+    attrs::FOCUSWIN,
+    attrs::FILTER_EVENTS,
+    attrs::PREEDIT_ATTRIBUTES,
+    attrs::STATUS_ATTRIBUTES,
+    attrs::RESOURCE_NAME,
+    attrs::RESOURCE_CLASS,
+    attrs::PREEDIT_STATE,
+    attrs::RESET_STATE,
+    attrs::HOT_KEY,
+    attrs::HOT_KEY_STATE,
+    attrs::FONT_SET,
+    attrs::AREA,
+    attrs::AREA_NEEDED,
+    attrs::COLOR_MAP,
+    attrs::STD_COLOR_MAP,
+    attrs::FOREGROUND,
+    attrs::BACKGROUND,
+    attrs::BACKGROUND_PIXMAP,
+    attrs::SPOT_LOCATION,
+    attrs::LINE_SPACE,
+    attrs::CURSOR,
+    attrs::SEPARATOR_OF_NESTED_LIST,
+];
+
+/// Names of `attrs` for `QueryIMValuesList` and `QueryICValuesList`
+fn values_list(attrs: &[Attr]) -> ValuesList {
+    ValuesList {
+        names: attrs
+            .iter()
+            .filter(|attr| attr.ty != AttrType::Separator)
+            .map(|attr| attr.name.name().into())
+            .collect(),
+    }
+}
+
 pub struct InputContext {
     client_win: u32,
+    endian: Endian,
     app_win: Option<NonZeroU32>,
     app_focus_win: Option<NonZeroU32>,
     input_method_id: NonZeroU16,
     input_context_id: NonZeroU16,
     input_style: InputStyle,
-    preedit_spot: Point,
+    resource_name: Option<String>,
+    resource_class: Option<String>,
+    preedit_attributes: NestedAttributes,
+    status_attributes: NestedAttributes,
+    preedit_state: Option<u32>,
+    reset_state: Option<u32>,
+    hot_key: Option<HotKeyTriggers>,
+    hot_key_state: Option<HotKeyState>,
     pub(super) preedit_started: bool,
-    pub(super) prev_preedit_length: usize,
+    pub(super) prev_preedit: Vec<(char, Feedback)>,
+    pub(super) prev_preedit_caret: usize,
+    pub(super) outstanding_syncs: u32,
+    pub(super) status_started: bool,
     locale: String,
+    encoding: TextEncoding,
+    encode_strategy: EncodeStrategy,
 }
 
 impl InputContext {
     pub fn new(
         client_win: u32,
+        endian: Endian,
         input_method_id: NonZeroU16,
         input_context_id: NonZeroU16,
         locale: String,
     ) -> Self {
         Self {
             client_win,
+            endian,
             app_win: None,
             app_focus_win: None,
             input_method_id,
             input_context_id,
             input_style: InputStyle::empty(),
-            preedit_spot: Point { x: 0, y: 0 },
+            resource_name: None,
+            resource_class: None,
+            preedit_attributes: NestedAttributes::default(),
+            status_attributes: NestedAttributes::default(),
+            preedit_state: None,
+            reset_state: None,
+            hot_key: None,
+            hot_key_state: None,
             preedit_started: false,
-            prev_preedit_length: 0,
+            prev_preedit: Vec::new(),
+            prev_preedit_caret: 0,
+            outstanding_syncs: 0,
+            status_started: false,
             locale,
+            encoding: TextEncoding::default(),
+            encode_strategy: EncodeStrategy::default(),
         }
     }
 
@@ -51,6 +134,10 @@ impl InputContext {
         self.client_win
     }
 
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
     pub fn app_win(&self) -> Option<NonZeroU32> {
         self.app_win
     }
@@ -60,7 +147,9 @@ impl InputContext {
     }
 
     pub fn preedit_spot(&self) -> Point {
-        self.preedit_spot.clone()
+        self.preedit_attributes
+            .spot_location()
+            .unwrap_or(Point { x: 0, y: 0 })
     }
 
     pub fn input_method_id(&self) -> NonZeroU16 {
@@ -75,9 +164,28 @@ impl InputContext {
         self.input_style
     }
 
+    /// Number of synchronous requests still waiting for `SyncReply`
+    pub fn outstanding_syncs(&self) -> u32 {
+        self.outstanding_syncs
+    }
+
     pub fn locale(&self) -> &str {
         self.locale.as_str()
     }
+
+    /// Encoding negotiated for the input method of this input context
+    pub fn encoding(&self) -> TextEncoding {
+        self.encoding
+    }
+
+    /// How strings sent to this input context are encoded to COMPOUND_TEXT
+    pub fn encode_strategy(&self) -> EncodeStrategy {
+        self.encode_strategy
+    }
+
+    pub fn set_encode_strategy(&mut self, encode_strategy: EncodeStrategy) {
+        self.encode_strategy = encode_strategy;
+    }
 }
 
 pub struct UserInputContext<T> {
@@ -91,61 +199,9 @@ impl<T> UserInputContext<T> {
     }
 }
 
-fn set_ic_attrs(ic: &mut InputContext, ic_attributes: Vec<Attribute>) {
-    for attr in ic_attributes {
-        let name = if let Some(name) = attrs::get_name(attr.id) {
-            name
-        } else {
-            log::warn!("Unknown attr id: {}", attr.id);
-            continue;
-        };
-
-        match name {
-            AttributeName::InputStyle => {
-                if let Ok(style) = xim_parser::read(&attr.value) {
-                    log::debug!("Style: {:?}", style);
-                    ic.input_style = style;
-                }
-            }
-            AttributeName::ClientWindow => {
-                ic.app_win = xim_parser::read(&attr.value).ok().and_then(NonZeroU32::new);
-            }
-            AttributeName::FocusWindow => {
-                ic.app_focus_win = xim_parser::read(&attr.value).ok().and_then(NonZeroU32::new);
-            }
-            AttributeName::PreeditAttributes => {
-                let mut b = &attr.value[..];
-                while !b.is_empty() {
-                    match xim_parser::read::<Attribute>(b) {
-                        Ok(attr) => {
-                            b = &b[attr.size()..];
-                            match attrs::get_name(attr.id) {
-                                Some(AttributeName::SpotLocation) => {
-                                    if let Ok(spot) = xim_parser::read(&attr.value) {
-                                        log::debug!("Spot: {:?}", spot);
-                                        ic.preedit_spot = spot;
-                                    }
-                                }
-                                name => {
-                                    log::warn!("Ignore unhandled preedit attr: {:?}", name);
-                                }
-                            }
-                        }
-                        Err(_) => {
-                            break;
-                        }
-                    }
-                }
-            }
-            name => {
-                log::warn!("Ignore unhandled attr: {:?}", name);
-            }
-        }
-    }
-}
-
 pub struct InputMethod<T> {
     pub(crate) locale: String,
+    pub(crate) encoding: TextEncoding,
     pub(crate) input_contexts: ImVec<UserInputContext<T>>,
 }
 
@@ -153,15 +209,25 @@ impl<T> InputMethod<T> {
     pub fn new(locale: String) -> Self {
         Self {
             locale,
+            encoding: TextEncoding::default(),
             input_contexts: ImVec::new(),
         }
     }
 
+    /// Encoding negotiated with `EncodingNegotiation`, used by input contexts created after it
+    pub fn encoding(&self) -> TextEncoding {
+        self.encoding
+    }
+
     pub fn clone_locale(&self) -> String {
         self.locale.clone()
     }
 
-    pub fn new_ic(&mut self, ic: UserInputContext<T>) -> (NonZeroU16, &mut UserInputContext<T>) {
+    /// Return `None` when every input context id is in use
+    pub fn new_ic(
+        &mut self,
+        ic: UserInputContext<T>,
+    ) -> Option<(NonZeroU16, &mut UserInputContext<T>)> {
         self.input_contexts.new_item(ic)
     }
 
@@ -183,19 +249,38 @@ impl<T> InputMethod<T> {
 
 pub struct XimConnection<T> {
     pub(crate) client_win: u32,
+    pub(crate) endian: Endian,
     pub(crate) disconnected: bool,
     pub(crate) input_methods: ImVec<InputMethod<T>>,
+    encode_strategy: EncodeStrategy,
 }
 
 impl<T> XimConnection<T> {
     pub fn new(client_win: u32) -> Self {
         Self {
             client_win,
+            endian: Endian::NATIVE,
             disconnected: false,
             input_methods: ImVec::new(),
+            encode_strategy: EncodeStrategy::default(),
         }
     }
 
+    /// Byte order negotiated by the client in `Connect`
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    pub fn encode_strategy(&self) -> EncodeStrategy {
+        self.encode_strategy
+    }
+
+    /// Use `encode_strategy` for input contexts created after this, for clients that can't read
+    /// the utf8 segment of COMPOUND_TEXT
+    pub fn set_encode_strategy(&mut self, encode_strategy: EncodeStrategy) {
+        self.encode_strategy = encode_strategy;
+    }
+
     pub fn disconnect<S: ServerCore + Server, H: ServerHandler<S, InputContextData = T>>(
         &mut self,
         server: &mut S,
@@ -229,6 +314,86 @@ impl<T> XimConnection<T> {
         server: &mut S,
         req: Request,
         handler: &mut H,
+    ) -> Result<(), ServerError> {
+        let input_method_id = req.input_method_id();
+        let input_context_id = req.input_context_id();
+
+        match self.handle_request_inner(server, req, handler) {
+            Ok(()) => Ok(()),
+            Err(e) => self.reply_error(server, handler, e, input_method_id, input_context_id),
+        }
+    }
+
+    /// Handle a request read from the transport, unreadable ones are replied with `Error`
+    pub(crate) fn handle_read<S: ServerCore, H: ServerHandler<S, InputContextData = T>>(
+        &mut self,
+        server: &mut S,
+        req: Result<Request, ReadError>,
+        handler: &mut H,
+    ) -> Result<(), ServerError> {
+        match req {
+            Ok(req) => self.handle_request(server, req, handler),
+            Err(e) => self.handle_error(server, handler, e.into()),
+        }
+    }
+
+    /// Reply XIM `Error` for failures caused by a single request so the connection keeps working,
+    /// other errors like transport failures are returned
+    pub(crate) fn handle_error<S: ServerCore, H: ServerHandler<S, InputContextData = T>>(
+        &mut self,
+        server: &mut S,
+        handler: &mut H,
+        e: ServerError,
+    ) -> Result<(), ServerError> {
+        self.reply_error(server, handler, e, None, None)
+    }
+
+    /// Same as [`handle_error`](Self::handle_error) with ids of the failed request,
+    /// only ids that exist on this connection are marked valid
+    fn reply_error<S: ServerCore, H: ServerHandler<S, InputContextData = T>>(
+        &mut self,
+        server: &mut S,
+        handler: &mut H,
+        e: ServerError,
+        input_method_id: Option<u16>,
+        input_context_id: Option<u16>,
+    ) -> Result<(), ServerError> {
+        let (code, detail) = match &e {
+            ServerError::ClientNotExists => (
+                ErrorCode::BadSomething,
+                "Unknown input method or input context id".into(),
+            ),
+            ServerError::ReadProtocol(e) => {
+                (ErrorCode::BadProtocol, format!("Can't read request: {}", e))
+            }
+            _ => return Err(e),
+        };
+
+        log::warn!("Request from {} failed: {}", self.client_win, e);
+
+        let im = input_method_id.and_then(|id| Some((id, self.input_methods.get_item(id)?)));
+        let im_id = im.as_ref().map(|(id, _)| *id);
+        let ic_id = match (im, input_context_id) {
+            (Some((_, im)), Some(id)) => im.input_contexts.get_item(id).map(|_| id),
+            _ => None,
+        };
+
+        server.error(
+            self.client_win,
+            self.endian,
+            code,
+            detail,
+            im_id.and_then(NonZeroU16::new),
+            ic_id.and_then(NonZeroU16::new),
+        )?;
+        handler.handle_request_error(server, self.client_win, &e)
+    }
+
+    fn handle_request_inner<S: ServerCore, H: ServerHandler<S, InputContextData = T>>(
+        &mut self,
+        server: &mut S,
+        req: Request,
+        handler: &mut H,
     ) -> Result<(), ServerError> {
         if log::log_enabled!(log::Level::Trace) {
             log::trace!("<-: {:?}", req);
@@ -249,9 +414,11 @@ impl<T> XimConnection<T> {
                 log::error!("XIM ERROR! code: {:?}, detail: {}", code, detail);
             }
 
-            Request::Connect { .. } => {
+            Request::Connect { endian, .. } => {
+                self.endian = endian;
                 server.send_req(
                     self.client_win,
+                    self.endian,
                     Request::ConnectReply {
                         server_major_protocol_version: 1,
                         server_minor_protocol_version: 0,
@@ -262,36 +429,46 @@ impl<T> XimConnection<T> {
 
             Request::Disconnect {} => {
                 self.disconnect(server, handler)?;
-                server.send_req(self.client_win, Request::DisconnectReply {})?;
+                server.send_req(self.client_win, self.endian, Request::DisconnectReply {})?;
             }
 
             Request::Open { locale } => {
-                let (input_method_id, _im) = self.input_methods.new_item(InputMethod::new(locale));
+                let input_method_id = match self.input_methods.new_item(InputMethod::new(locale)) {
+                    Some((id, _im)) => id,
+                    None => {
+                        return server.error(
+                            self.client_win,
+                            self.endian,
+                            ErrorCode::BadAlloc,
+                            "No input method id available".into(),
+                            None,
+                            None,
+                        );
+                    }
+                };
+
+                let (on_keys, off_keys) = handler.trigger_keys();
+
+                // Trigger keys must be registered before OpenReply
+                if !on_keys.is_empty() || !off_keys.is_empty() {
+                    server.send_req(
+                        self.client_win,
+                        self.endian,
+                        Request::RegisterTriggerKeys {
+                            input_method_id: input_method_id.get(),
+                            on_keys,
+                            off_keys,
+                        },
+                    )?;
+                }
 
                 server.send_req(
                     self.client_win,
+                    self.endian,
                     Request::OpenReply {
                         input_method_id: input_method_id.get(),
-                        im_attrs: vec![attrs::QUERY_INPUT_STYLE],
-                        ic_attrs: vec![
-                            attrs::INPUT_STYLE,
-                            attrs::CLIENTWIN,
-                            attrs::FOCUSWIN,
-                            attrs::FILTER_EVENTS,
-                            attrs::PREEDIT_ATTRIBUTES,
-                            attrs::STATUS_ATTRIBUTES,
-                            attrs::FONT_SET,
-                            attrs::AREA,
-                            attrs::AREA_NEEDED,
-                            attrs::COLOR_MAP,
-                            attrs::STD_COLOR_MAP,
-                            attrs::FOREGROUND,
-                            attrs::BACKGROUND,
-                            attrs::BACKGROUND_PIXMAP,
-                            attrs::SPOT_LOCATION,
-                            attrs::LINE_SPACE,
-                            attrs::SEPARATOR_OF_NESTED_LIST,
-                        ],
+                        im_attrs: IM_ATTRS.to_vec(),
+                        ic_attrs: IC_ATTRS.to_vec(),
                     },
                 )?;
             }
@@ -301,21 +478,66 @@ impl<T> XimConnection<T> {
                 ic_attributes,
             } => {
                 let client_win = self.client_win;
+                let endian = self.endian;
+                let encode_strategy = self.encode_strategy;
                 let im = self.get_input_method(input_method_id)?;
                 let mut ic = InputContext::new(
                     client_win,
+                    endian,
                     NonZeroU16::new(input_method_id).unwrap(),
                     NonZeroU16::new(1).unwrap(),
                     im.clone_locale(),
                 );
-                set_ic_attrs(&mut ic, ic_attributes);
+                ic.encoding = im.encoding;
+                ic.set_encode_strategy(encode_strategy);
+                let input_styles = handler.input_styles();
+
+                if let Err((code, detail)) =
+                    set_ic_attrs(server, &mut ic, ic_attributes, input_styles.as_ref(), true)
+                {
+                    return server.error(
+                        client_win,
+                        endian,
+                        code,
+                        detail,
+                        NonZeroU16::new(input_method_id),
+                        None,
+                    );
+                }
+
+                // Style can only be set on creation
+                if ic.input_style.is_empty() {
+                    return server.error(
+                        client_win,
+                        endian,
+                        ErrorCode::BadStyle,
+                        "Input style is required".into(),
+                        NonZeroU16::new(input_method_id),
+                        None,
+                    );
+                }
+
+                // Check before `new_ic_data`, handler's data is only released by
+                // `handle_destroy_ic` of a created input context
+                if !im.input_contexts.has_free_id() {
+                    return server.error(
+                        client_win,
+                        endian,
+                        ErrorCode::BadAlloc,
+                        "No input context id available".into(),
+                        NonZeroU16::new(input_method_id),
+                        None,
+                    );
+                }
+
                 let input_style = ic.input_style;
                 let ic = UserInputContext::new(ic, handler.new_ic_data(server, input_style)?);
-                let (input_context_id, ic) = im.new_ic(ic);
+                let (input_context_id, ic) = im.new_ic(ic).unwrap();
                 ic.ic.input_context_id = input_context_id;
 
                 server.send_req(
                     ic.ic.client_win(),
+                    ic.ic.endian(),
                     Request::CreateIcReply {
                         input_method_id,
                         input_context_id: input_context_id.get(),
@@ -336,6 +558,7 @@ impl<T> XimConnection<T> {
                 )?;
                 server.send_req(
                     self.client_win,
+                    self.endian,
                     Request::DestroyIcReply {
                         input_method_id,
                         input_context_id,
@@ -348,7 +571,11 @@ impl<T> XimConnection<T> {
                     handler.handle_destroy_ic(server, ic)?;
                 }
 
-                server.send_req(self.client_win, Request::CloseReply { input_method_id })?;
+                server.send_req(
+                    self.client_win,
+                    self.endian,
+                    Request::CloseReply { input_method_id },
+                )?;
             }
 
             Request::QueryExtension {
@@ -357,6 +584,7 @@ impl<T> XimConnection<T> {
                 // Extension not supported now
                 server.send_req(
                     self.client_win,
+                    self.endian,
                     Request::QueryExtensionReply {
                         input_method_id,
                         extensions: Vec::new(),
@@ -366,35 +594,41 @@ impl<T> XimConnection<T> {
             Request::EncodingNegotiation {
                 input_method_id,
                 encodings,
-                ..
+                encoding_infos,
             } => {
-                log::debug!("Encodings: {:?}", encodings);
-
-                match encodings
-                    .iter()
-                    .position(|e| e.starts_with("COMPOUND_TEXT"))
-                {
-                    Some(pos) => {
-                        server.send_req(
-                            self.client_win,
-                            Request::EncodingNegotiationReply {
-                                input_method_id,
-                                category: 0,
-                                index: pos as i16,
-                            },
-                        )?;
+                log::debug!("Encodings: {:?}, infos: {:?}", encodings, encoding_infos);
+
+                // Category 0 is an index of names and 1 is an index of detailed infos,
+                // both are listed by client's preference
+                let find = |list: &[String], parse: fn(&str) -> Option<TextEncoding>| {
+                    list.iter().enumerate().find_map(|(index, item)| {
+                        parse(item)
+                            .filter(|encoding| handler.accept_encoding(*encoding))
+                            .map(|encoding| (index, encoding))
+                    })
+                };
+                let (category, index) = match find(&encodings, TextEncoding::from_name)
+                    .map(|found| (0, found))
+                    .or_else(|| {
+                        find(&encoding_infos, TextEncoding::from_info).map(|found| (1, found))
+                    }) {
+                    Some((category, (index, encoding))) => {
+                        log::debug!("Negotiated encoding: {}", encoding.name());
+                        self.get_input_method(input_method_id)?.encoding = encoding;
+                        (category, index as i16)
                     }
-                    None => {
-                        server.send_req(
-                            self.client_win,
-                            Request::EncodingNegotiationReply {
-                                input_method_id,
-                                category: 0,
-                                index: -1,
-                            },
-                        )?;
-                    }
-                }
+                    None => (0, -1),
+                };
+
+                server.send_req(
+                    self.client_win,
+                    self.endian,
+                    Request::EncodingNegotiationReply {
+                        input_method_id,
+                        category,
+                        index,
+                    },
+                )?;
             }
             Request::ResetIc {
                 input_method_id,
@@ -406,10 +640,11 @@ impl<T> XimConnection<T> {
                 let ret = handler.handle_reset_ic(server, ic)?;
                 server.send_req(
                     ic.ic.client_win(),
+                    ic.ic.endian(),
                     Request::ResetIcReply {
                         input_method_id,
                         input_context_id,
-                        preedit_string: xim_ctext::utf8_to_compound_text(&ret),
+                        preedit_string: ic.ic.encoding().encode(&ret, ic.ic.encode_strategy()),
                     },
                 )?;
             }
@@ -417,32 +652,61 @@ impl<T> XimConnection<T> {
                 input_method_id,
                 im_attributes,
             } => {
+                self.get_input_method(input_method_id)?;
                 let mut out = Vec::with_capacity(im_attributes.len());
 
-                for name in im_attributes.into_iter().filter_map(attrs::get_name) {
-                    match name {
-                        AttributeName::QueryInputStyle => {
-                            out.push(Attribute {
-                                id: attrs::get_id(name),
-                                value: xim_parser::write_to_vec(InputStyleList {
-                                    styles: handler.input_styles().as_ref().to_vec(),
-                                }),
-                            });
-                        }
-                        _ => {
+                for id in im_attributes {
+                    // Every advertised id is valid, only the ones of im attributes have a value
+                    let name = match IM_ATTRS.iter().chain(IC_ATTRS).find(|attr| attr.id == id) {
+                        Some(attr) => attr.name,
+                        None => {
                             return server.error(
                                 self.client_win,
+                                self.endian,
                                 ErrorCode::BadName,
-                                "Unknown im attribute name".into(),
+                                format!("Unknown im attribute id: {}", id),
                                 NonZeroU16::new(input_method_id),
                                 None,
                             );
                         }
-                    }
+                    };
+                    let value = match name {
+                        AttributeName::QueryInputStyle => xim_parser::write_to_vec_with_endian(
+                            InputStyleList {
+                                styles: handler.input_styles().as_ref().to_vec(),
+                            },
+                            self.endian,
+                        ),
+                        AttributeName::QueryIMValuesList => {
+                            xim_parser::write_to_vec_with_endian(values_list(IM_ATTRS), self.endian)
+                        }
+                        AttributeName::QueryICValuesList => {
+                            xim_parser::write_to_vec_with_endian(values_list(IC_ATTRS), self.endian)
+                        }
+                        AttributeName::ResourceName => handler.resource_name().as_bytes().to_vec(),
+                        AttributeName::ResourceClass => {
+                            handler.resource_class().as_bytes().to_vec()
+                        }
+                        // Callbacks live in client, there is nothing to return
+                        AttributeName::DestroyCallback => {
+                            xim_parser::write_to_vec_with_endian(0u32, self.endian)
+                        }
+                        AttributeName::VisiblePosition => xim_parser::write_to_vec_with_endian(
+                            handler.visible_position() as u32,
+                            self.endian,
+                        ),
+                        _ => {
+                            log::warn!("Skip unsupported im attribute {}", name.name());
+                            continue;
+                        }
+                    };
+
+                    out.push(Attribute { id, value });
                 }
 
                 server.send_req(
                     self.client_win,
+                    self.endian,
                     Request::GetImValuesReply {
                         input_method_id,
                         im_attributes: out,
@@ -450,6 +714,34 @@ impl<T> XimConnection<T> {
                 )?;
             }
 
+            Request::SetImValues {
+                input_method_id,
+                attributes,
+            } => {
+                let endian = self.endian;
+                let im = self.get_input_method(input_method_id)?;
+                let lookup = |id| IM_ATTRS.iter().find(|attr| attr.id == id).cloned();
+                let mut values = Vec::with_capacity(attributes.len());
+
+                for attribute in attributes {
+                    match lookup(attribute.id) {
+                        Some(attr) => values.push((
+                            attr.name,
+                            AttributeValue::read(attr.ty, &attribute.value, endian, &lookup)?,
+                        )),
+                        None => log::warn!("Unknown im attr id: {}", attribute.id),
+                    }
+                }
+
+                handler.handle_set_im_values(server, im, values)?;
+
+                server.send_req(
+                    self.client_win,
+                    self.endian,
+                    Request::SetImValuesReply { input_method_id },
+                )?;
+            }
+
             Request::GetIcValues {
                 input_method_id,
                 input_context_id,
@@ -461,45 +753,42 @@ impl<T> XimConnection<T> {
                     .ic;
                 let mut out = Vec::with_capacity(ic_attributes.len());
 
-                for name in ic_attributes.into_iter().filter_map(attrs::get_name) {
+                let mut ids = ic_attributes.into_iter();
+
+                while let Some(id) = ids.next() {
+                    let name = match attrs::get_name(id) {
+                        Some(name) => name,
+                        None => continue,
+                    };
+
                     match name {
-                        AttributeName::InputStyle => out.push(Attribute {
-                            id: attrs::get_id(name),
-                            value: xim_parser::write_to_vec(ic.input_style()),
-                        }),
-                        AttributeName::ClientWindow => out.push(Attribute {
-                            id: attrs::get_id(name),
-                            value: xim_parser::write_to_vec(
-                                ic.app_win().map_or(0, NonZeroU32::get),
-                            ),
-                        }),
-                        AttributeName::FocusWindow => out.push(Attribute {
-                            id: attrs::get_id(name),
-                            value: xim_parser::write_to_vec(
-                                ic.app_focus_win().map_or(0, NonZeroU32::get),
-                            ),
-                        }),
                         AttributeName::FilterEvents => out.push(Attribute {
-                            id: attrs::get_id(name),
-                            value: xim_parser::write_to_vec(handler.filter_events()),
+                            id,
+                            value: xim_parser::write_to_vec_with_endian(
+                                handler.filter_events(),
+                                ic.endian(),
+                            ),
                         }),
                         AttributeName::QueryInputStyle => {
                             return server.error(
                                 self.client_win,
+                                self.endian,
                                 ErrorCode::BadName,
                                 "Unknown ic attribute name".into(),
                                 NonZeroU16::new(input_method_id),
                                 None,
                             );
                         }
-                        name => {
-                            log::warn!("Unimplemented attribute {:?}", name);
-                        }
+                        name => match get_ic_attr(ic, name, &mut ids) {
+                            Some(value) => out.push(Attribute { id, value }),
+                            None => log::warn!("Attribute {:?} is not set", name),
+                        },
                     }
                 }
 
                 server.send_req(
                     self.client_win,
+                    self.endian,
                     Request::GetIcValuesReply {
                         ic_attributes: out,
                         input_method_id,
@@ -517,10 +806,28 @@ impl<T> XimConnection<T> {
                     .get_input_method(input_method_id)?
                     .get_input_context(input_context_id)?;
 
-                set_ic_attrs(&mut ic.ic, ic_attributes);
+                let input_styles = handler.input_styles();
+
+                if let Err((code, detail)) = set_ic_attrs(
+                    server,
+                    &mut ic.ic,
+                    ic_attributes,
+                    input_styles.as_ref(),
+                    false,
+                ) {
+                    return server.error(
+                        ic.ic.client_win(),
+                        ic.ic.endian(),
+                        code,
+                        detail,
+                        Some(ic.ic.input_method_id()),
+                        Some(ic.ic.input_context_id()),
+                    );
+                }
 
                 server.send_req(
                     ic.ic.client_win(),
+                    ic.ic.endian(),
                     Request::SetIcValuesReply {
                         input_method_id,
                         input_context_id,
@@ -553,6 +860,38 @@ impl<T> XimConnection<T> {
             // Ignore start reply
             Request::PreeditStartReply { .. } => {}
 
+            Request::TriggerNotify {
+                input_method_id,
+                input_context_id,
+                flag,
+                index,
+                event_mask: _,
+            } => {
+                let ic = self
+                    .get_input_method(input_method_id)?
+                    .get_input_context(input_context_id)?;
+                handler.handle_trigger_notify(server, ic, flag, index)?;
+                server.send_req(
+                    ic.ic.client_win(),
+                    ic.ic.endian(),
+                    Request::TriggerNotifyReply {
+                        input_method_id,
+                        input_context_id,
+                    },
+                )?;
+            }
+
+            Request::PreeditCaretReply {
+                input_method_id,
+                input_context_id,
+                position,
+            } => {
+                let ic = self
+                    .get_input_method(input_method_id)?
+                    .get_input_context(input_context_id)?;
+                handler.handle_preedit_caret_reply(server, ic, position)?;
+            }
+
             Request::ForwardEvent {
                 input_method_id,
                 input_context_id,
@@ -569,6 +908,7 @@ impl<T> XimConnection<T> {
                 if !consumed {
                     server.send_req(
                         self.client_win,
+                        self.endian,
                         Request::ForwardEvent {
                             input_method_id,
                             input_context_id,
@@ -582,6 +922,7 @@ impl<T> XimConnection<T> {
                 if flag.contains(ForwardEventFlag::SYNCHRONOUS) {
                     server.send_req(
                         self.client_win,
+                        self.endian,
                         Request::SyncReply {
                             input_method_id,
                             input_context_id,
@@ -596,6 +937,7 @@ impl<T> XimConnection<T> {
             } => {
                 server.send_req(
                     self.client_win,
+                    self.endian,
                     Request::SyncReply {
                         input_method_id,
                         input_context_id,
@@ -603,7 +945,16 @@ impl<T> XimConnection<T> {
                 )?;
             }
 
-            Request::SyncReply { .. } => {}
+            Request::SyncReply {
+                input_method_id,
+                input_context_id,
+            } => {
+                let ic = self
+                    .get_input_method(input_method_id)?
+                    .get_input_context(input_context_id)?;
+                ic.ic.outstanding_syncs = ic.ic.outstanding_syncs.saturating_sub(1);
+                handler.handle_sync_reply(server, ic)?;
+            }
 
             _ => {
                 log::warn!("Unknown request: {:?}", req);
@@ -643,4 +994,12 @@ impl<T> XimConnections<T> {
     pub fn remove_connection(&mut self, com_win: u32) -> Option<XimConnection<T>> {
         self.connections.remove(&com_win)
     }
+
+    /// Find communication window of the connection with `client_win`
+    pub fn find_com_win(&self, client_win: u32) -> Option<u32> {
+        self.connections
+            .iter()
+            .find(|(_, connection)| connection.client_win == client_win)
+            .map(|(com_win, _)| *com_win)
+    }
 }
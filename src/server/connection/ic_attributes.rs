@@ -0,0 +1,415 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::num::NonZeroU32;
+use xim_parser::{
+    attrs, Attribute, AttributeName, Endian, ErrorCode, FontSet, HotKeyState, HotKeyTriggers,
+    InputStyle, Point, Rectangle, XimRead, XimWrite,
+};
+
+use super::{InputContext, IC_ATTRS};
+use crate::server::ServerCore;
+
+/// Attributes inside `PreeditAttributes` or `StatusAttributes` nested list
+#[derive(Clone, Debug, Default)]
+pub struct NestedAttributes {
+    area: Option<Rectangle>,
+    area_needed: Option<Rectangle>,
+    spot_location: Option<Point>,
+    color_map: Option<u32>,
+    std_color_map: Option<u32>,
+    foreground: Option<u32>,
+    background: Option<u32>,
+    background_pixmap: Option<u32>,
+    font_set: Option<String>,
+    line_space: Option<u32>,
+    cursor: Option<u32>,
+}
+
+impl NestedAttributes {
+    pub fn area(&self) -> Option<Rectangle> {
+        self.area.clone()
+    }
+
+    pub fn area_needed(&self) -> Option<Rectangle> {
+        self.area_needed.clone()
+    }
+
+    pub fn spot_location(&self) -> Option<Point> {
+        self.spot_location.clone()
+    }
+
+    pub fn color_map(&self) -> Option<u32> {
+        self.color_map
+    }
+
+    pub fn std_color_map(&self) -> Option<u32> {
+        self.std_color_map
+    }
+
+    pub fn foreground(&self) -> Option<u32> {
+        self.foreground
+    }
+
+    pub fn background(&self) -> Option<u32> {
+        self.background
+    }
+
+    pub fn background_pixmap(&self) -> Option<u32> {
+        self.background_pixmap
+    }
+
+    /// Base font name list of the font set
+    pub fn font_set(&self) -> Option<&str> {
+        self.font_set.as_deref()
+    }
+
+    pub fn line_space(&self) -> Option<u32> {
+        self.line_space
+    }
+
+    pub fn cursor(&self) -> Option<u32> {
+        self.cursor
+    }
+
+    fn set(&mut self, name: AttributeName, value: &[u8], endian: Endian) -> Result<(), AttrError> {
+        match name {
+            AttributeName::Area => self.area = Some(read_value(name, value, endian)?),
+            AttributeName::AreaNeeded => self.area_needed = Some(read_value(name, value, endian)?),
+            AttributeName::SpotLocation => {
+                self.spot_location = Some(read_value(name, value, endian)?)
+            }
+            AttributeName::ColorMap => self.color_map = Some(read_value(name, value, endian)?),
+            AttributeName::StdColorMap => {
+                self.std_color_map = Some(read_value(name, value, endian)?)
+            }
+            AttributeName::Foreground => self.foreground = Some(read_value(name, value, endian)?),
+            AttributeName::Background => self.background = Some(read_value(name, value, endian)?),
+            AttributeName::BackgroundPixmap => {
+                self.background_pixmap = Some(read_value(name, value, endian)?)
+            }
+            AttributeName::FontSet => {
+                self.font_set = Some(read_value::<FontSet>(name, value, endian)?.name)
+            }
+            AttributeName::LineSpace => self.line_space = Some(read_value(name, value, endian)?),
+            AttributeName::Cursor => self.cursor = Some(read_value(name, value, endian)?),
+            name => {
+                log::warn!("Ignore unhandled nested attr: {:?}", name);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, name: AttributeName, endian: Endian) -> Option<Vec<u8>> {
+        match name {
+            AttributeName::Area => to_vec(&self.area, endian),
+            AttributeName::AreaNeeded => to_vec(&self.area_needed, endian),
+            AttributeName::SpotLocation => to_vec(&self.spot_location, endian),
+            AttributeName::ColorMap => to_vec(&self.color_map, endian),
+            AttributeName::StdColorMap => to_vec(&self.std_color_map, endian),
+            AttributeName::Foreground => to_vec(&self.foreground, endian),
+            AttributeName::Background => to_vec(&self.background, endian),
+            AttributeName::BackgroundPixmap => to_vec(&self.background_pixmap, endian),
+            AttributeName::FontSet => self.font_set.as_ref().map(|name| {
+                xim_parser::write_to_vec_with_endian(FontSet { name: name.clone() }, endian)
+            }),
+            AttributeName::LineSpace => to_vec(&self.line_space, endian),
+            AttributeName::Cursor => to_vec(&self.cursor, endian),
+            _ => None,
+        }
+    }
+
+    fn set_nested_list(&mut self, value: &[u8], endian: Endian) -> Result<(), AttrError> {
+        let mut b = value;
+        while !b.is_empty() {
+            let attr: Attribute = xim_parser::read_with_endian(b, endian).map_err(|e| {
+                (
+                    ErrorCode::BadSomething,
+                    format!("Invalid nested list: {}", e),
+                )
+            })?;
+            b = &b[attr.size().min(b.len())..];
+            match get_advertised_name(attr.id)? {
+                AttributeName::SeparatorofNestedList => {}
+                name => self.set(name, &attr.value, endian)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encode attributes of `ids` until `SeparatorofNestedList`, unset attributes are skipped
+    fn get_nested_list(&self, ids: &mut impl Iterator<Item = u16>, endian: Endian) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        for id in ids {
+            let name = match attrs::get_name(id) {
+                Some(AttributeName::SeparatorofNestedList) => break,
+                Some(name) => name,
+                None => continue,
+            };
+
+            if let Some(value) = self.get(name, endian) {
+                xim_parser::write_extend_vec_with_endian(Attribute { id, value }, &mut out, endian);
+            }
+        }
+
+        out
+    }
+}
+
+/// Error code and detail sent to client for a rejected attribute
+pub(super) type AttrError = (ErrorCode, String);
+
+fn error_code(name: AttributeName) -> ErrorCode {
+    match name {
+        AttributeName::InputStyle => ErrorCode::BadStyle,
+        AttributeName::ClientWindow => ErrorCode::BadClientWindow,
+        AttributeName::FocusWindow => ErrorCode::BadFocusWindow,
+        AttributeName::Area | AttributeName::AreaNeeded => ErrorCode::BadArea,
+        AttributeName::SpotLocation => ErrorCode::BadSpotLocation,
+        AttributeName::ColorMap | AttributeName::StdColorMap => ErrorCode::BadColormap,
+        AttributeName::Foreground => ErrorCode::BadForeground,
+        AttributeName::Background => ErrorCode::BadBackground,
+        AttributeName::BackgroundPixmap => ErrorCode::BadPixmap,
+        AttributeName::Cursor => ErrorCode::BadCursor,
+        _ => ErrorCode::BadSomething,
+    }
+}
+
+/// Only attributes sent in `OpenReply` can be set
+fn get_advertised_name(id: u16) -> Result<AttributeName, AttrError> {
+    IC_ATTRS
+        .iter()
+        .find(|attr| attr.id == id)
+        .map(|attr| attr.name)
+        .ok_or_else(|| {
+            (
+                ErrorCode::BadName,
+                format!("Unknown ic attribute id {}", id),
+            )
+        })
+}
+
+fn read_value<T: XimRead>(
+    name: AttributeName,
+    value: &[u8],
+    endian: Endian,
+) -> Result<T, AttrError> {
+    xim_parser::read_with_endian(value, endian)
+        .map_err(|e| (error_code(name), format!("Invalid {}: {}", name.name(), e)))
+}
+
+fn read_window<S: ServerCore>(
+    server: &S,
+    name: AttributeName,
+    value: &[u8],
+    endian: Endian,
+) -> Result<NonZeroU32, AttrError> {
+    let window: u32 = read_value(name, value, endian)?;
+
+    match NonZeroU32::new(window) {
+        Some(window) if server.window_exists(window.get()) => Ok(window),
+        _ => Err((
+            error_code(name),
+            format!("Window {} for {} doesn't exist", window, name.name()),
+        )),
+    }
+}
+
+fn to_vec<T: XimWrite>(value: &Option<T>, endian: Endian) -> Option<Vec<u8>> {
+    value
+        .as_ref()
+        .map(|value| xim_parser::write_to_vec_with_endian(value, endian))
+}
+
+/// `Char` attributes are sent as bytes without terminator
+fn read_char(value: &[u8]) -> String {
+    String::from_utf8_lossy(value).trim_end_matches('\0').into()
+}
+
+/// IC attribute read from client, applied after every attribute of the request is valid
+enum IcValue {
+    InputStyle(InputStyle),
+    ClientWindow(NonZeroU32),
+    FocusWindow(NonZeroU32),
+    ResourceName(String),
+    ResourceClass(String),
+    PreeditState(u32),
+    ResetState(u32),
+    HotKey(HotKeyTriggers),
+    HotKeyState(HotKeyState),
+}
+
+/// Apply `ic_attributes` to `ic`, nothing is applied if any attribute is rejected
+///
+/// `InputStyle` can only be set when `creating` the input context.
+pub(super) fn set_ic_attrs<S: ServerCore>(
+    server: &S,
+    ic: &mut InputContext,
+    ic_attributes: Vec<Attribute>,
+    input_styles: &[InputStyle],
+    creating: bool,
+) -> Result<(), AttrError> {
+    let endian = ic.endian;
+    let mut values = Vec::with_capacity(ic_attributes.len());
+    let mut preedit_attributes = None;
+    let mut status_attributes = None;
+
+    for attr in ic_attributes {
+        let name = get_advertised_name(attr.id)?;
+
+        let value = match name {
+            AttributeName::InputStyle => {
+                if !creating {
+                    return Err((
+                        ErrorCode::BadStyle,
+                        "Input style can't be changed after CreateIc".into(),
+                    ));
+                }
+                let style = read_value(name, &attr.value, endian)?;
+                log::debug!("Style: {:?}", style);
+                if !input_styles.contains(&style) {
+                    return Err((
+                        ErrorCode::BadStyle,
+                        format!("Unsupported input style {:?}", style),
+                    ));
+                }
+                IcValue::InputStyle(style)
+            }
+            AttributeName::ClientWindow => {
+                IcValue::ClientWindow(read_window(server, name, &attr.value, endian)?)
+            }
+            AttributeName::FocusWindow => {
+                IcValue::FocusWindow(read_window(server, name, &attr.value, endian)?)
+            }
+            AttributeName::ResourceName => IcValue::ResourceName(read_char(&attr.value)),
+            AttributeName::ResourceClass => IcValue::ResourceClass(read_char(&attr.value)),
+            AttributeName::PreeditState => {
+                IcValue::PreeditState(read_value(name, &attr.value, endian)?)
+            }
+            AttributeName::ResetState => {
+                IcValue::ResetState(read_value(name, &attr.value, endian)?)
+            }
+            AttributeName::HotKey => IcValue::HotKey(read_value(name, &attr.value, endian)?),
+            AttributeName::HotKeyState => {
+                IcValue::HotKeyState(read_value(name, &attr.value, endian)?)
+            }
+            AttributeName::PreeditAttributes => {
+                preedit_attributes
+                    .get_or_insert_with(|| ic.preedit_attributes.clone())
+                    .set_nested_list(&attr.value, endian)?;
+                continue;
+            }
+            AttributeName::StatusAttributes => {
+                status_attributes
+                    .get_or_insert_with(|| ic.status_attributes.clone())
+                    .set_nested_list(&attr.value, endian)?;
+                continue;
+            }
+            name => {
+                log::warn!("Ignore unhandled attr: {:?}", name);
+                continue;
+            }
+        };
+
+        values.push(value);
+    }
+
+    for value in values {
+        match value {
+            IcValue::InputStyle(style) => ic.input_style = style,
+            IcValue::ClientWindow(window) => ic.app_win = Some(window),
+            IcValue::FocusWindow(window) => ic.app_focus_win = Some(window),
+            IcValue::ResourceName(name) => ic.resource_name = Some(name),
+            IcValue::ResourceClass(class) => ic.resource_class = Some(class),
+            IcValue::PreeditState(state) => ic.preedit_state = Some(state),
+            IcValue::ResetState(state) => ic.reset_state = Some(state),
+            IcValue::HotKey(triggers) => ic.hot_key = Some(triggers),
+            IcValue::HotKeyState(state) => ic.hot_key_state = Some(state),
+        }
+    }
+
+    if let Some(attributes) = preedit_attributes {
+        ic.preedit_attributes = attributes;
+    }
+
+    if let Some(attributes) = status_attributes {
+        ic.status_attributes = attributes;
+    }
+
+    Ok(())
+}
+
+/// Encode IC attribute `name`, nested lists take their ids from `ids`
+///
+/// Return `None` when attribute is not set or can't be read from client
+pub(super) fn get_ic_attr(
+    ic: &InputContext,
+    name: AttributeName,
+    ids: &mut impl Iterator<Item = u16>,
+) -> Option<Vec<u8>> {
+    let endian = ic.endian;
+
+    match name {
+        AttributeName::InputStyle => {
+            Some(xim_parser::write_to_vec_with_endian(ic.input_style, endian))
+        }
+        AttributeName::ClientWindow => Some(xim_parser::write_to_vec_with_endian(
+            ic.app_win.map_or(0, NonZeroU32::get),
+            endian,
+        )),
+        AttributeName::FocusWindow => Some(xim_parser::write_to_vec_with_endian(
+            ic.app_focus_win.map_or(0, NonZeroU32::get),
+            endian,
+        )),
+        AttributeName::ResourceName => ic.resource_name.clone().map(String::into_bytes),
+        AttributeName::ResourceClass => ic.resource_class.clone().map(String::into_bytes),
+        AttributeName::PreeditState => to_vec(&ic.preedit_state, endian),
+        AttributeName::ResetState => to_vec(&ic.reset_state, endian),
+        AttributeName::HotKey => to_vec(&ic.hot_key, endian),
+        AttributeName::HotKeyState => to_vec(&ic.hot_key_state, endian),
+        AttributeName::PreeditAttributes => {
+            Some(ic.preedit_attributes.get_nested_list(ids, endian))
+        }
+        AttributeName::StatusAttributes => Some(ic.status_attributes.get_nested_list(ids, endian)),
+        _ => None,
+    }
+}
+
+impl InputContext {
+    pub fn resource_name(&self) -> Option<&str> {
+        self.resource_name.as_deref()
+    }
+
+    pub fn resource_class(&self) -> Option<&str> {
+        self.resource_class.as_deref()
+    }
+
+    pub fn preedit_attributes(&self) -> &NestedAttributes {
+        &self.preedit_attributes
+    }
+
+    pub fn status_attributes(&self) -> &NestedAttributes {
+        &self.status_attributes
+    }
+
+    /// `XIMPreeditEnable` (1) or `XIMPreeditDisable` (2)
+    pub fn preedit_state(&self) -> Option<u32> {
+        self.preedit_state
+    }
+
+    /// `XIMInitialState` (1) or `XIMPreserveState` (2)
+    pub fn reset_state(&self) -> Option<u32> {
+        self.reset_state
+    }
+
+    pub fn hot_key(&self) -> Option<&HotKeyTriggers> {
+        self.hot_key.as_ref()
+    }
+
+    pub fn hot_key_state(&self) -> Option<HotKeyState> {
+        self.hot_key_state
+    }
+}
@@ -2,6 +2,10 @@ use crate::AHashMap;
 use core::num::NonZeroU16;
 use hashbrown::hash_map::Entry;
 
+fn wrapping_next(idx: NonZeroU16) -> NonZeroU16 {
+    NonZeroU16::new(idx.get().wrapping_add(1)).unwrap_or_else(|| NonZeroU16::new(1).unwrap())
+}
+
 pub struct ImVec<T> {
     next: NonZeroU16,
     inner: AHashMap<NonZeroU16, T>,
@@ -15,24 +19,36 @@ impl<T> ImVec<T> {
         }
     }
 
-    fn next(&mut self) -> NonZeroU16 {
-        let ret = self.next;
-        self.next = NonZeroU16::new(self.next.get() + 1).unwrap();
-        ret
+    /// Return `false` when every id is in use
+    pub fn has_free_id(&self) -> bool {
+        self.inner.len() < u16::MAX as usize
     }
 
-    pub fn new_item(&mut self, data: T) -> (NonZeroU16, &mut T) {
-        let idx = self.next();
+    /// Find a free id from `next`, wrapping around after `u16::MAX`
+    fn next(&mut self) -> Option<NonZeroU16> {
+        if !self.has_free_id() {
+            return None;
+        }
+
+        let mut idx = self.next;
+
+        while self.inner.contains_key(&idx) {
+            idx = wrapping_next(idx);
+        }
+
+        self.next = wrapping_next(idx);
 
-        let val = match self.inner.entry(idx) {
-            Entry::Occupied(mut o) => {
-                o.insert(data);
-                o.into_mut()
-            }
-            Entry::Vacant(v) => v.insert(data),
-        };
+        Some(idx)
+    }
+
+    /// Return `None` when every id is in use
+    pub fn new_item(&mut self, data: T) -> Option<(NonZeroU16, &mut T)> {
+        let idx = self.next()?;
 
-        (idx, val)
+        match self.inner.entry(idx) {
+            Entry::Occupied(_) => None,
+            Entry::Vacant(v) => Some((idx, v.insert(data))),
+        }
     }
 
     #[allow(unused)]
@@ -58,3 +74,54 @@ impl<T> IntoIterator for ImVec<T> {
         self.inner.into_iter()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ImVec;
+
+    #[test]
+    fn reuse_removed_id_after_wrap() {
+        let mut v = ImVec::new();
+
+        for i in 1..=u16::MAX {
+            assert_eq!(v.new_item(i).unwrap().0.get(), i);
+        }
+
+        v.remove_item(3).unwrap();
+        v.remove_item(100).unwrap();
+
+        assert_eq!(v.new_item(0).unwrap().0.get(), 3);
+        assert_eq!(v.new_item(0).unwrap().0.get(), 100);
+    }
+
+    #[test]
+    fn full() {
+        let mut v = ImVec::new();
+
+        for i in 1..=u16::MAX {
+            v.new_item(i).unwrap();
+        }
+
+        assert!(!v.has_free_id());
+        assert!(v.new_item(0).is_none());
+        assert_eq!(v.get_item(u16::MAX).copied(), Some(u16::MAX));
+
+        v.remove_item(u16::MAX).unwrap();
+        assert!(v.has_free_id());
+        assert_eq!(v.new_item(0).unwrap().0.get(), u16::MAX);
+        assert!(v.new_item(0).is_none());
+    }
+
+    #[test]
+    fn skip_used_id() {
+        let mut v = ImVec::new();
+
+        let first = v.new_item(()).unwrap().0;
+        let second = v.new_item(()).unwrap().0;
+        v.remove_item(first.get()).unwrap();
+
+        let third = v.new_item(()).unwrap().0;
+        assert_ne!(third, second);
+        assert_ne!(third, first);
+    }
+}
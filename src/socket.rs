@@ -0,0 +1,473 @@
+//! Provides an implementation of XIM over TCP and Unix domain sockets.
+//!
+//! These are the `tcp/` and `local/` entries of `@transport=`. Requests are written as is,
+//! the length in each request header is enough to split the stream into requests.
+//!
+//! Advertise the socket of a `SocketServer` with `X11rbServer::set_transports`, and
+//! connect a `SocketClient` to one of `X11rbClient::transports`.
+
+use alloc::format;
+use alloc::vec::Vec;
+use std::io::{self, Read, Write};
+#[cfg(feature = "socket-server")]
+use std::net::TcpListener;
+use std::net::TcpStream;
+#[cfg(all(unix, feature = "socket-server"))]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+#[cfg(feature = "socket-client")]
+use crate::client::{
+    handle_request as client_handle_request, ClientCore, ClientError, ClientHandler, ClientState,
+};
+#[cfg(feature = "socket-server")]
+use crate::server::{ServerCore, ServerError, ServerHandler, XimConnections};
+use crate::{AHashMap, Transport};
+#[cfg(feature = "socket-client")]
+use xim_parser::{Attr, AttributeName};
+use xim_parser::{Endian, Request, XEvent, XimWrite};
+
+#[cfg(feature = "socket-client")]
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> Self {
+        ClientError::Other(err.into())
+    }
+}
+
+#[cfg(feature = "socket-server")]
+impl From<io::Error> for ServerError {
+    fn from(err: io::Error) -> Self {
+        ServerError::Other(err.into())
+    }
+}
+
+const CONNECT_OPCODE: u8 = 1;
+
+/// Connected TCP or Unix domain socket
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn connect(transport: &Transport) -> io::Result<Self> {
+        match transport {
+            Transport::Tcp(addr) => Ok(Stream::Tcp(TcpStream::connect(addr.as_str())?)),
+            #[cfg(unix)]
+            Transport::Local(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Can't connect to {}", transport),
+            )),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// Read one request, `endian` is ignored for `Connect` which carries its own byte order
+pub fn read_frame(r: &mut impl Read, endian: Endian, buf: &mut Vec<u8>) -> io::Result<()> {
+    buf.resize(4, 0);
+    r.read_exact(buf)?;
+
+    let endian = if buf[0] == CONNECT_OPCODE {
+        buf.resize(5, 0);
+        r.read_exact(&mut buf[4..])?;
+        match buf[4] {
+            b'B' => Endian::Big,
+            b'l' => Endian::Little,
+            _ => endian,
+        }
+    } else {
+        endian
+    };
+
+    let length = match endian {
+        Endian::Big => u16::from_be_bytes([buf[2], buf[3]]),
+        Endian::Little => u16::from_le_bytes([buf[2], buf[3]]),
+    };
+    let read = buf.len();
+    buf.resize(4 + length as usize * 4, 0);
+
+    if buf.len() < read {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Request length is too short",
+        ));
+    }
+
+    r.read_exact(&mut buf[read..])
+}
+
+fn write_frame(
+    w: &mut impl Write,
+    endian: Endian,
+    buf: &mut Vec<u8>,
+    req: &Request,
+) -> io::Result<()> {
+    if log::log_enabled!(log::Level::Trace) {
+        log::trace!("->: {:?}", req);
+    } else {
+        log::debug!("->: {}", req.name());
+    }
+    buf.resize(req.size(), 0);
+    xim_parser::write_with_endian(req, buf, endian);
+    let ret = w.write_all(buf).and_then(|_| w.flush());
+    buf.clear();
+    ret
+}
+
+#[cfg(feature = "socket-client")]
+pub struct SocketClient {
+    stream: Stream,
+    endian: Endian,
+    im_attributes: AHashMap<AttributeName, Attr>,
+    ic_attributes: AHashMap<AttributeName, Attr>,
+    state: ClientState,
+    buf: Vec<u8>,
+}
+
+#[cfg(feature = "socket-client")]
+impl SocketClient {
+    /// Connect to `transport` and send `Connect`
+    pub fn connect(transport: &Transport) -> Result<Self, ClientError> {
+        let stream = Stream::connect(transport).map_err(|e| match e.kind() {
+            io::ErrorKind::Unsupported => ClientError::UnsupportedTransport,
+            _ => e.into(),
+        })?;
+
+        let mut client = Self {
+            stream,
+            endian: Endian::NATIVE,
+            im_attributes: AHashMap::with_hasher(Default::default()),
+            ic_attributes: AHashMap::with_hasher(Default::default()),
+            state: ClientState::default(),
+            buf: Vec::with_capacity(1024),
+        };
+
+        client.send_req(Request::Connect {
+            client_major_protocol_version: 1,
+            client_minor_protocol_version: 0,
+            endian: client.endian,
+            client_auth_protocol_names: Vec::new(),
+        })?;
+
+        Ok(client)
+    }
+
+    /// Use it to wait until the socket is readable
+    pub fn stream(&self) -> &Stream {
+        &self.stream
+    }
+
+    /// Block until one request is read and handle it
+    pub fn read_request(
+        &mut self,
+        handler: &mut impl ClientHandler<Self>,
+    ) -> Result<(), ClientError> {
+        let mut buf = core::mem::take(&mut self.buf);
+        let ret = read_frame(&mut self.stream, self.endian, &mut buf);
+        let req = ret.map_err(ClientError::from).and_then(|_| {
+            xim_parser::read_with_endian(&buf, self.endian).map_err(ClientError::from)
+        });
+        buf.clear();
+        self.buf = buf;
+
+        client_handle_request(self, handler, req?)
+    }
+}
+
+#[cfg(feature = "socket-client")]
+impl ClientCore for SocketClient {
+    type XEvent = XEvent;
+
+    fn set_attrs(&mut self, im_attrs: Vec<Attr>, ic_attrs: Vec<Attr>) {
+        for im_attr in im_attrs {
+            self.im_attributes.insert(im_attr.name, im_attr);
+        }
+
+        for ic_attr in ic_attrs {
+            self.ic_attributes.insert(ic_attr.name, ic_attr);
+        }
+    }
+
+    #[inline]
+    fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    #[inline]
+    fn ic_attributes(&self) -> &AHashMap<AttributeName, Attr> {
+        &self.ic_attributes
+    }
+
+    #[inline]
+    fn im_attributes(&self) -> &AHashMap<AttributeName, Attr> {
+        &self.im_attributes
+    }
+
+    #[inline]
+    fn state(&self) -> &ClientState {
+        &self.state
+    }
+
+    #[inline]
+    fn state_mut(&mut self) -> &mut ClientState {
+        &mut self.state
+    }
+
+    #[inline]
+    fn serialize_event(&self, xev: &Self::XEvent) -> XEvent {
+        xev.clone()
+    }
+
+    #[inline]
+    fn deserialize_event(&self, xev: &XEvent) -> Self::XEvent {
+        xev.clone()
+    }
+
+    #[inline]
+    fn send_req(&mut self, req: Request) -> Result<(), ClientError> {
+        write_frame(&mut self.stream, self.endian, &mut self.buf, &req)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "socket-server")]
+#[derive(Debug)]
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+#[cfg(feature = "socket-server")]
+pub struct SocketServer {
+    listener: Listener,
+    transport: Transport,
+    streams: AHashMap<u32, Stream>,
+    next_client: u32,
+    buf: Vec<u8>,
+}
+
+#[cfg(feature = "socket-server")]
+impl SocketServer {
+    /// Listen on `transport`, binding `tcp/host:0` picks a free port
+    pub fn bind(transport: &Transport) -> Result<Self, ServerError> {
+        let (listener, transport) = match transport {
+            Transport::Tcp(addr) => {
+                let listener = TcpListener::bind(addr.as_str())?;
+                let transport = Transport::Tcp(format!("{}", listener.local_addr()?));
+                (Listener::Tcp(listener), transport)
+            }
+            #[cfg(unix)]
+            Transport::Local(path) => {
+                (Listener::Unix(UnixListener::bind(path)?), transport.clone())
+            }
+            _ => {
+                return Err(ServerError::Internal(format!(
+                    "Can't listen on {}",
+                    transport
+                )))
+            }
+        };
+
+        Ok(Self {
+            listener,
+            transport,
+            streams: AHashMap::with_hasher(Default::default()),
+            next_client: 1,
+            buf: Vec::with_capacity(1024),
+        })
+    }
+
+    /// Transport clients can connect to, advertise it with `X11rbServer::set_transports`
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    /// Block until a client connects, return the id used as its `client_win` and `com_win`
+    pub fn accept<T>(&mut self, connections: &mut XimConnections<T>) -> Result<u32, ServerError> {
+        let stream = match &self.listener {
+            Listener::Tcp(l) => Stream::Tcp(l.accept()?.0),
+            #[cfg(unix)]
+            Listener::Unix(l) => Stream::Unix(l.accept()?.0),
+        };
+
+        let client = self.next_client;
+        self.next_client = self.next_client.wrapping_add(1).max(1);
+        self.streams.insert(client, stream);
+        connections.new_connection(client, client);
+
+        Ok(client)
+    }
+
+    /// Use it to wait until the socket of `client` is readable
+    pub fn stream(&self, client: u32) -> Option<&Stream> {
+        self.streams.get(&client)
+    }
+
+    /// Block until one request of `client` is read and handle it,
+    /// return `false` when the connection is closed
+    pub fn read_request<T>(
+        &mut self,
+        client: u32,
+        connections: &mut XimConnections<T>,
+        handler: &mut impl ServerHandler<Self, InputContextData = T>,
+    ) -> Result<bool, ServerError> {
+        let connection = connections
+            .get_connection(client)
+            .ok_or(ServerError::ClientNotExists)?;
+        let stream = self
+            .streams
+            .get_mut(&client)
+            .ok_or(ServerError::ClientNotExists)?;
+        let mut buf = core::mem::take(&mut self.buf);
+
+        let ret = read_frame(stream, connection.endian(), &mut buf)
+            .map(|()| xim_parser::read_with_endian(&buf, connection.endian()));
+        buf.clear();
+        self.buf = buf;
+
+        match ret {
+            Ok(req) => {
+                connection.handle_read(self, req, handler)?;
+
+                if connection.disconnected {
+                    self.remove_connection(client, connections, handler)?;
+                    Ok(false)
+                } else {
+                    Ok(true)
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                log::info!("Client {} closed", client);
+                self.remove_connection(client, connections, handler)?;
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn remove_connection<T>(
+        &mut self,
+        client: u32,
+        connections: &mut XimConnections<T>,
+        handler: &mut impl ServerHandler<Self, InputContextData = T>,
+    ) -> Result<(), ServerError> {
+        if let Some(mut connection) = connections.remove_connection(client) {
+            if !connection.disconnected {
+                connection.disconnect(self, handler)?;
+            }
+        }
+
+        self.streams.remove(&client);
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "socket-server")]
+impl ServerCore for SocketServer {
+    type XEvent = XEvent;
+
+    fn send_req(
+        &mut self,
+        client_win: u32,
+        endian: Endian,
+        req: Request,
+    ) -> Result<(), ServerError> {
+        let stream = self
+            .streams
+            .get_mut(&client_win)
+            .ok_or(ServerError::ClientNotExists)?;
+        write_frame(stream, endian, &mut self.buf, &req)?;
+        Ok(())
+    }
+
+    #[inline]
+    fn deserialize_event(&self, ev: &XEvent) -> Self::XEvent {
+        ev.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_frame, write_frame};
+    use alloc::vec::Vec;
+    use xim_parser::{Endian, Request};
+
+    fn round_trip(req: Request, writer_endian: Endian, reader_endian: Endian) {
+        let mut stream = Vec::new();
+        let mut buf = Vec::new();
+
+        write_frame(&mut stream, writer_endian, &mut buf, &req).unwrap();
+        write_frame(&mut stream, writer_endian, &mut buf, &req).unwrap();
+
+        let mut r = stream.as_slice();
+        for _ in 0..2 {
+            read_frame(&mut r, reader_endian, &mut buf).unwrap();
+            assert_eq!(
+                xim_parser::read_with_endian::<Request>(&buf, writer_endian).unwrap(),
+                req
+            );
+        }
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn connect_frame() {
+        let req = Request::Connect {
+            client_major_protocol_version: 1,
+            client_minor_protocol_version: 0,
+            endian: Endian::Big,
+            client_auth_protocol_names: Vec::new(),
+        };
+
+        round_trip(req, Endian::Big, Endian::Little);
+    }
+
+    #[test]
+    fn commit_frame() {
+        let req = Request::Commit {
+            input_method_id: 1,
+            input_context_id: 1,
+            data: xim_parser::CommitData::Chars {
+                commited: xim_ctext::utf8_to_compound_text("가나다라마바사"),
+                syncronous: false,
+            },
+        };
+
+        round_trip(req.clone(), Endian::Big, Endian::Big);
+        round_trip(req, Endian::Little, Endian::Little);
+    }
+}
@@ -0,0 +1,106 @@
+//! Transports a XIM server can advertise in its `TRANSPORT` selection.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// One entry of `@transport=X/,tcp/host:port,local/path`
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Transport {
+    /// `X/`, ClientMessage and property based transport
+    X,
+    /// `tcp/host:port`
+    Tcp(String),
+    /// `local/hostname:path`, Unix domain socket, only `path` is kept
+    Local(String),
+}
+
+impl Transport {
+    /// Parse `TRANSPORT` selection data, unknown transports are skipped
+    pub fn parse_list(data: &[u8]) -> Vec<Transport> {
+        let data = match core::str::from_utf8(data) {
+            Ok(data) => data.trim_end_matches('\0'),
+            Err(_) => return Vec::new(),
+        };
+
+        let list = match data.strip_prefix("@transport=") {
+            Some(list) => list,
+            None => return Vec::new(),
+        };
+
+        list.split(',')
+            .filter_map(|transport| {
+                let transport = transport.trim();
+
+                if transport == "X/" {
+                    Some(Transport::X)
+                } else if let Some(addr) = transport.strip_prefix("tcp/") {
+                    Some(Transport::Tcp(addr.into()))
+                } else if let Some(addr) = transport.strip_prefix("local/") {
+                    // `local/hostname:path`, hostname is optional
+                    let path = addr.split_once(':').map_or(addr, |(_, path)| path);
+                    Some(Transport::Local(path.into()))
+                } else {
+                    log::warn!("Unknown transport {}", transport);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Make `TRANSPORT` selection data from `transports`
+    pub fn to_list(transports: &[Transport]) -> String {
+        let mut ret = String::from("@transport=");
+
+        for (i, transport) in transports.iter().enumerate() {
+            if i != 0 {
+                ret.push(',');
+            }
+            fmt::write(&mut ret, format_args!("{}", transport)).unwrap();
+        }
+
+        ret
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::X => f.write_str("X/"),
+            Transport::Tcp(addr) => write!(f, "tcp/{}", addr),
+            Transport::Local(path) => write!(f, "local/{}", path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Transport;
+
+    #[test]
+    fn parse_list() {
+        let transports =
+            Transport::parse_list(b"@transport=X/,tcp/localhost:9010,local/tmp/xim,foo/");
+
+        assert_eq!(
+            transports,
+            [
+                Transport::X,
+                Transport::Tcp("localhost:9010".into()),
+                Transport::Local("tmp/xim".into()),
+            ]
+        );
+        assert_eq!(
+            Transport::to_list(&transports),
+            "@transport=X/,tcp/localhost:9010,local/tmp/xim"
+        );
+
+        assert_eq!(
+            Transport::parse_list(b"@transport=local/myhost:/tmp/.xim-unix,local/:/tmp/xim"),
+            [
+                Transport::Local("/tmp/.xim-unix".into()),
+                Transport::Local("/tmp/xim".into()),
+            ]
+        );
+    }
+}
@@ -5,23 +5,25 @@
 //!
 //! [`x11rb`]: https://crates.io/crates/x11rb
 
+#[cfg(feature = "x11rb-server")]
 use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
-use std::{convert::TryInto, rc::Rc, sync::Arc};
+use std::{rc::Rc, sync::Arc};
 use x11rb::protocol::xproto::EventMask;
 
 #[cfg(feature = "x11rb-client")]
 use crate::client::{
-    handle_request as client_handle_request, ClientCore, ClientError, ClientHandler,
+    handle_request as client_handle_request, ClientCore, ClientError, ClientHandler, ClientState,
 };
 #[cfg(feature = "x11rb-server")]
-use crate::server::{ServerCore, ServerError, ServerHandler, XimConnection, XimConnections};
-#[cfg(feature = "x11rb-client")]
-use crate::AHashMap;
+use crate::server::{ServerCore, ServerError, ServerHandler, XimConnections};
+use crate::{AHashMap, Transport};
 #[cfg(feature = "x11rb-client")]
 use xim_parser::{Attr, AttributeName};
 
+use crate::atom_pool::{self, AtomPool};
+use crate::framing::{self, Fragment, Fragments, Frames, PropertyAtom};
 use crate::Atoms;
 
 #[cfg(feature = "x11rb-xcb")]
@@ -33,9 +35,9 @@ use x11rb::{
     errors::{ConnectError, ConnectionError, ParseError, ReplyError, ReplyOrIdError},
     protocol::{
         xproto::{
-            Atom, AtomEnum, ClientMessageEvent, ConnectionExt, KeyPressEvent, PropMode, Screen,
-            SelectionNotifyEvent, SelectionRequestEvent, Window, WindowClass, CLIENT_MESSAGE_EVENT,
-            SELECTION_NOTIFY_EVENT,
+            Atom, AtomEnum, ChangeWindowAttributesAux, ClientMessageEvent, ConnectionExt,
+            KeyPressEvent, PropMode, Property, PropertyNotifyEvent, Screen, SelectionNotifyEvent,
+            Window, WindowClass, CLIENT_MESSAGE_EVENT, SELECTION_NOTIFY_EVENT,
         },
         Event,
     },
@@ -44,7 +46,7 @@ use x11rb::{
     COPY_DEPTH_FROM_PARENT, CURRENT_TIME,
 };
 
-use xim_parser::{Request, XimWrite};
+use xim_parser::{Endian, ReadError, Request, XimWrite};
 
 macro_rules! convert_error {
     ($($ty:ty,)+) => {
@@ -119,7 +121,7 @@ impl<C: HasConnection> HasConnection for X11rbServer<C> {
     }
 }
 
-impl<'x, C: HasConnection> HasConnection for &'x C {
+impl<C: HasConnection> HasConnection for &C {
     type Connection = C::Connection;
 
     #[inline(always)]
@@ -149,11 +151,8 @@ impl<C: HasConnection> HasConnection for Arc<C> {
 #[cfg(feature = "x11rb-server")]
 pub struct X11rbServer<C: HasConnection> {
     has_conn: C,
-    locale_data: String,
-    im_win: Window,
-    atoms: Atoms<Atom>,
+    transport: ServerTransport,
     buf: Vec<u8>,
-    sequence: u16,
 }
 
 #[cfg(feature = "x11rb-server")]
@@ -184,6 +183,7 @@ impl<C: HasConnection> X11rbServer<C> {
         let atoms = Atoms::new::<ServerError, _>(|name| {
             Ok(conn.intern_atom(false, name.as_bytes())?.reply()?.atom)
         })?;
+        let atom_pool = intern_atom_pool::<_, ServerError>(&has_conn)?;
 
         let reply = conn
             .get_property(
@@ -232,126 +232,107 @@ impl<C: HasConnection> X11rbServer<C> {
 
         Ok(Self {
             has_conn,
-            locale_data: format!("@locale={}", locales),
-            im_win,
-            atoms,
+            transport: ServerTransport::new(im_win, atoms, atom_pool, locales),
             buf: Vec::with_capacity(1024),
-            sequence: 0,
         })
     }
 
+    /// Transports advertised to clients, only `X/` by default
+    ///
+    /// Clients choosing a socket transport have to be served by a `SocketServer`
+    pub fn set_transports(&mut self, transports: &[Transport]) {
+        self.transport.set_transports(transports);
+    }
+
     pub fn filter_event<T>(
         &mut self,
         e: &Event,
         connections: &mut XimConnections<T>,
         handler: &mut impl ServerHandler<Self, InputContextData = T>,
     ) -> Result<bool, ServerError> {
-        match e {
-            Event::SelectionRequest(req) if req.owner == self.im_win => {
-                if req.property == self.atoms.LOCALES {
-                    log::trace!("Selection notify locale");
-                    self.send_selection_notify(req, &self.locale_data)?;
-                } else if req.property == self.atoms.TRANSPORT {
-                    log::trace!("Selection notify transport");
-                    self.send_selection_notify(req, "@transport=X/")?;
-                }
-                Ok(true)
+        match self.transport.handle_event(e, connections) {
+            ServerEvent::SelectionNotify(notify) => {
+                self.conn().change_property8(
+                    PropMode::REPLACE,
+                    notify.requestor,
+                    notify.property,
+                    notify.target,
+                    self.transport.selection_data(notify.property),
+                )?;
+                self.conn()
+                    .send_event(false, notify.requestor, EventMask::NO_EVENT, notify)?;
+                self.conn().flush()?;
             }
-            Event::ClientMessage(msg) => {
-                if msg.type_ == self.atoms.XIM_XCONNECT {
-                    let com_win = self.conn().generate_id()?;
-                    self.conn().create_window(
-                        COPY_DEPTH_FROM_PARENT,
-                        com_win,
-                        self.im_win,
-                        0,
-                        0,
-                        1,
-                        1,
-                        0,
-                        WindowClass::INPUT_ONLY,
-                        0,
-                        &Default::default(),
-                    )?;
-                    let client_win = msg.data.as_data32()[0];
-                    log::info!("XConnected with {}", client_win);
-                    self.conn().send_event(
-                        false,
-                        client_win,
-                        EventMask::NO_EVENT,
-                        ClientMessageEvent {
-                            format: 32,
-                            type_: self.atoms.XIM_XCONNECT,
-                            data: [com_win, 0, 0, 0, 0].into(),
-                            response_type: CLIENT_MESSAGE_EVENT,
-                            sequence: 0,
-                            window: client_win,
-                        },
-                    )?;
-                    self.conn().flush()?;
-                    connections.new_connection(com_win, client_win);
-                } else if msg.type_ == self.atoms.XIM_PROTOCOL {
-                    if let Some(connection) = connections.get_connection(msg.window) {
-                        self.handle_xim_protocol(msg, connection, handler)?;
-                        if connection.disconnected {
-                            connections.remove_connection(msg.window);
-                        }
-                    } else {
-                        log::warn!("Unknown connection");
-                    }
-                }
+            ServerEvent::XConnect(client_win) => {
+                let com_win = self.conn().generate_id()?;
+                self.conn().create_window(
+                    COPY_DEPTH_FROM_PARENT,
+                    com_win,
+                    self.transport.im_win,
+                    0,
+                    0,
+                    1,
+                    1,
+                    0,
+                    WindowClass::INPUT_ONLY,
+                    0,
+                    &Default::default(),
+                )?;
+                self.conn()
+                    .change_window_attributes(client_win, &client_window_attributes())?;
+                self.conn().send_event(
+                    false,
+                    client_win,
+                    EventMask::NO_EVENT,
+                    self.transport.xconnect_reply(client_win, com_win),
+                )?;
+                self.conn().flush()?;
+                connections.new_connection(com_win, client_win);
+            }
+            ServerEvent::Protocol {
+                com_win,
+                endian,
+                data,
+            } => {
+                let data = match data {
+                    ProtocolData::Property {
+                        window,
+                        atom,
+                        length,
+                    } => Ok(read_property::<_, ServerError>(
+                        &self.has_conn,
+                        window,
+                        atom,
+                        length,
+                    )?),
+                    ProtocolData::Fragments(data) => data,
+                };
+                let req = data.and_then(|data| xim_parser::read_with_endian(&data, endian));
 
-                Ok(true)
+                if dispatch_request(self, connections, com_win, req, handler)? {
+                    self.remove_connection(com_win, connections, handler)?;
+                }
             }
-            _ => Ok(false),
+            ServerEvent::ClientDestroyed(com_win) => {
+                self.remove_connection(com_win, connections, handler)?;
+            }
+            ServerEvent::Handled => {}
+            ServerEvent::Unhandled => return Ok(false),
         }
+
+        Ok(true)
     }
 
-    fn handle_xim_protocol<T>(
+    /// Drop connection of `com_win`, destroying its input contexts and the window
+    fn remove_connection<T>(
         &mut self,
-        msg: &ClientMessageEvent,
-        connection: &mut XimConnection<T>,
+        com_win: Window,
+        connections: &mut XimConnections<T>,
         handler: &mut impl ServerHandler<Self, InputContextData = T>,
     ) -> Result<(), ServerError> {
-        if msg.format == 32 {
-            let [length, atom, ..] = msg.data.as_data32();
-            let data = self
-                .conn()
-                .get_property(true, msg.window, atom, AtomEnum::ANY, 0, length)?
-                .reply()?
-                .value;
-            let req = xim_parser::read(&data)?;
-            connection.handle_request(self, req, handler)
-        } else {
-            let req = xim_parser::read(&msg.data.as_data8())?;
-            connection.handle_request(self, req, handler)
-        }
-    }
-
-    fn send_selection_notify(
-        &self,
-        req: &SelectionRequestEvent,
-        data: &str,
-    ) -> Result<(), ServerError> {
-        let e = SelectionNotifyEvent {
-            response_type: SELECTION_NOTIFY_EVENT,
-            property: req.property,
-            time: req.time,
-            target: req.target,
-            selection: req.selection,
-            requestor: req.requestor,
-            sequence: 0,
-        };
-
-        self.conn().change_property8(
-            PropMode::REPLACE,
-            req.requestor,
-            req.property,
-            req.target,
-            data.as_bytes(),
-        )?;
-        self.conn()
-            .send_event(false, req.requestor, EventMask::NO_EVENT, e)?;
+        let client_win = disconnect(self, connections, com_win, handler)?;
+        self.transport.remove_connection(com_win, client_win);
+        self.conn().destroy_window(com_win)?;
         self.conn().flush()?;
 
         Ok(())
@@ -362,13 +343,19 @@ impl<C: HasConnection> X11rbServer<C> {
 impl<C: HasConnection> ServerCore for X11rbServer<C> {
     type XEvent = KeyPressEvent;
 
-    fn send_req(&mut self, client_win: u32, req: Request) -> Result<(), ServerError> {
+    fn send_req(
+        &mut self,
+        client_win: u32,
+        endian: Endian,
+        req: Request,
+    ) -> Result<(), ServerError> {
         send_req_impl(
             &self.has_conn,
-            &self.atoms,
+            &self.transport.atoms,
             client_win,
+            endian,
             &mut self.buf,
-            &mut self.sequence,
+            &mut self.transport.atom_pool,
             20,
             &req,
         )
@@ -378,6 +365,15 @@ impl<C: HasConnection> ServerCore for X11rbServer<C> {
     fn deserialize_event(&self, ev: &xim_parser::XEvent) -> Self::XEvent {
         deserialize_event_impl(ev)
     }
+
+    fn window_exists(&self, window: u32) -> bool {
+        self.has_conn
+            .conn()
+            .get_window_attributes(window)
+            .ok()
+            .and_then(|cookie| cookie.reply().ok())
+            .is_some()
+    }
 }
 
 #[cfg(feature = "x11rb-client")]
@@ -388,11 +384,15 @@ pub struct X11rbClient<C: HasConnection> {
     server_atom: Atom,
     atoms: Atoms<Atom>,
     transport_max: usize,
+    transports: Vec<Transport>,
     client_window: u32,
-    im_attributes: AHashMap<AttributeName, u16>,
-    ic_attributes: AHashMap<AttributeName, u16>,
-    sequence: u16,
+    endian: Endian,
+    im_attributes: AHashMap<AttributeName, Attr>,
+    ic_attributes: AHashMap<AttributeName, Attr>,
+    state: ClientState,
+    atom_pool: AtomPool<Atom, Window>,
     buf: Vec<u8>,
+    fragments: Fragments,
 }
 
 #[cfg(feature = "x11rb-client")]
@@ -429,6 +429,7 @@ impl<C: HasConnection> X11rbClient<C> {
         let atoms = Atoms::new::<ClientError, _>(|name| {
             Ok(conn.intern_atom(false, name.as_bytes())?.reply()?.atom)
         })?;
+        let atom_pool = intern_atom_pool::<_, ClientError>(&has_conn)?;
         let server_reply = conn
             .get_property(
                 false,
@@ -471,11 +472,15 @@ impl<C: HasConnection> X11rbClient<C> {
                             server_owner_window: server_owner,
                             im_attributes: AHashMap::with_hasher(Default::default()),
                             ic_attributes: AHashMap::with_hasher(Default::default()),
+                            state: ClientState::default(),
                             im_window: x11rb::NONE,
                             transport_max: 20,
+                            transports: Vec::new(),
                             client_window,
-                            sequence: 0,
+                            endian: Endian::NATIVE,
+                            atom_pool,
                             buf: Vec::with_capacity(1024),
+                            fragments: Fragments::default(),
                         });
                     }
                 }
@@ -522,7 +527,9 @@ impl<C: HasConnection> X11rbClient<C> {
                         )?
                         .reply()?;
 
-                    if !transport.value.starts_with(b"@transport=X/") {
+                    self.transports = Transport::parse_list(&transport.value);
+
+                    if !self.transports.contains(&Transport::X) {
                         return Err(ClientError::UnsupportedTransport);
                     }
 
@@ -542,56 +549,53 @@ impl<C: HasConnection> X11rbClient<C> {
                 }
             }
             Event::ClientMessage(msg) if msg.window == self.client_window => {
-                if msg.type_ == self.atoms.XIM_XCONNECT {
-                    let [im_window, major, minor, max, _] = msg.data.as_data32();
-                    log::info!(
-                        "XConnected server on {}, transport version: {}.{}, TRANSPORT_MAX: {}",
+                match client_message(&self.atoms, &mut self.fragments, msg) {
+                    ClientEvent::XConnect {
                         im_window,
-                        major,
-                        minor,
-                        max
-                    );
-                    self.im_window = im_window;
-                    self.transport_max = max as usize;
-                    self.send_req(Request::Connect {
-                        client_major_protocol_version: 1,
-                        client_minor_protocol_version: 0,
-                        endian: xim_parser::Endian::Native,
-                        client_auth_protocol_names: Vec::new(),
-                    })?;
-                    Ok(true)
-                } else if msg.type_ == self.atoms.XIM_PROTOCOL {
-                    self.handle_xim_protocol(msg, handler)?;
-                    Ok(true)
-                } else {
-                    Ok(false)
+                        transport_max,
+                    } => {
+                        self.im_window = im_window;
+                        self.transport_max = transport_max;
+                        self.conn()
+                            .change_window_attributes(im_window, &server_window_attributes())?;
+                        self.send_req(connect_request(self.endian))?;
+                    }
+                    ClientEvent::Protocol(data) => {
+                        let data = match data {
+                            ProtocolData::Property {
+                                window,
+                                atom,
+                                length,
+                            } => read_property::<_, ClientError>(
+                                &self.has_conn,
+                                window,
+                                atom,
+                                length,
+                            )?,
+                            ProtocolData::Fragments(data) => data?,
+                        };
+                        let req = xim_parser::read_with_endian(&data, self.endian)?;
+                        if let Request::DisconnectReply {} = req {
+                            self.atom_pool.release_window(self.im_window);
+                        }
+                        client_handle_request(self, handler, req)?;
+                    }
+                    ClientEvent::Handled => {}
+                    ClientEvent::Unhandled => return Ok(false),
                 }
+
+                Ok(true)
+            }
+            Event::PropertyNotify(e) if e.window == self.im_window => {
+                Ok(release_atom(&mut self.atom_pool, e))
             }
             _ => Ok(false),
         }
     }
 
-    fn handle_xim_protocol(
-        &mut self,
-        msg: &ClientMessageEvent,
-        handler: &mut impl ClientHandler<Self>,
-    ) -> Result<(), ClientError> {
-        if msg.format == 32 {
-            let [length, atom, ..] = msg.data.as_data32();
-            let data = self
-                .conn()
-                .get_property(true, msg.window, atom, AtomEnum::ANY, 0, length)?
-                .reply()?
-                .value;
-            let req = xim_parser::read(&data)?;
-            client_handle_request(self, handler, req)?;
-        } else if msg.format == 8 {
-            let data = msg.data.as_data8();
-            let req: xim_parser::Request = xim_parser::read(&data)?;
-            client_handle_request(self, handler, req)?;
-        }
-
-        Ok(())
+    /// Transports server advertised, use a `SocketClient` when `X/` isn't one of them
+    pub fn transports(&self) -> &[Transport] {
+        &self.transports
     }
 
     fn xconnect(&mut self) -> Result<(), ClientError> {
@@ -599,14 +603,7 @@ impl<C: HasConnection> X11rbClient<C> {
             false,
             self.server_owner_window,
             EventMask::NO_EVENT,
-            ClientMessageEvent {
-                data: [self.client_window, 0, 0, 0, 0].into(),
-                format: 32,
-                response_type: CLIENT_MESSAGE_EVENT,
-                sequence: 0,
-                type_: self.atoms.XIM_XCONNECT,
-                window: self.server_owner_window,
-            },
+            xconnect_request(&self.atoms, self.server_owner_window, self.client_window),
         )?;
 
         self.conn().flush()?;
@@ -620,41 +617,42 @@ impl<C: HasConnection> ClientCore for X11rbClient<C> {
     type XEvent = KeyPressEvent;
     fn set_attrs(&mut self, im_attrs: Vec<Attr>, ic_attrs: Vec<Attr>) {
         for im_attr in im_attrs {
-            self.im_attributes.insert(im_attr.name, im_attr.id);
+            self.im_attributes.insert(im_attr.name, im_attr);
         }
 
         for ic_attr in ic_attrs {
-            self.ic_attributes.insert(ic_attr.name, ic_attr.id);
+            self.ic_attributes.insert(ic_attr.name, ic_attr);
         }
     }
 
     #[inline]
-    fn ic_attributes(&self) -> &AHashMap<AttributeName, u16> {
+    fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    #[inline]
+    fn ic_attributes(&self) -> &AHashMap<AttributeName, Attr> {
         &self.ic_attributes
     }
 
     #[inline]
-    fn im_attributes(&self) -> &AHashMap<AttributeName, u16> {
+    fn im_attributes(&self) -> &AHashMap<AttributeName, Attr> {
         &self.im_attributes
     }
 
+    #[inline]
+    fn state(&self) -> &ClientState {
+        &self.state
+    }
+
+    #[inline]
+    fn state_mut(&mut self) -> &mut ClientState {
+        &mut self.state
+    }
+
     #[inline]
     fn serialize_event(&self, xev: &Self::XEvent) -> xim_parser::XEvent {
-        xim_parser::XEvent {
-            response_type: xev.response_type,
-            detail: xev.detail,
-            sequence: xev.sequence,
-            time: xev.time,
-            root: xev.root,
-            event: xev.event,
-            child: xev.child,
-            root_x: xev.root_x,
-            root_y: xev.root_y,
-            event_x: xev.event_x,
-            event_y: xev.event_y,
-            state: xev.state.into(),
-            same_screen: xev.same_screen,
-        }
+        serialize_event_impl(xev)
     }
 
     #[inline]
@@ -668,87 +666,492 @@ impl<C: HasConnection> ClientCore for X11rbClient<C> {
             &self.has_conn,
             &self.atoms,
             self.im_window,
+            self.endian,
             &mut self.buf,
-            &mut self.sequence,
+            &mut self.atom_pool,
             self.transport_max,
             &req,
         )
     }
 }
 
-fn send_req_impl<C: HasConnection, E: From<ConnectionError> + From<ReplyError>>(
+/// Send every intern request before waiting for the first reply
+fn intern_atom_pool<C: HasConnection, E: From<ConnectionError> + From<ReplyError>>(
     c: &C,
+) -> Result<AtomPool<Atom, Window>, E> {
+    let cookies = (0..atom_pool::INITIAL_SIZE)
+        .map(|i| {
+            c.conn()
+                .intern_atom(false, atom_pool::atom_name(i).as_bytes())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let atoms = cookies
+        .into_iter()
+        .map(|cookie| cookie.reply().map(|reply| reply.atom))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(AtomPool::new(atoms))
+}
+
+/// Return `true` when peer deleted a property of `atom_pool`
+pub(crate) fn release_atom(
+    atom_pool: &mut AtomPool<Atom, Window>,
+    e: &PropertyNotifyEvent,
+) -> bool {
+    e.state == Property::DELETE && atom_pool.release(e.window, e.atom)
+}
+
+/// Read the request of `length` bytes from a property, requests appended after it are put back
+fn read_property<C: HasConnection, E: From<ConnectionError> + From<ReplyError>>(
+    c: &C,
+    window: Window,
+    atom: Atom,
+    length: u32,
+) -> Result<Vec<u8>, E> {
+    let reply = c
+        .conn()
+        .get_property(true, window, atom, AtomEnum::ANY, 0, u32::MAX)?
+        .reply()?;
+    let mut data = reply.value;
+
+    if let Some(rest) = framing::split_property(&mut data, length) {
+        // Before anything peer appended since the delete
+        c.conn().change_property(
+            PropMode::PREPEND,
+            window,
+            atom,
+            reply.type_,
+            8,
+            rest.len() as u32,
+            &rest,
+        )?;
+    }
+
+    Ok(data)
+}
+
+/// Where the request of a `_XIM_PROTOCOL` is
+pub(crate) enum ProtocolData {
+    /// Format 32, `length` bytes in property `atom` of `window`, read it with delete
+    Property {
+        window: Window,
+        atom: Atom,
+        length: u32,
+    },
+    /// Format 8, joined with the previous `_XIM_MOREDATA`
+    Fragments(Result<Vec<u8>, ReadError>),
+}
+
+impl ProtocolData {
+    fn new(msg: &ClientMessageEvent, fragments: &mut Fragments) -> Self {
+        if msg.format == 32 {
+            let [length, atom, ..] = msg.data.as_data32();
+            ProtocolData::Property {
+                window: msg.window,
+                atom,
+                length,
+            }
+        } else {
+            ProtocolData::Fragments(fragments.finish(&msg.data.as_data8()))
+        }
+    }
+}
+
+/// What a server driver has to do for an event
+#[cfg(feature = "x11rb-server")]
+pub(crate) enum ServerEvent {
+    /// Replace the requestor's property with [`ServerTransport::selection_data`] and send this
+    SelectionNotify(SelectionNotifyEvent),
+    /// Client window asked for a connection, create a communication window for it, select
+    /// [`client_window_attributes`] and send [`ServerTransport::xconnect_reply`]
+    XConnect(Window),
+    /// Request to the communication window of an existing connection
+    Protocol {
+        com_win: Window,
+        endian: Endian,
+        data: ProtocolData,
+    },
+    /// Client window of the connection of this communication window was destroyed
+    ClientDestroyed(Window),
+    Handled,
+    Unhandled,
+}
+
+/// X transport state of a server without the connection, shared by every x11rb server
+#[cfg(feature = "x11rb-server")]
+pub(crate) struct ServerTransport {
+    pub(crate) im_win: Window,
+    pub(crate) atoms: Atoms<Atom>,
+    pub(crate) atom_pool: AtomPool<Atom, Window>,
+    locale_data: String,
+    transport_data: String,
+    fragments: AHashMap<Window, Fragments>,
+}
+
+#[cfg(feature = "x11rb-server")]
+impl ServerTransport {
+    pub fn new(
+        im_win: Window,
+        atoms: Atoms<Atom>,
+        atom_pool: AtomPool<Atom, Window>,
+        locales: &str,
+    ) -> Self {
+        Self {
+            im_win,
+            atoms,
+            atom_pool,
+            locale_data: format!("@locale={}", locales),
+            transport_data: Transport::to_list(&[Transport::X]),
+            fragments: AHashMap::with_hasher(Default::default()),
+        }
+    }
+
+    pub fn set_transports(&mut self, transports: &[Transport]) {
+        self.transport_data = Transport::to_list(transports);
+    }
+
+    /// Data of the property `SelectionNotify` points to
+    pub fn selection_data(&self, property: Atom) -> &[u8] {
+        if property == self.atoms.LOCALES {
+            self.locale_data.as_bytes()
+        } else {
+            self.transport_data.as_bytes()
+        }
+    }
+
+    pub fn handle_event<T>(
+        &mut self,
+        e: &Event,
+        connections: &mut XimConnections<T>,
+    ) -> ServerEvent {
+        match e {
+            Event::SelectionRequest(req) if req.owner == self.im_win => {
+                if req.property == self.atoms.LOCALES || req.property == self.atoms.TRANSPORT {
+                    log::trace!("Selection notify {}", req.property);
+                    ServerEvent::SelectionNotify(SelectionNotifyEvent {
+                        response_type: SELECTION_NOTIFY_EVENT,
+                        property: req.property,
+                        time: req.time,
+                        target: req.target,
+                        selection: req.selection,
+                        requestor: req.requestor,
+                        sequence: 0,
+                    })
+                } else {
+                    ServerEvent::Handled
+                }
+            }
+            Event::ClientMessage(msg) => {
+                if msg.type_ == self.atoms.XIM_XCONNECT {
+                    let client_win = msg.data.as_data32()[0];
+                    log::info!("XConnected with {}", client_win);
+                    return ServerEvent::XConnect(client_win);
+                } else if msg.type_ != self.atoms.XIM_MOREDATA
+                    && msg.type_ != self.atoms.XIM_PROTOCOL
+                {
+                    return ServerEvent::Handled;
+                }
+
+                let endian = match connections.get_connection(msg.window) {
+                    Some(connection) => connection.endian(),
+                    None => {
+                        log::warn!("Unknown connection");
+                        self.fragments.remove(&msg.window);
+                        return ServerEvent::Handled;
+                    }
+                };
+                let fragments = self.fragments.entry(msg.window).or_default();
+
+                if msg.type_ == self.atoms.XIM_MOREDATA {
+                    fragments.push(&msg.data.as_data8());
+                    ServerEvent::Handled
+                } else {
+                    ServerEvent::Protocol {
+                        com_win: msg.window,
+                        endian,
+                        data: ProtocolData::new(msg, fragments),
+                    }
+                }
+            }
+            Event::DestroyNotify(e) => match connections.find_com_win(e.window) {
+                Some(com_win) => {
+                    log::info!("Client window {} destroyed", e.window);
+                    ServerEvent::ClientDestroyed(com_win)
+                }
+                None => ServerEvent::Unhandled,
+            },
+            Event::PropertyNotify(e) if release_atom(&mut self.atom_pool, e) => {
+                ServerEvent::Handled
+            }
+            _ => ServerEvent::Unhandled,
+        }
+    }
+
+    /// `_XIM_XCONNECT` telling `client_win` its communication window
+    pub fn xconnect_reply(&self, client_win: Window, com_win: Window) -> ClientMessageEvent {
+        ClientMessageEvent {
+            format: 32,
+            type_: self.atoms.XIM_XCONNECT,
+            data: [com_win, 0, 0, 0, 0].into(),
+            response_type: CLIENT_MESSAGE_EVENT,
+            sequence: 0,
+            window: client_win,
+        }
+    }
+
+    /// Forget the connection of `com_win`, its window is destroyed by the driver
+    pub fn remove_connection(&mut self, com_win: Window, client_win: Option<Window>) {
+        if let Some(client_win) = client_win {
+            self.atom_pool.release_window(client_win);
+        }
+        self.fragments.remove(&com_win);
+    }
+}
+
+/// Get `DestroyNotify` when client dies without `Disconnect`, and `PropertyNotify` when
+/// client read a request sent with property
+#[cfg(feature = "x11rb-server")]
+pub(crate) fn client_window_attributes() -> ChangeWindowAttributesAux {
+    ChangeWindowAttributesAux::new()
+        .event_mask(EventMask::STRUCTURE_NOTIFY | EventMask::PROPERTY_CHANGE)
+}
+
+/// Handle request read for the connection of `com_win`, return `true` when it disconnected
+#[cfg(feature = "x11rb-server")]
+pub(crate) fn dispatch_request<S: ServerCore, T>(
+    server: &mut S,
+    connections: &mut XimConnections<T>,
+    com_win: Window,
+    req: Result<Request, ReadError>,
+    handler: &mut impl ServerHandler<S, InputContextData = T>,
+) -> Result<bool, ServerError> {
+    match connections.get_connection(com_win) {
+        Some(connection) => {
+            connection.handle_read(server, req, handler)?;
+            Ok(connection.disconnected)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Remove the connection of `com_win`, destroying its input contexts
+///
+/// Return its client window when there was a connection.
+#[cfg(feature = "x11rb-server")]
+pub(crate) fn disconnect<S: ServerCore, T>(
+    server: &mut S,
+    connections: &mut XimConnections<T>,
+    com_win: Window,
+    handler: &mut impl ServerHandler<S, InputContextData = T>,
+) -> Result<Option<Window>, ServerError> {
+    match connections.remove_connection(com_win) {
+        Some(mut connection) => {
+            if !connection.disconnected {
+                connection.disconnect(server, handler)?;
+            }
+            Ok(Some(connection.client_win))
+        }
+        None => Ok(None),
+    }
+}
+
+/// What a client driver has to do for a `ClientMessage` to its window
+#[cfg(feature = "x11rb-client")]
+pub(crate) enum ClientEvent {
+    /// Server accepted `_XIM_XCONNECT`, select [`server_window_attributes`] on `im_window` and
+    /// send [`connect_request`]
+    XConnect {
+        im_window: Window,
+        transport_max: usize,
+    },
+    Protocol(ProtocolData),
+    Handled,
+    Unhandled,
+}
+
+#[cfg(feature = "x11rb-client")]
+pub(crate) fn client_message(
     atoms: &Atoms<Atom>,
-    target: Window,
-    buf: &mut Vec<u8>,
-    sequence: &mut u16,
-    transport_max: usize,
-    req: &Request,
-) -> Result<(), E> {
+    fragments: &mut Fragments,
+    msg: &ClientMessageEvent,
+) -> ClientEvent {
+    if msg.type_ == atoms.XIM_XCONNECT {
+        let [im_window, major, minor, max, _] = msg.data.as_data32();
+        log::info!(
+            "XConnected server on {}, transport version: {}.{}, TRANSPORT_MAX: {}",
+            im_window,
+            major,
+            minor,
+            max
+        );
+        ClientEvent::XConnect {
+            im_window,
+            transport_max: max as usize,
+        }
+    } else if msg.type_ == atoms.XIM_MOREDATA {
+        fragments.push(&msg.data.as_data8());
+        ClientEvent::Handled
+    } else if msg.type_ == atoms.XIM_PROTOCOL {
+        ClientEvent::Protocol(ProtocolData::new(msg, fragments))
+    } else {
+        ClientEvent::Unhandled
+    }
+}
+
+/// Get `PropertyNotify` when server read a request sent with property
+#[cfg(feature = "x11rb-client")]
+pub(crate) fn server_window_attributes() -> ChangeWindowAttributesAux {
+    ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE)
+}
+
+/// `_XIM_XCONNECT` asking the selection owner of the server for a connection
+#[cfg(feature = "x11rb-client")]
+pub(crate) fn xconnect_request(
+    atoms: &Atoms<Atom>,
+    server_owner_window: Window,
+    client_window: Window,
+) -> ClientMessageEvent {
+    ClientMessageEvent {
+        data: [client_window, 0, 0, 0, 0].into(),
+        format: 32,
+        response_type: CLIENT_MESSAGE_EVENT,
+        sequence: 0,
+        type_: atoms.XIM_XCONNECT,
+        window: server_owner_window,
+    }
+}
+
+/// First request after `_XIM_XCONNECT`
+#[cfg(feature = "x11rb-client")]
+pub(crate) fn connect_request(endian: Endian) -> Request {
+    Request::Connect {
+        client_major_protocol_version: 1,
+        client_minor_protocol_version: 0,
+        endian,
+        client_auth_protocol_names: Vec::new(),
+    }
+}
+
+/// Serialize `req` into `buf`
+pub(crate) fn serialize_request(req: &Request, endian: Endian, buf: &mut Vec<u8>) {
     if log::log_enabled!(log::Level::Trace) {
         log::trace!("->: {:?}", req);
     } else {
         log::debug!("->: {}", req.name());
     }
     buf.resize(req.size(), 0);
-    xim_parser::write(req, buf);
+    xim_parser::write_with_endian(req, buf, endian);
+}
+
+/// `ClientMessage` carrying `fragment` of a request
+pub(crate) fn fragment_message(
+    atoms: &Atoms<Atom>,
+    target: Window,
+    fragment: &Fragment,
+) -> ClientMessageEvent {
+    ClientMessageEvent {
+        response_type: CLIENT_MESSAGE_EVENT,
+        data: fragment.data().into(),
+        format: 8,
+        sequence: 0,
+        type_: fragment.message_type(atoms),
+        window: target,
+    }
+}
 
-    if buf.len() < transport_max {
-        if buf.len() > 20 {
-            todo!("multi-CM");
+/// `_XIM_PROTOCOL` pointing to a request of `length` bytes appended to property `prop`
+pub(crate) fn property_message(
+    atoms: &Atoms<Atom>,
+    target: Window,
+    prop: Atom,
+    length: usize,
+) -> ClientMessageEvent {
+    ClientMessageEvent {
+        data: [length as u32, prop, 0, 0, 0].into(),
+        format: 32,
+        sequence: 0,
+        response_type: CLIENT_MESSAGE_EVENT,
+        type_: atoms.XIM_PROTOCOL,
+        window: target,
+    }
+}
+
+fn send_req_impl<C: HasConnection, E: From<ConnectionError> + From<ReplyError>>(
+    c: &C,
+    atoms: &Atoms<Atom>,
+    target: Window,
+    endian: Endian,
+    buf: &mut Vec<u8>,
+    atom_pool: &mut AtomPool<Atom, Window>,
+    transport_max: usize,
+    req: &Request,
+) -> Result<(), E> {
+    serialize_request(req, endian, buf);
+
+    match framing::frame(buf, transport_max, atom_pool, target) {
+        Frames::ClientMessages(messages) => {
+            for message in messages {
+                c.conn().send_event(
+                    false,
+                    target,
+                    EventMask::NO_EVENT,
+                    fragment_message(atoms, target, &message),
+                )?;
+            }
+        }
+        Frames::Property(prop, data) => {
+            let prop = match prop {
+                PropertyAtom::Acquired(prop) => prop,
+                PropertyAtom::Intern(name) => {
+                    let prop = c.conn().intern_atom(false, name.as_bytes())?.reply()?.atom;
+                    atom_pool.push(target, prop);
+                    prop
+                }
+            };
+            c.conn().change_property(
+                PropMode::APPEND,
+                target,
+                prop,
+                AtomEnum::STRING,
+                8,
+                data.len() as u32,
+                data,
+            )?;
+            c.conn().send_event(
+                false,
+                target,
+                EventMask::NO_EVENT,
+                property_message(atoms, target, prop, data.len()),
+            )?;
         }
-        buf.resize(20, 0);
-        let buf: [u8; 20] = buf.as_slice().try_into().unwrap();
-        c.conn().send_event(
-            false,
-            target,
-            EventMask::NO_EVENT,
-            ClientMessageEvent {
-                response_type: CLIENT_MESSAGE_EVENT,
-                data: buf.into(),
-                format: 8,
-                sequence: 0,
-                type_: atoms.XIM_PROTOCOL,
-                window: target,
-            },
-        )?;
-    } else {
-        let prop = c
-            .conn()
-            .intern_atom(false, format!("_XIM_DATA_{}", sequence).as_bytes())?
-            .reply()?
-            .atom;
-        *sequence = sequence.wrapping_add(1);
-        c.conn().change_property(
-            PropMode::APPEND,
-            target,
-            prop,
-            AtomEnum::STRING,
-            8,
-            buf.len() as u32,
-            buf,
-        )?;
-        c.conn().send_event(
-            false,
-            target,
-            EventMask::NO_EVENT,
-            ClientMessageEvent {
-                data: [buf.len() as u32, prop, 0, 0, 0].into(),
-                format: 32,
-                sequence: 0,
-                response_type: CLIENT_MESSAGE_EVENT,
-                type_: atoms.XIM_PROTOCOL,
-                window: target,
-            },
-        )?;
     }
     buf.clear();
     c.conn().flush()?;
     Ok(())
 }
 
+#[cfg(feature = "x11rb-client")]
+#[inline]
+pub(crate) fn serialize_event_impl(xev: &KeyPressEvent) -> xim_parser::XEvent {
+    xim_parser::XEvent {
+        response_type: xev.response_type,
+        detail: xev.detail,
+        sequence: xev.sequence,
+        time: xev.time,
+        root: xev.root,
+        event: xev.event,
+        child: xev.child,
+        root_x: xev.root_x,
+        root_y: xev.root_y,
+        event_x: xev.event_x,
+        event_y: xev.event_y,
+        state: xev.state.into(),
+        same_screen: xev.same_screen,
+    }
+}
+
 #[inline]
-fn deserialize_event_impl(xev: &xim_parser::XEvent) -> KeyPressEvent {
+pub(crate) fn deserialize_event_impl(xev: &xim_parser::XEvent) -> KeyPressEvent {
     KeyPressEvent {
         response_type: xev.response_type,
         detail: xev.detail,
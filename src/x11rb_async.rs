@@ -0,0 +1,929 @@
+//! Provides an async implementation of XIM using [`x11rb-async`] as a transport.
+//!
+//! Wrap your async `Connection` in an [`X11rbAsyncClient`] or [`X11rbAsyncServer`].
+//! Requests made through the `Client` and `Server` traits are queued, they are sent by
+//! `flush` and by the event functions which flush before waiting.
+//!
+//! [`x11rb-async`]: https://crates.io/crates/x11rb-async
+
+use alloc::collections::VecDeque;
+#[cfg(feature = "x11rb-async-server")]
+use alloc::format;
+#[cfg(feature = "x11rb-async-client")]
+use alloc::string::String;
+use alloc::vec::Vec;
+use std::{rc::Rc, sync::Arc};
+
+use x11rb::errors::{ConnectionError, ReplyError, ReplyOrIdError};
+use x11rb::protocol::xproto::{Atom, AtomEnum, EventMask, PropMode, Window, WindowClass};
+use x11rb::protocol::Event;
+use x11rb::{COPY_DEPTH_FROM_PARENT, CURRENT_TIME};
+use x11rb_async::connection::Connection;
+use x11rb_async::protocol::xproto::ConnectionExt;
+use x11rb_async::rust_connection::RustConnection;
+
+use crate::atom_pool::{self, AtomPool};
+#[cfg(feature = "x11rb-async-client")]
+use crate::client::{
+    handle_request as client_handle_request, ClientCore, ClientError, ClientHandler, ClientState,
+};
+#[cfg(feature = "x11rb-async-client")]
+use crate::framing::Fragments;
+use crate::framing::{self, Frames, PropertyAtom};
+#[cfg(feature = "x11rb-async-server")]
+use crate::server::{ServerCore, ServerError, ServerHandler, XimConnections};
+#[cfg(feature = "x11rb-async-client")]
+use crate::x11rb::{
+    client_message, connect_request, release_atom, serialize_event_impl, server_window_attributes,
+    xconnect_request, ClientEvent,
+};
+#[cfg(feature = "x11rb-async-server")]
+use crate::x11rb::{
+    client_window_attributes, disconnect, dispatch_request, ServerEvent, ServerTransport,
+};
+use crate::x11rb::{
+    deserialize_event_impl, fragment_message, property_message, serialize_request, ProtocolData,
+};
+#[cfg(feature = "x11rb-async-client")]
+use crate::AHashMap;
+#[cfg(feature = "x11rb-async-client")]
+use crate::Client;
+use crate::{Atoms, Transport};
+#[cfg(feature = "x11rb-async-server")]
+use xim_parser::attrs;
+#[cfg(feature = "x11rb-async-client")]
+use xim_parser::{Attr, AttributeName};
+use xim_parser::{Endian, ReadError, Request};
+
+pub trait HasConnection {
+    type Connection: Connection + ConnectionExt;
+
+    fn conn(&self) -> &Self::Connection;
+}
+
+impl HasConnection for RustConnection {
+    type Connection = Self;
+
+    #[inline(always)]
+    fn conn(&self) -> &Self::Connection {
+        self
+    }
+}
+
+impl<C: HasConnection> HasConnection for &C {
+    type Connection = C::Connection;
+
+    #[inline(always)]
+    fn conn(&self) -> &Self::Connection {
+        (**self).conn()
+    }
+}
+
+impl<C: HasConnection> HasConnection for Rc<C> {
+    type Connection = C::Connection;
+
+    #[inline(always)]
+    fn conn(&self) -> &Self::Connection {
+        (**self).conn()
+    }
+}
+
+impl<C: HasConnection> HasConnection for Arc<C> {
+    type Connection = C::Connection;
+
+    #[inline(always)]
+    fn conn(&self) -> &Self::Connection {
+        (**self).conn()
+    }
+}
+
+/// Send every intern request before waiting for the first reply
+async fn intern_atoms<C, E>(conn: &C) -> Result<Atoms<Atom>, E>
+where
+    C: Connection + ConnectionExt,
+    E: From<ConnectionError> + From<ReplyError>,
+{
+    let mut cookies = Vec::with_capacity(Atoms::<Atom>::NAMES.len());
+    for name in Atoms::<Atom>::NAMES {
+        cookies.push(conn.intern_atom(false, name.as_bytes()).await?);
+    }
+
+    let mut atoms = Vec::with_capacity(cookies.len());
+    for cookie in cookies {
+        atoms.push(cookie.reply().await?.atom);
+    }
+
+    Atoms::new::<E, _>(|name| {
+        let idx = Atoms::<Atom>::NAMES
+            .iter()
+            .position(|n| *n == name)
+            .unwrap();
+        Ok(atoms[idx])
+    })
+}
+
+/// Pool of `atom_pool::INITIAL_SIZE` atoms, interned the same way as `intern_atoms`
+async fn intern_atom_pool<C, E>(conn: &C) -> Result<AtomPool<Atom, Window>, E>
+where
+    C: Connection + ConnectionExt,
+    E: From<ConnectionError> + From<ReplyError>,
+{
+    let mut cookies = Vec::with_capacity(atom_pool::INITIAL_SIZE);
+    for i in 0..atom_pool::INITIAL_SIZE {
+        let name = atom_pool::atom_name(i);
+        cookies.push(conn.intern_atom(false, name.as_bytes()).await?);
+    }
+
+    let mut atoms = Vec::with_capacity(cookies.len());
+    for cookie in cookies {
+        atoms.push(cookie.reply().await?.atom);
+    }
+
+    Ok(AtomPool::new(atoms))
+}
+
+async fn create_input_window<C, E>(conn: &C, parent: Window, visual: u32) -> Result<Window, E>
+where
+    C: Connection + ConnectionExt,
+    E: From<ConnectionError> + From<ReplyOrIdError>,
+{
+    let window = conn.generate_id().await?;
+    conn.create_window(
+        COPY_DEPTH_FROM_PARENT,
+        window,
+        parent,
+        0,
+        0,
+        1,
+        1,
+        0,
+        WindowClass::INPUT_ONLY,
+        visual,
+        &Default::default(),
+    )
+    .await?;
+    Ok(window)
+}
+
+async fn send_frames<C, E>(
+    c: &C,
+    atoms: &Atoms<Atom>,
+    target: Window,
+    mut buf: Vec<u8>,
+    atom_pool: &mut AtomPool<Atom, Window>,
+    transport_max: usize,
+) -> Result<(), E>
+where
+    C: HasConnection,
+    E: From<ConnectionError> + From<ReplyError>,
+{
+    match framing::frame(&mut buf, transport_max, atom_pool, target) {
+        Frames::ClientMessages(messages) => {
+            for message in messages {
+                c.conn()
+                    .send_event(
+                        false,
+                        target,
+                        EventMask::NO_EVENT,
+                        fragment_message(atoms, target, &message),
+                    )
+                    .await?;
+            }
+        }
+        Frames::Property(prop, data) => {
+            let prop = match prop {
+                PropertyAtom::Acquired(prop) => prop,
+                PropertyAtom::Intern(name) => {
+                    let prop = c
+                        .conn()
+                        .intern_atom(false, name.as_bytes())
+                        .await?
+                        .reply()
+                        .await?
+                        .atom;
+                    atom_pool.push(target, prop);
+                    prop
+                }
+            };
+            c.conn()
+                .change_property(
+                    PropMode::APPEND,
+                    target,
+                    prop,
+                    AtomEnum::STRING,
+                    8,
+                    data.len() as u32,
+                    data,
+                )
+                .await?;
+            c.conn()
+                .send_event(
+                    false,
+                    target,
+                    EventMask::NO_EVENT,
+                    property_message(atoms, target, prop, data.len()),
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Bytes of the request of `_XIM_PROTOCOL`, reading the property it points to
+///
+/// Requests appended after it to the property are put back.
+async fn read_protocol_data<C, E>(
+    c: &C,
+    data: ProtocolData,
+) -> Result<Result<Vec<u8>, ReadError>, E>
+where
+    C: HasConnection,
+    E: From<ConnectionError> + From<ReplyError>,
+{
+    match data {
+        ProtocolData::Property {
+            window,
+            atom,
+            length,
+        } => {
+            let reply = c
+                .conn()
+                .get_property(true, window, atom, AtomEnum::ANY, 0, u32::MAX)
+                .await?
+                .reply()
+                .await?;
+            let mut data = reply.value;
+
+            if let Some(rest) = framing::split_property(&mut data, length) {
+                // Before anything peer appended since the delete
+                c.conn()
+                    .change_property(
+                        PropMode::PREPEND,
+                        window,
+                        atom,
+                        reply.type_,
+                        8,
+                        rest.len() as u32,
+                        &rest,
+                    )
+                    .await?;
+            }
+
+            Ok(Ok(data))
+        }
+        ProtocolData::Fragments(data) => Ok(data),
+    }
+}
+
+#[cfg(feature = "x11rb-async-server")]
+pub struct X11rbAsyncServer<C: HasConnection> {
+    has_conn: C,
+    transport: ServerTransport,
+    outbox: VecDeque<(Window, Vec<u8>)>,
+    existing_windows: Vec<Window>,
+}
+
+#[cfg(feature = "x11rb-async-server")]
+impl<C: HasConnection> X11rbAsyncServer<C> {
+    pub async fn init(
+        has_conn: C,
+        screen_num: usize,
+        im_name: &str,
+        locales: &str,
+    ) -> Result<Self, ServerError> {
+        let im_name = format!("@server={}", im_name);
+        let conn = has_conn.conn();
+        let screen = &conn.setup().roots[screen_num];
+        let im_win =
+            create_input_window::<_, ServerError>(conn, screen.root, screen.root_visual).await?;
+        let atoms = intern_atoms::<_, ServerError>(conn).await?;
+        let atom_pool = intern_atom_pool::<_, ServerError>(conn).await?;
+        let server_name_cookie = conn.intern_atom(false, im_name.as_bytes()).await?;
+
+        let reply = conn
+            .get_property(
+                false,
+                screen.root,
+                atoms.XIM_SERVERS,
+                AtomEnum::ATOM,
+                0,
+                u32::MAX,
+            )
+            .await?
+            .reply()
+            .await?;
+
+        if reply.type_ != x11rb::NONE && (reply.type_ != u32::from(AtomEnum::ATOM)) {
+            return Err(ServerError::InvalidReply);
+        }
+
+        let server_name = server_name_cookie.reply().await?.atom;
+
+        let mut found = false;
+
+        if reply.type_ != x11rb::NONE {
+            for prop in reply.value32().ok_or(ServerError::InvalidReply)? {
+                if prop == server_name {
+                    log::info!("Found previous XIM_SERVER it will overrided");
+                    found = true;
+                }
+            }
+        }
+
+        // override owner
+        conn.set_selection_owner(im_win, server_name, CURRENT_TIME)
+            .await?;
+
+        if !found {
+            conn.change_property(
+                PropMode::PREPEND,
+                screen.root,
+                atoms.XIM_SERVERS,
+                AtomEnum::ATOM,
+                32,
+                1,
+                &server_name.to_ne_bytes(),
+            )
+            .await?;
+        }
+
+        conn.flush().await?;
+
+        log::info!("Start server win: {}", im_win);
+
+        Ok(Self {
+            has_conn,
+            transport: ServerTransport::new(im_win, atoms, atom_pool, locales),
+            outbox: VecDeque::new(),
+            existing_windows: Vec::new(),
+        })
+    }
+
+    #[inline(always)]
+    fn conn(&self) -> &C::Connection {
+        self.has_conn.conn()
+    }
+
+    /// Transports advertised to clients, only `X/` by default
+    pub fn set_transports(&mut self, transports: &[Transport]) {
+        self.transport.set_transports(transports);
+    }
+
+    /// Send every queued request
+    pub async fn flush(&mut self) -> Result<(), ServerError> {
+        while let Some((target, buf)) = self.outbox.pop_front() {
+            send_frames::<_, ServerError>(
+                &self.has_conn,
+                &self.transport.atoms,
+                target,
+                buf,
+                &mut self.transport.atom_pool,
+                20,
+            )
+            .await?;
+        }
+        self.conn().flush().await?;
+        Ok(())
+    }
+
+    pub async fn filter_event<T>(
+        &mut self,
+        e: &Event,
+        connections: &mut XimConnections<T>,
+        handler: &mut impl ServerHandler<Self, InputContextData = T>,
+    ) -> Result<bool, ServerError> {
+        match self.transport.handle_event(e, connections) {
+            ServerEvent::SelectionNotify(notify) => {
+                let data = self.transport.selection_data(notify.property);
+                self.conn()
+                    .change_property(
+                        PropMode::REPLACE,
+                        notify.requestor,
+                        notify.property,
+                        notify.target,
+                        8,
+                        data.len() as u32,
+                        data,
+                    )
+                    .await?;
+                self.conn()
+                    .send_event(false, notify.requestor, EventMask::NO_EVENT, notify)
+                    .await?;
+            }
+            ServerEvent::XConnect(client_win) => {
+                let com_win =
+                    create_input_window::<_, ServerError>(self.conn(), self.transport.im_win, 0)
+                        .await?;
+                self.conn()
+                    .change_window_attributes(client_win, &client_window_attributes())
+                    .await?;
+                self.conn()
+                    .send_event(
+                        false,
+                        client_win,
+                        EventMask::NO_EVENT,
+                        self.transport.xconnect_reply(client_win, com_win),
+                    )
+                    .await?;
+                connections.new_connection(com_win, client_win);
+            }
+            ServerEvent::Protocol {
+                com_win,
+                endian,
+                data,
+            } => {
+                let req = read_protocol_data::<_, ServerError>(&self.has_conn, data)
+                    .await?
+                    .and_then(|data| xim_parser::read_with_endian(&data, endian));
+
+                if let Ok(req) = &req {
+                    self.check_windows(req, endian).await?;
+                }
+                let disconnected = dispatch_request(self, connections, com_win, req, handler);
+                self.existing_windows.clear();
+
+                if disconnected? {
+                    self.remove_connection(com_win, connections, handler)
+                        .await?;
+                }
+            }
+            ServerEvent::ClientDestroyed(com_win) => {
+                self.remove_connection(com_win, connections, handler)
+                    .await?;
+            }
+            ServerEvent::Handled => {}
+            ServerEvent::Unhandled => return Ok(false),
+        }
+
+        self.flush().await?;
+
+        Ok(true)
+    }
+
+    /// Look up `ClientWindow` and `FocusWindow` of `req` for `window_exists`
+    ///
+    /// `window_exists` can't wait for a reply, so only windows of the request being handled
+    /// are checked and every other window is rejected.
+    async fn check_windows(&mut self, req: &Request, endian: Endian) -> Result<(), ServerError> {
+        let ic_attributes = match req {
+            Request::CreateIc { ic_attributes, .. }
+            | Request::SetIcValues { ic_attributes, .. } => ic_attributes,
+            _ => return Ok(()),
+        };
+
+        for attr in ic_attributes {
+            if attr.id != attrs::CLIENTWIN.id && attr.id != attrs::FOCUSWIN.id {
+                continue;
+            }
+
+            if let Ok(window) = xim_parser::read_with_endian::<u32>(&attr.value, endian) {
+                let cookie = self.conn().get_window_attributes(window).await?;
+                if cookie.reply().await.is_ok() {
+                    self.existing_windows.push(window);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn remove_connection<T>(
+        &mut self,
+        com_win: Window,
+        connections: &mut XimConnections<T>,
+        handler: &mut impl ServerHandler<Self, InputContextData = T>,
+    ) -> Result<(), ServerError> {
+        let client_win = disconnect(self, connections, com_win, handler)?;
+        self.transport.remove_connection(com_win, client_win);
+        self.conn().destroy_window(com_win).await?;
+
+        Ok(())
+    }
+}
+
+/// Requests are queued, call `X11rbAsyncServer::flush` after sending outside of `filter_event`
+#[cfg(feature = "x11rb-async-server")]
+impl<C: HasConnection> ServerCore for X11rbAsyncServer<C> {
+    type XEvent = x11rb::protocol::xproto::KeyPressEvent;
+
+    fn send_req(
+        &mut self,
+        client_win: u32,
+        endian: Endian,
+        req: Request,
+    ) -> Result<(), ServerError> {
+        let mut buf = Vec::new();
+        serialize_request(&req, endian, &mut buf);
+        self.outbox.push_back((client_win, buf));
+        Ok(())
+    }
+
+    #[inline]
+    fn deserialize_event(&self, ev: &xim_parser::XEvent) -> Self::XEvent {
+        deserialize_event_impl(ev)
+    }
+
+    /// Only windows of the request `filter_event` is handling are known to exist
+    fn window_exists(&self, window: u32) -> bool {
+        self.existing_windows.contains(&window)
+    }
+}
+
+#[cfg(feature = "x11rb-async-client")]
+pub struct X11rbAsyncClient<C: HasConnection> {
+    has_conn: C,
+    server_owner_window: Window,
+    im_window: Window,
+    server_atom: Atom,
+    atoms: Atoms<Atom>,
+    transport_max: usize,
+    transports: Vec<Transport>,
+    client_window: u32,
+    endian: Endian,
+    im_attributes: AHashMap<AttributeName, Attr>,
+    ic_attributes: AHashMap<AttributeName, Attr>,
+    state: ClientState,
+    atom_pool: AtomPool<Atom, Window>,
+    fragments: Fragments,
+    outbox: VecDeque<Vec<u8>>,
+    pending_events: VecDeque<Event>,
+}
+
+#[cfg(feature = "x11rb-async-client")]
+impl<C: HasConnection> X11rbAsyncClient<C> {
+    /// Find the server and finish `_XIM_XCONNECT`, `Connect` is sent on return
+    ///
+    /// Unrelated events arriving meanwhile are returned later by `next_event`
+    pub async fn init(
+        has_conn: C,
+        screen_num: usize,
+        im_name: Option<&str>,
+    ) -> Result<Self, ClientError> {
+        let conn = has_conn.conn();
+        let screen = &conn.setup().roots[screen_num];
+        let client_window =
+            create_input_window::<_, ClientError>(conn, screen.root, screen.root_visual).await?;
+
+        let var = std::env::var("XMODIFIERS").ok();
+        let var = var.as_ref().and_then(|n| n.strip_prefix("@im="));
+        let im_name = im_name.or(var).ok_or(ClientError::NoXimServer)?;
+
+        log::info!("Try connect {}", im_name);
+
+        let atoms = intern_atoms::<_, ClientError>(conn).await?;
+        let atom_pool = intern_atom_pool::<_, ClientError>(conn).await?;
+        let server_reply = conn
+            .get_property(
+                false,
+                screen.root,
+                atoms.XIM_SERVERS,
+                AtomEnum::ATOM,
+                0,
+                u32::MAX,
+            )
+            .await?
+            .reply()
+            .await?;
+
+        if server_reply.type_ != u32::from(AtomEnum::ATOM) || server_reply.format != 32 {
+            return Err(ClientError::InvalidReply);
+        }
+
+        let mut found = None;
+
+        for server_atom in server_reply.value32().ok_or(ClientError::InvalidReply)? {
+            let owner_cookie = conn.get_selection_owner(server_atom).await?;
+            let name_cookie = conn.get_atom_name(server_atom).await?;
+            let server_owner = owner_cookie.reply().await?.owner;
+            let name = name_cookie.reply().await?.name;
+
+            let name = match String::from_utf8(name) {
+                Ok(name) => name,
+                _ => continue,
+            };
+
+            if name.strip_prefix("@server=") == Some(im_name) {
+                found = Some((server_atom, server_owner));
+                break;
+            }
+        }
+
+        let (server_atom, server_owner_window) = found.ok_or(ClientError::NoXimServer)?;
+
+        let mut client = Self {
+            has_conn,
+            atoms,
+            server_atom,
+            server_owner_window,
+            im_attributes: AHashMap::with_hasher(Default::default()),
+            ic_attributes: AHashMap::with_hasher(Default::default()),
+            state: ClientState::default(),
+            im_window: x11rb::NONE,
+            transport_max: 20,
+            transports: Vec::new(),
+            client_window,
+            endian: Endian::NATIVE,
+            atom_pool,
+            fragments: Fragments::default(),
+            outbox: VecDeque::new(),
+            pending_events: VecDeque::new(),
+        };
+
+        client.xconnect().await?;
+
+        Ok(client)
+    }
+
+    #[inline(always)]
+    fn conn(&self) -> &C::Connection {
+        self.has_conn.conn()
+    }
+
+    /// Transports server advertised
+    pub fn transports(&self) -> &[Transport] {
+        &self.transports
+    }
+
+    async fn selection_property(&self, property: Atom) -> Result<Vec<u8>, ClientError> {
+        Ok(self
+            .conn()
+            .get_property(true, self.client_window, property, property, 0, u32::MAX)
+            .await?
+            .reply()
+            .await?
+            .value)
+    }
+
+    async fn convert_selection(&self, property: Atom) -> Result<(), ClientError> {
+        self.conn()
+            .convert_selection(
+                self.client_window,
+                self.server_atom,
+                property,
+                property,
+                CURRENT_TIME,
+            )
+            .await?;
+        self.conn().flush().await?;
+        Ok(())
+    }
+
+    async fn xconnect(&mut self) -> Result<(), ClientError> {
+        self.convert_selection(self.atoms.TRANSPORT).await?;
+
+        loop {
+            let e = self.conn().wait_for_event().await?;
+
+            match &e {
+                Event::SelectionNotify(e) if e.requestor == self.client_window => {
+                    if e.property == self.atoms.TRANSPORT {
+                        let transport = self.selection_property(self.atoms.TRANSPORT).await?;
+                        self.transports = Transport::parse_list(&transport);
+
+                        if !self.transports.contains(&Transport::X) {
+                            return Err(ClientError::UnsupportedTransport);
+                        }
+
+                        self.convert_selection(self.atoms.LOCALES).await?;
+                    } else if e.property == self.atoms.LOCALES {
+                        // TODO: set locale
+                        let _locale = self.selection_property(self.atoms.LOCALES).await?;
+
+                        self.conn()
+                            .send_event(
+                                false,
+                                self.server_owner_window,
+                                EventMask::NO_EVENT,
+                                xconnect_request(
+                                    &self.atoms,
+                                    self.server_owner_window,
+                                    self.client_window,
+                                ),
+                            )
+                            .await?;
+                        self.conn().flush().await?;
+                    }
+                }
+                Event::ClientMessage(msg)
+                    if msg.window == self.client_window && msg.type_ == self.atoms.XIM_XCONNECT =>
+                {
+                    if let ClientEvent::XConnect {
+                        im_window,
+                        transport_max,
+                    } = client_message(&self.atoms, &mut self.fragments, msg)
+                    {
+                        self.im_window = im_window;
+                        self.transport_max = transport_max;
+                        self.conn()
+                            .change_window_attributes(im_window, &server_window_attributes())
+                            .await?;
+                        self.send_req(connect_request(self.endian))?;
+                        return self.flush().await;
+                    }
+                }
+                _ => self.pending_events.push_back(e),
+            }
+        }
+    }
+
+    /// Send every queued request
+    pub async fn flush(&mut self) -> Result<(), ClientError> {
+        while let Some(buf) = self.outbox.pop_front() {
+            send_frames::<_, ClientError>(
+                &self.has_conn,
+                &self.atoms,
+                self.im_window,
+                buf,
+                &mut self.atom_pool,
+                self.transport_max,
+            )
+            .await?;
+        }
+        self.conn().flush().await?;
+        Ok(())
+    }
+
+    pub async fn filter_event(
+        &mut self,
+        e: &Event,
+        handler: &mut impl ClientHandler<Self>,
+    ) -> Result<bool, ClientError> {
+        self.filter_event_with(e, handler, &mut |_| {}).await
+    }
+
+    /// Flush and wait for the next event, XIM events are handled instead of returned
+    pub async fn next_event(
+        &mut self,
+        handler: &mut impl ClientHandler<Self>,
+    ) -> Result<Event, ClientError> {
+        loop {
+            self.flush().await?;
+
+            let e = match self.pending_events.pop_front() {
+                Some(e) => e,
+                None => self.conn().wait_for_event().await?,
+            };
+
+            if !self.filter_event(&e, handler).await? {
+                return Ok(e);
+            }
+        }
+    }
+
+    /// Send `Open` and wait for `OpenReply`, return the input method id
+    pub async fn open_and_wait(
+        &mut self,
+        locale: &str,
+        handler: &mut impl ClientHandler<Self>,
+    ) -> Result<u16, ClientError> {
+        self.open(locale)?;
+        self.wait_for(handler, |req| match req {
+            Request::OpenReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Send `CreateIc` and wait for `CreateIcReply`, return the input context id
+    pub async fn create_ic_and_wait(
+        &mut self,
+        input_method_id: u16,
+        ic_attributes: Vec<xim_parser::Attribute>,
+        handler: &mut impl ClientHandler<Self>,
+    ) -> Result<u16, ClientError> {
+        self.create_ic(input_method_id, ic_attributes)?;
+        self.wait_for(handler, |req| match req {
+            Request::CreateIcReply {
+                input_method_id: im,
+                input_context_id,
+            } if *im == input_method_id => Some(*input_context_id),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Handle events until `f` picks a request, other events are kept for `next_event`
+    async fn wait_for<T>(
+        &mut self,
+        handler: &mut impl ClientHandler<Self>,
+        mut f: impl FnMut(&Request) -> Option<T>,
+    ) -> Result<T, ClientError> {
+        let mut ret = None;
+
+        loop {
+            self.flush().await?;
+
+            let e = self.conn().wait_for_event().await?;
+            let filtered = self
+                .filter_event_with(&e, handler, &mut |req| {
+                    if ret.is_none() {
+                        ret = f(req);
+                    }
+                })
+                .await?;
+
+            if !filtered {
+                self.pending_events.push_back(e);
+            }
+
+            if let Some(ret) = ret.take() {
+                self.flush().await?;
+                return Ok(ret);
+            }
+        }
+    }
+
+    async fn filter_event_with(
+        &mut self,
+        e: &Event,
+        handler: &mut impl ClientHandler<Self>,
+        f: &mut dyn FnMut(&Request),
+    ) -> Result<bool, ClientError> {
+        match e {
+            Event::ClientMessage(msg) if msg.window == self.client_window => {
+                match client_message(&self.atoms, &mut self.fragments, msg) {
+                    ClientEvent::Protocol(data) => {
+                        let data =
+                            read_protocol_data::<_, ClientError>(&self.has_conn, data).await??;
+                        let req = xim_parser::read_with_endian(&data, self.endian)?;
+                        if let Request::DisconnectReply {} = req {
+                            self.atom_pool.release_window(self.im_window);
+                        }
+                        f(&req);
+                        client_handle_request(self, handler, req)?;
+                        self.flush().await?;
+                        Ok(true)
+                    }
+                    // Late `_XIM_XCONNECT` is ignored, `init` already connected
+                    ClientEvent::XConnect { .. } | ClientEvent::Handled => Ok(true),
+                    ClientEvent::Unhandled => Ok(false),
+                }
+            }
+            Event::PropertyNotify(e) if e.window == self.im_window => {
+                Ok(release_atom(&mut self.atom_pool, e))
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Requests are queued, call `X11rbAsyncClient::flush` or wait for events to send them
+#[cfg(feature = "x11rb-async-client")]
+impl<C: HasConnection> ClientCore for X11rbAsyncClient<C> {
+    type XEvent = x11rb::protocol::xproto::KeyPressEvent;
+
+    fn set_attrs(&mut self, im_attrs: Vec<Attr>, ic_attrs: Vec<Attr>) {
+        for im_attr in im_attrs {
+            self.im_attributes.insert(im_attr.name, im_attr);
+        }
+
+        for ic_attr in ic_attrs {
+            self.ic_attributes.insert(ic_attr.name, ic_attr);
+        }
+    }
+
+    #[inline]
+    fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    #[inline]
+    fn ic_attributes(&self) -> &AHashMap<AttributeName, Attr> {
+        &self.ic_attributes
+    }
+
+    #[inline]
+    fn im_attributes(&self) -> &AHashMap<AttributeName, Attr> {
+        &self.im_attributes
+    }
+
+    #[inline]
+    fn state(&self) -> &ClientState {
+        &self.state
+    }
+
+    #[inline]
+    fn state_mut(&mut self) -> &mut ClientState {
+        &mut self.state
+    }
+
+    #[inline]
+    fn serialize_event(&self, xev: &Self::XEvent) -> xim_parser::XEvent {
+        serialize_event_impl(xev)
+    }
+
+    #[inline]
+    fn deserialize_event(&self, xev: &xim_parser::XEvent) -> Self::XEvent {
+        deserialize_event_impl(xev)
+    }
+
+    #[inline]
+    fn send_req(&mut self, req: Request) -> Result<(), ClientError> {
+        let mut buf = Vec::new();
+        serialize_request(&req, self.endian, &mut buf);
+        self.outbox.push_back(buf);
+        Ok(())
+    }
+}
@@ -3,34 +3,54 @@
 //!
 //! Note that it is generally discouraged to use Xlib in the modern era.
 
-use crate::AHashMap;
+use crate::{AHashMap, Transport};
 use alloc::vec::Vec;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::mem::MaybeUninit;
 use std::rc::Rc;
 use std::sync::Arc;
-use std::{convert::TryInto, os::raw::c_long};
+use std::{
+    convert::TryInto,
+    os::raw::{c_char, c_int, c_long},
+};
 
 use crate::{
-    client::{handle_request, ClientCore, ClientError, ClientHandler},
+    atom_pool::{self, AtomPool},
+    client::{handle_request, ClientCore, ClientError, ClientHandler, ClientState},
+    framing::{self, Fragments, Frames, PropertyAtom},
     Atoms,
 };
 use x11_dl::xlib;
-use xim_parser::{AttributeName, Request, XimWrite};
+use xim_parser::{Attr, AttributeName, Endian, Request, XimWrite};
 
 impl<X: XlibRef> ClientCore for XlibClient<X> {
     type XEvent = xlib::XKeyEvent;
 
     #[inline]
-    fn ic_attributes(&self) -> &AHashMap<AttributeName, u16> {
+    fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    #[inline]
+    fn ic_attributes(&self) -> &AHashMap<AttributeName, Attr> {
         &self.ic_attributes
     }
 
     #[inline]
-    fn im_attributes(&self) -> &AHashMap<AttributeName, u16> {
+    fn im_attributes(&self) -> &AHashMap<AttributeName, Attr> {
         &self.im_attributes
     }
 
+    #[inline]
+    fn state(&self) -> &ClientState {
+        &self.state
+    }
+
+    #[inline]
+    fn state_mut(&mut self) -> &mut ClientState {
+        &mut self.state
+    }
+
     #[inline]
     fn serialize_event(&self, xev: &Self::XEvent) -> xim_parser::XEvent {
         xim_parser::XEvent {
@@ -79,16 +99,16 @@ impl<X: XlibRef> ClientCore for XlibClient<X> {
 
     fn set_attrs(&mut self, ic_attrs: Vec<xim_parser::Attr>, im_attrs: Vec<xim_parser::Attr>) {
         for im_attr in im_attrs {
-            self.im_attributes.insert(im_attr.name, im_attr.id);
+            self.im_attributes.insert(im_attr.name, im_attr);
         }
 
         for ic_attr in ic_attrs {
-            self.ic_attributes.insert(ic_attr.name, ic_attr.id);
+            self.ic_attributes.insert(ic_attr.name, ic_attr);
         }
     }
 }
 
-impl<'a> XlibRef for &'a xlib::Xlib {
+impl XlibRef for &xlib::Xlib {
     fn xlib(&self) -> &xlib::Xlib {
         self
     }
@@ -131,10 +151,13 @@ pub struct XlibClient<X: XlibRef> {
     atoms: Atoms<xlib::Atom>,
     transport_max: usize,
     client_window: xlib::Window,
-    im_attributes: AHashMap<AttributeName, u16>,
-    ic_attributes: AHashMap<AttributeName, u16>,
+    endian: Endian,
+    im_attributes: AHashMap<AttributeName, Attr>,
+    ic_attributes: AHashMap<AttributeName, Attr>,
+    state: ClientState,
     buf: Vec<u8>,
-    sequence: u16,
+    fragments: Fragments,
+    atom_pool: AtomPool<xlib::Atom, xlib::Window>,
 }
 
 impl<X: XlibRef> XlibClient<X> {
@@ -165,6 +188,25 @@ impl<X: XlibRef> XlibClient<X> {
             }
         })?;
 
+        let pool_names = (0..atom_pool::INITIAL_SIZE)
+            .map(|i| CString::new(atom_pool::atom_name(i)).unwrap())
+            .collect::<Vec<_>>();
+        let mut pool_name_ptrs = pool_names
+            .iter()
+            .map(|name| name.as_ptr() as *mut c_char)
+            .collect::<Vec<_>>();
+        let mut pool_atoms = alloc::vec![0; pool_names.len()];
+        if (xlib.XInternAtoms)(
+            display,
+            pool_name_ptrs.as_mut_ptr(),
+            pool_name_ptrs.len() as c_int,
+            xlib::False,
+            pool_atoms.as_mut_ptr(),
+        ) == 0
+        {
+            return Err(ClientError::InvalidReply);
+        }
+
         let mut ty = MaybeUninit::uninit();
         let mut format = MaybeUninit::uninit();
         let mut items = MaybeUninit::uninit();
@@ -226,6 +268,7 @@ impl<X: XlibRef> XlibClient<X> {
                         return Ok(Self {
                             atoms,
                             client_window,
+                            endian: Endian::NATIVE,
                             server_atom,
                             server_owner_window: server_owner,
                             im_window: 0,
@@ -234,8 +277,10 @@ impl<X: XlibRef> XlibClient<X> {
                             x,
                             ic_attributes: AHashMap::with_hasher(Default::default()),
                             im_attributes: AHashMap::with_hasher(Default::default()),
+                            state: ClientState::default(),
                             buf: Vec::with_capacity(1024),
-                            sequence: 0,
+                            fragments: Fragments::default(),
+                            atom_pool: AtomPool::new(pool_atoms),
                         });
                     }
                 } else {
@@ -293,7 +338,7 @@ impl<X: XlibRef> XlibClient<X> {
                 } else if e.selection.property == self.atoms.TRANSPORT {
                     let transport = std::slice::from_raw_parts(prop, items as usize);
 
-                    if !transport.starts_with(b"@transport=X/") {
+                    if !Transport::parse_list(transport).contains(&Transport::X) {
                         return Err(ClientError::UnsupportedTransport);
                     }
 
@@ -326,14 +371,25 @@ impl<X: XlibRef> XlibClient<X> {
 
                     self.im_window = im_window as xlib::Window;
                     self.transport_max = max as usize;
+                    // Get PropertyNotify when server read a request sent with property
+                    (self.x.xlib().XSelectInput)(
+                        self.display,
+                        self.im_window,
+                        xlib::PropertyChangeMask,
+                    );
                     self.send_req(Request::Connect {
                         client_major_protocol_version: 1,
                         client_minor_protocol_version: 0,
-                        endian: xim_parser::Endian::Native,
+                        endian: self.endian,
                         client_auth_protocol_names: Vec::new(),
                     })?;
 
                     Ok(true)
+                } else if e.client_message.message_type == self.atoms.XIM_MOREDATA {
+                    let bytes = e.client_message.data.as_bytes();
+                    self.fragments
+                        .push(std::slice::from_raw_parts(bytes.as_ptr() as _, bytes.len()));
+                    Ok(true)
                 } else if e.client_message.message_type == self.atoms.XIM_PROTOCOL {
                     self.handle_xim_protocol(&e.client_message, handler)?;
                     Ok(true)
@@ -341,6 +397,12 @@ impl<X: XlibRef> XlibClient<X> {
                     Ok(false)
                 }
             }
+            xlib::PropertyNotify
+                if e.property.window == self.im_window
+                    && e.property.state == xlib::PropertyDelete =>
+            {
+                Ok(self.atom_pool.release(e.property.window, e.property.atom))
+            }
             _ => Ok(false),
         }
     }
@@ -360,13 +422,13 @@ impl<X: XlibRef> XlibClient<X> {
             let mut bytes = MaybeUninit::uninit();
             let mut prop = MaybeUninit::uninit();
 
-            unsafe {
+            let data = unsafe {
                 let code = (self.x.xlib().XGetWindowProperty)(
                     self.display,
                     msg.window,
                     atom as _,
                     0,
-                    length,
+                    i64::MAX,
                     xlib::True,
                     0,
                     ty.as_mut_ptr(),
@@ -380,25 +442,43 @@ impl<X: XlibRef> XlibClient<X> {
                     return Err(ClientError::InvalidReply);
                 }
 
-                let _ty = ty.assume_init();
+                let ty = ty.assume_init();
                 let _format = format.assume_init();
                 let items = items.assume_init();
                 let _bytes = bytes.assume_init();
                 let prop = prop.assume_init();
 
-                let data = std::slice::from_raw_parts(prop, items as usize);
+                let mut data = std::slice::from_raw_parts(prop, items as usize).to_vec();
+                (self.x.xlib().XFree)(prop as _);
 
-                let req = xim_parser::read(data)?;
+                if let Some(rest) = framing::split_property(&mut data, length as u32) {
+                    // Before anything server appended since the delete
+                    (self.x.xlib().XChangeProperty)(
+                        self.display,
+                        msg.window,
+                        atom as _,
+                        ty,
+                        8,
+                        xlib::PropModePrepend,
+                        rest.as_ptr(),
+                        rest.len() as _,
+                    );
+                }
 
-                handle_request(self, handler, req)?;
+                data
+            };
 
-                (self.x.xlib().XFree)(prop as _);
+            let req = xim_parser::read_with_endian(&data, self.endian)?;
+            if let Request::DisconnectReply {} = req {
+                self.atom_pool.release_window(self.im_window);
             }
+            handle_request(self, handler, req)?;
         } else if msg.format == 8 {
             let bytes = msg.data.as_bytes();
             let data: &[u8] =
                 unsafe { std::slice::from_raw_parts(bytes.as_ptr() as _, bytes.len()) };
-            let req = xim_parser::read(data)?;
+            let data = self.fragments.finish(data)?;
+            let req = xim_parser::read_with_endian(&data, self.endian)?;
             handle_request(self, handler, req)?;
         }
 
@@ -437,71 +517,83 @@ impl<X: XlibRef> XlibClient<X> {
         }
 
         self.buf.resize(req.size(), 0);
-        xim_parser::write(&req, &mut self.buf);
-
-        if self.buf.len() < self.transport_max {
-            if self.buf.len() > 20 {
-                todo!("multi-CM");
-            }
-            self.buf.resize(20, 0);
-            let buf: [u8; 20] = self.buf.as_slice().try_into().unwrap();
-            let mut ev = xlib::XClientMessageEvent {
-                type_: xlib::ClientMessage,
-                display: self.display,
-                message_type: self.atoms.XIM_PROTOCOL,
-                data: buf.into(),
-                format: 8,
-                serial: 0,
-                send_event: xlib::True,
-                window: self.im_window,
-            }
-            .into();
-            unsafe {
-                (self.x.xlib().XSendEvent)(
-                    self.display,
-                    self.im_window,
-                    xlib::False,
-                    xlib::NoEventMask,
-                    &mut ev,
-                );
+        xim_parser::write_with_endian(&req, &mut self.buf, self.endian);
+
+        match framing::frame(
+            &mut self.buf,
+            self.transport_max,
+            &mut self.atom_pool,
+            self.im_window,
+        ) {
+            Frames::ClientMessages(messages) => {
+                for message in messages {
+                    let mut ev = xlib::XClientMessageEvent {
+                        type_: xlib::ClientMessage,
+                        display: self.display,
+                        message_type: message.message_type(&self.atoms),
+                        data: message.data().into(),
+                        format: 8,
+                        serial: 0,
+                        send_event: xlib::True,
+                        window: self.im_window,
+                    }
+                    .into();
+                    unsafe {
+                        (self.x.xlib().XSendEvent)(
+                            self.display,
+                            self.im_window,
+                            xlib::False,
+                            xlib::NoEventMask,
+                            &mut ev,
+                        );
+                    }
+                }
             }
-        } else {
-            let name = alloc::format!("_XIM_DATA_{}\0", self.sequence);
-            self.sequence += 1;
-            let prop =
-                unsafe { (self.x.xlib().XInternAtom)(self.display, name.as_ptr().cast(), 0) };
+            Frames::Property(prop, data) => {
+                let prop = match prop {
+                    PropertyAtom::Acquired(prop) => prop,
+                    PropertyAtom::Intern(name) => {
+                        let name = CString::new(name).unwrap();
+                        let prop = unsafe {
+                            (self.x.xlib().XInternAtom)(self.display, name.as_ptr(), xlib::False)
+                        };
+                        self.atom_pool.push(self.im_window, prop);
+                        prop
+                    }
+                };
 
-            unsafe {
-                (self.x.xlib().XChangeProperty)(
-                    self.display,
-                    self.im_window,
-                    prop,
-                    xlib::XA_STRING,
-                    8,
-                    xlib::PropModeAppend,
-                    self.buf.as_ptr(),
-                    self.buf.len() as _,
-                );
-            }
-            let mut ev = xlib::XClientMessageEvent {
-                type_: xlib::ClientMessage,
-                display: self.display,
-                message_type: self.atoms.XIM_PROTOCOL,
-                data: [self.buf.len() as _, prop, 0, 0, 0].into(),
-                format: 32,
-                serial: 0,
-                send_event: xlib::True,
-                window: self.im_window,
-            }
-            .into();
-            unsafe {
-                (self.x.xlib().XSendEvent)(
-                    self.display,
-                    self.im_window,
-                    xlib::False,
-                    xlib::NoEventMask,
-                    &mut ev,
-                );
+                unsafe {
+                    (self.x.xlib().XChangeProperty)(
+                        self.display,
+                        self.im_window,
+                        prop,
+                        xlib::XA_STRING,
+                        8,
+                        xlib::PropModeAppend,
+                        data.as_ptr(),
+                        data.len() as _,
+                    );
+                }
+                let mut ev = xlib::XClientMessageEvent {
+                    type_: xlib::ClientMessage,
+                    display: self.display,
+                    message_type: self.atoms.XIM_PROTOCOL,
+                    data: [data.len() as _, prop, 0, 0, 0].into(),
+                    format: 32,
+                    serial: 0,
+                    send_event: xlib::True,
+                    window: self.im_window,
+                }
+                .into();
+                unsafe {
+                    (self.x.xlib().XSendEvent)(
+                        self.display,
+                        self.im_window,
+                        xlib::False,
+                        xlib::NoEventMask,
+                        &mut ev,
+                    );
+                }
             }
         }
         self.buf.clear();
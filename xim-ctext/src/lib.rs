@@ -1,6 +1,10 @@
 //! A parser for the compound text encoding used by the X Input Method protocol.
 //!
-//! Currently only support utf8 mode. This is intended to be used as a building block for
+//! Decoding supports every ISO 2022 designation of the X11 Compound Text spec, the utf8
+//! segment and extended segments with an encoding name. Encoding uses the utf8 segment or
+//! legacy ISO 8859-1 and CJK segments for older clients. Lossy decoding replaces bad
+//! sequences with U+FFFD, and [`Decoder`] decodes chunks into [`core::fmt::Write`] without
+//! allocation. This is intended to be used as a building block for
 //! higher level libraries. See the [`xim`] crate for an example.
 //!
 //! [xim]: https://crates.io/crates/xim
@@ -17,12 +21,13 @@ extern crate std;
 use alloc::string::String;
 use alloc::vec::Vec;
 use core::fmt;
+use encoding_rs::{DecoderResult, EncoderResult, Encoding};
 
 #[cfg(feature = "std")]
 use std::io::{self, Write};
 
-const UTF8_START: &[u8] = &[0x1B, 0x25, 0x47];
-const UTF8_END: &[u8] = &[0x1B, 0x25, 0x40];
+const UTF8_START: &str = "\x1B%G";
+const UTF8_END: &str = "\x1B%@";
 
 /// Wrapper for reduce allocation
 #[derive(Clone, Copy)]
@@ -59,19 +64,148 @@ impl<'s> CText<'s> {
     #[cfg(feature = "std")]
     pub fn write(self, mut out: impl Write) -> io::Result<usize> {
         let mut writed = 0;
-        writed += out.write(UTF8_START)?;
+        writed += out.write(UTF8_START.as_bytes())?;
         writed += out.write(self.utf8.as_bytes())?;
-        writed += out.write(UTF8_END)?;
+        writed += out.write(UTF8_END.as_bytes())?;
         Ok(writed)
     }
+
+    /// Write COMPOUND_TEXT to `out`, escape sequences of utf8 segment are ASCII so it is
+    /// still valid utf8
+    pub fn encode_into<W: fmt::Write + ?Sized>(self, out: &mut W) -> fmt::Result {
+        out.write_str(UTF8_START)?;
+        out.write_str(self.utf8)?;
+        out.write_str(UTF8_END)
+    }
+}
+
+/// 94^2 character set tried by [`EncodeStrategy::Legacy`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CjkCharset {
+    Jis0208,
+    Gb2312,
+    Ksc5601,
+}
+
+impl CjkCharset {
+    const ALL: [CjkCharset; 3] = [Self::Jis0208, Self::Gb2312, Self::Ksc5601];
+
+    /// Final byte of `ESC $ ) F`
+    const fn final_byte(self) -> u8 {
+        match self {
+            Self::Jis0208 => b'B',
+            Self::Gb2312 => b'A',
+            Self::Ksc5601 => b'C',
+        }
+    }
+
+    const fn encoding(self) -> &'static Encoding {
+        match self {
+            Self::Jis0208 => encoding_rs::EUC_JP,
+            Self::Gb2312 => encoding_rs::GBK,
+            Self::Ksc5601 => encoding_rs::EUC_KR,
+        }
+    }
+
+    /// Encode `c` in GR, `None` when the set doesn't have it
+    fn encode(self, c: char) -> Option<[u8; 2]> {
+        let mut src = [0; 4];
+        let mut dst = [0; 8];
+        let mut encoder = self.encoding().new_encoder();
+        let (ret, _, written) =
+            encoder.encode_from_utf8_without_replacement(c.encode_utf8(&mut src), &mut dst, true);
+
+        // Extensions of GBK and UHC or halfwidth katakana of EUC-JP are not in the 94^2 set
+        match (ret, &dst[..written]) {
+            (EncoderResult::InputEmpty, &[a @ 0xA1..=0xFE, b @ 0xA1..=0xFE]) => Some([a, b]),
+            _ => None,
+        }
+    }
+}
+
+/// How [`utf8_to_compound_text_with`] chooses segments
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EncodeStrategy {
+    /// Wrap the whole string with utf8 escape
+    Utf8,
+    /// Use ISO 8859-1 and 94^2 sets understood by pre-UTF-8 Xlib, the given set is tried first
+    ///
+    /// Characters not in any of them still go to the utf8 segment.
+    Legacy(CjkCharset),
+}
+
+impl Default for EncodeStrategy {
+    fn default() -> Self {
+        Self::Utf8
+    }
 }
 
 /// Encoding utf8 to COMPOUND_TEXT with utf8 escape
 pub fn utf8_to_compound_text(text: &str) -> Vec<u8> {
     let mut ret = Vec::with_capacity(text.len() + 6);
-    ret.extend_from_slice(UTF8_START);
+    ret.extend_from_slice(UTF8_START.as_bytes());
     ret.extend_from_slice(text.as_bytes());
-    ret.extend_from_slice(UTF8_END);
+    ret.extend_from_slice(UTF8_END.as_bytes());
+    ret
+}
+
+/// Encoding utf8 to COMPOUND_TEXT with `strategy`
+pub fn utf8_to_compound_text_with(text: &str, strategy: EncodeStrategy) -> Vec<u8> {
+    let preferred = match strategy {
+        EncodeStrategy::Utf8 => return utf8_to_compound_text(text),
+        EncodeStrategy::Legacy(preferred) => preferred,
+    };
+
+    let charsets = core::iter::once(preferred)
+        .chain(CjkCharset::ALL.iter().copied().filter(|&c| c != preferred));
+
+    let mut ret = Vec::with_capacity(text.len());
+    // G0 is always ASCII, `None` is the initial ISO 8859-1 right half
+    let mut g1 = None;
+    let mut in_utf8 = false;
+
+    for c in text.chars() {
+        let encoded = match u32::from(c) {
+            0x00..=0x7F | 0xA0..=0xFF => Some((None, [c as u8, 0], 1)),
+            _ => charsets
+                .clone()
+                .find_map(|charset| Some((Some(charset), charset.encode(c)?, 2))),
+        };
+
+        let (charset, bytes, len) = match encoded {
+            Some(encoded) => encoded,
+            None => {
+                if !in_utf8 {
+                    ret.extend_from_slice(UTF8_START.as_bytes());
+                    in_utf8 = true;
+                }
+                let mut buf = [0; 4];
+                ret.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                continue;
+            }
+        };
+
+        if in_utf8 {
+            ret.extend_from_slice(UTF8_END.as_bytes());
+            in_utf8 = false;
+        }
+
+        // ASCII doesn't touch G1
+        if bytes[0] >= 0x80 && g1 != charset {
+            match charset {
+                Some(charset) => ret.extend_from_slice(&[ESC, b'$', b')', charset.final_byte()]),
+                None => ret.extend_from_slice(&[ESC, b'-', b'A']),
+            }
+            g1 = charset;
+        }
+
+        ret.extend_from_slice(&bytes[..len]);
+    }
+
+    if in_utf8 {
+        ret.extend_from_slice(UTF8_END.as_bytes());
+    }
+
     ret
 }
 
@@ -80,6 +214,14 @@ pub enum DecodeError {
     InvalidEncoding,
     UnsupportedEncoding,
     Utf8Error(alloc::string::FromUtf8Error),
+    /// Output of [`decode_into`] failed
+    Fmt(fmt::Error),
+}
+
+impl From<fmt::Error> for DecodeError {
+    fn from(err: fmt::Error) -> Self {
+        DecodeError::Fmt(err)
+    }
 }
 
 impl From<alloc::string::FromUtf8Error> for DecodeError {
@@ -94,73 +236,751 @@ impl fmt::Display for DecodeError {
             Self::InvalidEncoding => write!(f, "Invalid compound text"),
             Self::UnsupportedEncoding => write!(f, "This encoding is not supported yet"),
             Self::Utf8Error(e) => write!(f, "Not a valid utf8 {}", e),
+            Self::Fmt(e) => write!(f, "Can't write decoded text {}", e),
         }
     }
 }
 
-macro_rules! decode {
-    ($decoder:expr, $out:expr, $bytes:expr, $last:expr) => {
-        loop {
-            let (ret, _, _) = $decoder.decode_to_string($bytes, $out, $last);
-
-            match ret {
-                encoding_rs::CoderResult::InputEmpty => break,
-                encoding_rs::CoderResult::OutputFull => {
-                    $out.reserve(
-                        $decoder
-                            .max_utf8_buffer_length($bytes.len())
-                            .unwrap_or_default(),
-                    );
-                }
+const ESC: u8 = 0x1B;
+const CSI: u8 = 0x9B;
+const STX: u8 = 0x02;
+
+/// Longest pending escape sequence, utf8 character or extended segment name
+const PENDING_MAX: usize = 32;
+
+/// Character set designated to G0 or G1
+#[derive(Clone, Copy)]
+enum Charset {
+    /// ISO 8859-1 left half
+    Ascii,
+    /// JIS X 0201 left half
+    JisRoman,
+    /// JIS X 0201 right half
+    JisKatakana,
+    /// ISO 8859-1 right half
+    Latin1,
+    /// Other ISO 8859 right halves
+    Iso8859(&'static Encoding),
+    Gb2312,
+    Jis0208,
+    Jis0212,
+    Ksc5601,
+    /// Unsupported set with bytes per character, only designated in lossy mode
+    Unknown(usize),
+}
+
+impl Charset {
+    /// 94 character set for `ESC ( F` and `ESC ) F`
+    fn from_94(f: u8) -> Result<Self, DecodeError> {
+        match f {
+            b'B' => Ok(Self::Ascii),
+            b'J' => Ok(Self::JisRoman),
+            b'I' => Ok(Self::JisKatakana),
+            _ => Err(unknown_final(f)),
+        }
+    }
+
+    /// 96 character set for `ESC - F`
+    fn from_96(f: u8) -> Result<Self, DecodeError> {
+        let encoding = match f {
+            b'A' => return Ok(Self::Latin1),
+            b'B' => encoding_rs::ISO_8859_2,
+            b'C' => encoding_rs::ISO_8859_3,
+            b'D' => encoding_rs::ISO_8859_4,
+            b'F' => encoding_rs::ISO_8859_7,
+            b'G' => encoding_rs::ISO_8859_6,
+            b'H' => encoding_rs::ISO_8859_8,
+            b'L' => encoding_rs::ISO_8859_5,
+            // right half of windows-1254 and windows-874 matches ISO 8859-9 and TIS 620
+            b'M' => encoding_rs::WINDOWS_1254,
+            b'T' => encoding_rs::WINDOWS_874,
+            b'V' => encoding_rs::ISO_8859_10,
+            b'Y' => encoding_rs::ISO_8859_13,
+            b'_' => encoding_rs::ISO_8859_14,
+            b'b' => encoding_rs::ISO_8859_15,
+            b'f' => encoding_rs::ISO_8859_16,
+            _ => return Err(unknown_final(f)),
+        };
+
+        Ok(Self::Iso8859(encoding))
+    }
+
+    /// 94^N character set for `ESC $ ( F` and `ESC $ ) F`
+    fn from_94n(f: u8) -> Result<Self, DecodeError> {
+        match f {
+            b'A' => Ok(Self::Gb2312),
+            // `@` is JIS C 6226-1978 which is mostly compatible with JIS X 0208
+            b'@' | b'B' => Ok(Self::Jis0208),
+            b'C' => Ok(Self::Ksc5601),
+            b'D' => Ok(Self::Jis0212),
+            _ => Err(unknown_final(f)),
+        }
+    }
+
+    /// Bytes per character
+    const fn width(self) -> usize {
+        match self {
+            Self::Gb2312 | Self::Jis0208 | Self::Jis0212 | Self::Ksc5601 => 2,
+            Self::Unknown(width) => width,
+            _ => 1,
+        }
+    }
+
+    /// Decode a character from GL(0x21..=0x7E) or GR(0xA0..=0xFF), `c` has `width` bytes
+    fn decode<W: fmt::Write + ?Sized>(
+        self,
+        c: &[u8],
+        lossy: bool,
+        out: &mut W,
+    ) -> Result<(), DecodeError> {
+        let (encoding, prefix): (_, &[u8]) = match self {
+            Self::Ascii => return Ok(out.write_char(char::from(c[0] & 0x7F))?),
+            Self::JisRoman => {
+                let c = match c[0] & 0x7F {
+                    0x5C => '\u{A5}',
+                    0x7E => '\u{203E}',
+                    b => char::from(b),
+                };
+                return Ok(out.write_char(c)?);
+            }
+            Self::JisKatakana => {
+                return match c[0] | 0x80 {
+                    // U+FF61 HALFWIDTH IDEOGRAPHIC FULL STOP..U+FF9F
+                    b @ 0xA1..=0xDF => {
+                        Ok(out.write_char(char::from_u32(0xFF61 + u32::from(b - 0xA1)).unwrap())?)
+                    }
+                    _ => invalid(DecodeError::InvalidEncoding, lossy, out),
+                };
             }
+            Self::Latin1 => return Ok(out.write_char(char::from(c[0] | 0x80))?),
+            // 96 sets are only designated to G1 so `c` is always in GR
+            Self::Iso8859(encoding) => (encoding, &[]),
+            // 94^2 sets are decoded as EUC
+            Self::Gb2312 => (encoding_rs::GBK, &[]),
+            Self::Jis0208 => (encoding_rs::EUC_JP, &[]),
+            Self::Jis0212 => (encoding_rs::EUC_JP, &[0x8F]),
+            Self::Ksc5601 => (encoding_rs::EUC_KR, &[]),
+            Self::Unknown(_) => return invalid(DecodeError::UnsupportedEncoding, lossy, out),
+        };
+
+        // 94^2 sets don't have 0x20, 0x7F and those in GR
+        if c.len() == 2 && !c.iter().all(|b| matches!(b & 0x7F, 0x21..=0x7E)) {
+            return invalid(DecodeError::InvalidEncoding, lossy, out);
         }
-    };
+
+        let mut buf = [0; 3];
+        let len = prefix.len() + c.len();
+        buf[..prefix.len()].copy_from_slice(prefix);
+        for (dst, b) in buf[prefix.len()..len].iter_mut().zip(c) {
+            *dst = b | 0x80;
+        }
+
+        let mut decoder = encoding.new_decoder_without_bom_handling();
+        decode_with(&mut decoder, &buf[..len], true, lossy, out)
+    }
 }
 
-pub fn compound_text_to_utf8(bytes: &[u8]) -> Result<String, DecodeError> {
-    let mut iter = bytes.iter();
-
-    match iter.next() {
-        None => Ok(String::new()),
-        Some(0x1B) => {
-            match (iter.next(), iter.next()) {
-                // UTF-8
-                (Some(0x25), Some(0x47)) => {
-                    let left = iter.as_slice();
-                    Ok(String::from_utf8(left.split_at(left.len() - 3).0.to_vec())?)
+fn unknown_final(f: u8) -> DecodeError {
+    if (0x30..=0x7E).contains(&f) {
+        DecodeError::UnsupportedEncoding
+    } else {
+        DecodeError::InvalidEncoding
+    }
+}
+
+/// Return `err` or write U+FFFD in lossy mode
+fn invalid<W: fmt::Write + ?Sized>(
+    err: DecodeError,
+    lossy: bool,
+    out: &mut W,
+) -> Result<(), DecodeError> {
+    if lossy {
+        Ok(out.write_char(char::REPLACEMENT_CHARACTER)?)
+    } else {
+        Err(err)
+    }
+}
+
+/// Encoding of extended segment from its XLFD style `CharsetRegistry-CharsetEncoding` name
+fn extended_segment_encoding(name: &[u8]) -> Option<&'static Encoding> {
+    let name = core::str::from_utf8(name).ok()?;
+
+    if name.eq_ignore_ascii_case("iso10646-1") {
+        return Some(encoding_rs::UTF_16BE);
+    }
+
+    Encoding::for_label(name.as_bytes()).or_else(|| {
+        let (registry, _) = name.rsplit_once('-')?;
+        Encoding::for_label(registry.as_bytes())
+    })
+}
+
+/// Decode with `decoder` through a stack buffer
+fn decode_with<W: fmt::Write + ?Sized>(
+    decoder: &mut encoding_rs::Decoder,
+    mut bytes: &[u8],
+    last: bool,
+    lossy: bool,
+    out: &mut W,
+) -> Result<(), DecodeError> {
+    let mut buf = [0; 64];
+
+    loop {
+        let dst = core::str::from_utf8_mut(&mut buf).unwrap();
+        let (ret, read, written) = decoder.decode_to_str_without_replacement(bytes, dst, last);
+        out.write_str(&dst[..written])?;
+        bytes = &bytes[read..];
+
+        match ret {
+            DecoderResult::InputEmpty => return Ok(()),
+            DecoderResult::OutputFull => {}
+            DecoderResult::Malformed(..) => invalid(DecodeError::InvalidEncoding, lossy, out)?,
+        }
+    }
+}
+
+enum State {
+    /// Graphic characters and control functions, pending bytes are an incomplete escape
+    /// sequence, control sequence or 94^2 character
+    Text,
+    /// Utf8 segment, pending bytes are an incomplete character
+    Utf8,
+    /// Name of extended segment, `remain` bytes are left in the segment
+    ExtendedName { remain: usize },
+    /// Data of extended segment, `None` is an unsupported encoding skipped in lossy mode
+    Extended {
+        remain: usize,
+        decoder: Option<encoding_rs::Decoder>,
+    },
+}
+
+/// Streaming COMPOUND_TEXT decoder
+///
+/// G0(GL) starts with ASCII and G1(GR) with the right half of ISO 8859-1 like the spec says.
+/// Input can be split at any byte, incomplete sequences are kept until next
+/// [`decode_into`](Self::decode_into) without allocation. Don't reuse the decoder after an error.
+pub struct Decoder {
+    g0: Charset,
+    g1: Charset,
+    /// Replace bad sequences with U+FFFD instead of returning error
+    lossy: bool,
+    state: State,
+    pending: [u8; PENDING_MAX],
+    pending_len: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self::with_lossy(false)
+    }
+
+    /// Decoder that replaces invalid or unsupported sequences with U+FFFD instead of failing
+    pub const fn new_lossy() -> Self {
+        Self::with_lossy(true)
+    }
+
+    const fn with_lossy(lossy: bool) -> Self {
+        Self {
+            g0: Charset::Ascii,
+            g1: Charset::Latin1,
+            lossy,
+            state: State::Text,
+            pending: [0; PENDING_MAX],
+            pending_len: 0,
+        }
+    }
+
+    /// Decode a chunk of COMPOUND_TEXT to `out`
+    pub fn decode_into<W: fmt::Write + ?Sized>(
+        &mut self,
+        mut bytes: &[u8],
+        out: &mut W,
+    ) -> Result<(), DecodeError> {
+        while !bytes.is_empty() {
+            bytes = match self.state {
+                State::Text => {
+                    self.text(bytes[0], out)?;
+                    &bytes[1..]
+                }
+                State::Utf8 => self.utf8(bytes, out)?,
+                State::ExtendedName { remain } => self.extended_name(bytes, remain, out)?,
+                State::Extended { .. } => self.extended(bytes, out)?,
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Report incomplete input and reset the decoder
+    pub fn finish<W: fmt::Write + ?Sized>(&mut self, out: &mut W) -> Result<(), DecodeError> {
+        let lossy = self.lossy;
+        let pending = self.pending_len != 0;
+
+        match core::mem::replace(self, Self::with_lossy(lossy)).state {
+            // Missing `ESC % @` at the end is fine
+            State::Text | State::Utf8 if !pending => Ok(()),
+            State::Extended {
+                decoder: Some(mut decoder),
+                ..
+            } => {
+                decode_with(&mut decoder, &[], true, lossy, out)?;
+                invalid(DecodeError::InvalidEncoding, lossy, out)
+            }
+            _ => invalid(DecodeError::InvalidEncoding, lossy, out),
+        }
+    }
+
+    fn push_pending(&mut self, b: u8) -> bool {
+        if self.pending_len == PENDING_MAX {
+            return false;
+        }
+
+        self.pending[self.pending_len] = b;
+        self.pending_len += 1;
+        true
+    }
+
+    /// Handle a byte in text state
+    fn text<W: fmt::Write + ?Sized>(&mut self, b: u8, out: &mut W) -> Result<(), DecodeError> {
+        let first = match self.pending_len {
+            0 => return self.start(b, out),
+            _ => self.pending[0],
+        };
+
+        if first != ESC && first != CSI {
+            // Second byte of 94^2 character should be in the same half
+            self.pending_len = 0;
+
+            return match (first, b) {
+                (0x21..=0x7E, 0x21..=0x7E) => self.g0.decode(&[first, b], self.lossy, out),
+                (0xA0..=0xFF, 0xA0..=0xFF) => self.g1.decode(&[first, b], self.lossy, out),
+                _ => {
+                    invalid(DecodeError::InvalidEncoding, self.lossy, out)?;
+                    self.start(b, out)
+                }
+            };
+        }
+
+        // Extended segment has `M L` after the final byte
+        let extended = matches!(
+            self.pending[..self.pending_len],
+            [ESC, b'%', b'/', b'0'..=b'4', ..]
+        );
+        let valid = if extended {
+            b >= 0x80
+        } else {
+            (0x20..=0x7E).contains(&b)
+        };
+
+        if !valid || !self.push_pending(b) {
+            // Byte which can't be in the sequence ends it
+            self.pending_len = 0;
+            invalid(DecodeError::InvalidEncoding, self.lossy, out)?;
+            return self.start(b, out);
+        }
+
+        let len = self.pending_len;
+        let done = match self.pending[..len] {
+            [ESC, b'%', b'/', b'0'..=b'4', ..] => len == 6,
+            [ESC, ..] => (0x30..=0x7E).contains(&b),
+            _ => (0x40..=0x7E).contains(&b),
+        };
+
+        if !done {
+            return Ok(());
+        }
+
+        let seq = self.pending;
+        self.pending_len = 0;
+
+        match seq[..len] {
+            [ESC, ..] => self.escape(&seq[..len], out),
+            // Directionality
+            [CSI, b'1' | b'2', b']'] | [CSI, b']'] => Ok(()),
+            _ => invalid(DecodeError::InvalidEncoding, self.lossy, out),
+        }
+    }
+
+    /// Handle a byte in text state without pending bytes
+    fn start<W: fmt::Write + ?Sized>(&mut self, b: u8, out: &mut W) -> Result<(), DecodeError> {
+        match b {
+            ESC | CSI => {
+                self.push_pending(b);
+                Ok(())
+            }
+            // HT, NL and other controls
+            0x00..=0x20 | 0x7F => Ok(out.write_char(char::from(b))?),
+            0x21..=0x7E | 0xA0..=0xFF => {
+                let charset = if b < 0x80 { self.g0 } else { self.g1 };
+
+                if charset.width() == 1 {
+                    charset.decode(&[b], self.lossy, out)
+                } else {
+                    self.push_pending(b);
+                    Ok(())
+                }
+            }
+            _ => invalid(DecodeError::InvalidEncoding, self.lossy, out),
+        }
+    }
+
+    /// Unsupported sets are still designated in lossy mode so their characters become U+FFFD
+    fn designate(
+        &self,
+        charset: Result<Charset, DecodeError>,
+        width: usize,
+    ) -> Result<Charset, DecodeError> {
+        match charset {
+            Err(_) if self.lossy => Ok(Charset::Unknown(width)),
+            charset => charset,
+        }
+    }
+
+    /// Handle a complete escape sequence
+    fn escape<W: fmt::Write + ?Sized>(
+        &mut self,
+        seq: &[u8],
+        out: &mut W,
+    ) -> Result<(), DecodeError> {
+        match *seq {
+            [_, b'(', f] => self.g0 = self.designate(Charset::from_94(f), 1)?,
+            [_, b')', f] => self.g1 = self.designate(Charset::from_94(f), 1)?,
+            [_, b'-', f] => self.g1 = self.designate(Charset::from_96(f), 1)?,
+            [_, b'$', b'(', f] => self.g0 = self.designate(Charset::from_94n(f), 2)?,
+            [_, b'$', b')', f] => self.g1 = self.designate(Charset::from_94n(f), 2)?,
+            // Old short form which always designates to G0
+            [_, b'$', f @ (b'@' | b'A' | b'B')] => self.g0 = Charset::from_94n(f)?,
+            [_, b'%', b'G'] => self.state = State::Utf8,
+            // `utf8` ends the segment at any escape sequence so this is left
+            [_, b'%', b'@'] => {}
+            // Extended segment `ESC % / F M L name STX data`
+            [_, b'%', b'/', _, m, l] => {
+                let remain = usize::from(m & 0x7F) * 128 + usize::from(l & 0x7F);
+                self.state = State::ExtendedName { remain };
+            }
+            _ => invalid(DecodeError::InvalidEncoding, self.lossy, out)?,
+        }
+
+        Ok(())
+    }
+
+    /// Decode utf8 segment until an escape sequence
+    fn utf8<'b, W: fmt::Write + ?Sized>(
+        &mut self,
+        bytes: &'b [u8],
+        out: &mut W,
+    ) -> Result<&'b [u8], DecodeError> {
+        let end = bytes.iter().position(|&b| b == ESC).unwrap_or(bytes.len());
+        let mut text = &bytes[..end];
+
+        // Complete a character split by previous chunk
+        while self.pending_len != 0 && !text.is_empty() {
+            self.push_pending(text[0]);
+
+            match core::str::from_utf8(&self.pending[..self.pending_len]) {
+                Ok(c) => {
+                    out.write_str(c)?;
+                    self.pending_len = 0;
+                    text = &text[1..];
                 }
-                // 94N
-                (Some(0x24), Some(0x28)) => match iter.next() {
-                    // JP
-                    Some(0x42) => {
-                        let left = iter.as_slice();
-                        let mut decoder =
-                            encoding_rs::ISO_2022_JP.new_decoder_without_bom_handling();
-                        let mut out = String::new();
-
-                        decode!(decoder, &mut out, &[0x1B, 0x24, 0x42], false);
-                        decode!(decoder, &mut out, left, true);
-
-                        Ok(out)
+                Err(e) if e.error_len().is_none() => text = &text[1..],
+                // The last byte isn't a continuation, decode it again
+                Err(_) => {
+                    self.pending_len = 0;
+                    invalid(DecodeError::InvalidEncoding, self.lossy, out)?;
+                }
+            }
+        }
+
+        while !text.is_empty() {
+            match core::str::from_utf8(text) {
+                Ok(s) => {
+                    out.write_str(s)?;
+                    break;
+                }
+                Err(e) => {
+                    let (valid, rest) = text.split_at(e.valid_up_to());
+                    out.write_str(core::str::from_utf8(valid).unwrap())?;
+
+                    match e.error_len() {
+                        Some(len) => {
+                            invalid(DecodeError::InvalidEncoding, self.lossy, out)?;
+                            text = &rest[len..];
+                        }
+                        None => {
+                            for &b in rest {
+                                self.push_pending(b);
+                            }
+                            break;
+                        }
                     }
+                }
+            }
+        }
+
+        if end == bytes.len() {
+            return Ok(&[]);
+        }
+
+        if self.pending_len != 0 {
+            self.pending_len = 0;
+            invalid(DecodeError::InvalidEncoding, self.lossy, out)?;
+        }
+
+        // Any escape sequence ends the segment
+        self.state = State::Text;
+        self.push_pending(ESC);
+
+        Ok(&bytes[end + 1..])
+    }
+
+    fn extended_name<'b, W: fmt::Write + ?Sized>(
+        &mut self,
+        bytes: &'b [u8],
+        remain: usize,
+        out: &mut W,
+    ) -> Result<&'b [u8], DecodeError> {
+        let avail = &bytes[..remain.min(bytes.len())];
+        let (name, rest, found) = match avail.iter().position(|&b| b == STX) {
+            Some(end) => (&avail[..end], &bytes[end + 1..], true),
+            None => (avail, &bytes[avail.len()..], false),
+        };
+        let remain = remain - (bytes.len() - rest.len());
+
+        // Names longer than the buffer don't fit and aren't supported
+        for &b in name {
+            if !self.push_pending(b) {
+                break;
+            }
+        }
+
+        if !found {
+            if remain == 0 {
+                self.pending_len = 0;
+                self.state = State::Text;
+                invalid(DecodeError::InvalidEncoding, self.lossy, out)?;
+            } else {
+                self.state = State::ExtendedName { remain };
+            }
+
+            return Ok(rest);
+        }
+
+        let encoding = match self.pending_len {
+            PENDING_MAX => None,
+            len => extended_segment_encoding(&self.pending[..len]),
+        };
+        self.pending_len = 0;
+
+        if encoding.is_none() {
+            invalid(DecodeError::UnsupportedEncoding, self.lossy, out)?;
+        }
+
+        self.state = match remain {
+            0 => State::Text,
+            _ => State::Extended {
+                remain,
+                decoder: encoding.map(Encoding::new_decoder_without_bom_handling),
+            },
+        };
+
+        Ok(rest)
+    }
+
+    fn extended<'b, W: fmt::Write + ?Sized>(
+        &mut self,
+        bytes: &'b [u8],
+        out: &mut W,
+    ) -> Result<&'b [u8], DecodeError> {
+        let lossy = self.lossy;
+        let (remain, decoder) = match &mut self.state {
+            State::Extended { remain, decoder } => (remain, decoder),
+            _ => unreachable!(),
+        };
+
+        let (data, rest) = bytes.split_at((*remain).min(bytes.len()));
+        *remain -= data.len();
+        let last = *remain == 0;
+
+        if let Some(decoder) = decoder {
+            decode_with(decoder, data, last, lossy, out)?;
+        }
+
+        if last {
+            self.state = State::Text;
+        }
+
+        Ok(rest)
+    }
+}
+
+/// Decode whole COMPOUND_TEXT to `out`
+pub fn decode_into<W: fmt::Write + ?Sized>(bytes: &[u8], out: &mut W) -> Result<(), DecodeError> {
+    let mut decoder = Decoder::new();
+    decoder.decode_into(bytes, out)?;
+    decoder.finish(out)
+}
+
+/// Decode COMPOUND_TEXT to utf8
+pub fn compound_text_to_utf8(bytes: &[u8]) -> Result<String, DecodeError> {
+    let mut out = String::with_capacity(bytes.len());
+    decode_into(bytes, &mut out)?;
+    Ok(out)
+}
+
+/// Decode COMPOUND_TEXT to utf8, invalid or unsupported sequences are replaced with U+FFFD
+pub fn compound_text_to_utf8_lossy(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    let mut decoder = Decoder::new_lossy();
+    // Can't fail in lossy mode when writing to `String`
+    let _ = decoder.decode_into(bytes, &mut out);
+    let _ = decoder.finish(&mut out);
+    out
+}
+
+/// Text encoding negotiated with `XIM_ENCODING_NEGOTIATION`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TextEncoding {
+    /// `COMPOUND_TEXT`, the default when nothing else is negotiated
+    CompoundText,
+    /// `UTF8_STRING`
+    Utf8String,
+    /// Locale charset like `EUC-JP` or `GBK`
+    Charset(&'static Encoding),
+}
+
+impl Default for TextEncoding {
+    fn default() -> Self {
+        Self::CompoundText
+    }
+}
+
+impl TextEncoding {
+    /// Find encoding by name, `ja_JP.eucJP` style locale names work too
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim_end_matches('\0');
+
+        if name.starts_with("COMPOUND_TEXT") {
+            return Some(Self::CompoundText);
+        }
+
+        if ["UTF8_STRING", "UTF-8_STRING"]
+            .iter()
+            .any(|n| name.eq_ignore_ascii_case(n))
+        {
+            return Some(Self::Utf8String);
+        }
+
+        let charset = name.rsplit('.').next().unwrap_or(name);
+        let charset = charset.split('@').next().unwrap_or(charset);
+
+        match Encoding::for_label(charset.as_bytes()).or_else(|| locale_charset(charset)) {
+            Some(encoding) if encoding == encoding_rs::UTF_8 => Some(Self::Utf8String),
+            // UTF-16 and replacement can't encode
+            Some(encoding) if encoding.output_encoding() == encoding => {
+                Some(Self::Charset(encoding))
+            }
+            _ => None,
+        }
+    }
+
+    /// Find encoding of a detailed info of `XIM_ENCODING_NEGOTIATION`
+    ///
+    /// Info is the encoding name followed by `;` separated details, details are ignored.
+    pub fn from_info(info: &str) -> Option<Self> {
+        let info = info.trim_end_matches('\0');
+        Self::from_name(info.split(';').next().unwrap_or(info))
+    }
+
+    /// Detailed info sent in `XIM_ENCODING_NEGOTIATION`, see [`TextEncoding::from_info`]
+    pub fn info(self) -> String {
+        let mut info = String::from(self.name());
+        info.push(';');
+        info
+    }
+
+    /// Name sent in `XIM_ENCODING_NEGOTIATION`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::CompoundText => "COMPOUND_TEXT",
+            Self::Utf8String => "UTF8_STRING",
+            Self::Charset(encoding) => encoding.name(),
+        }
+    }
 
-                    // CN
-                    Some(0x41) => Err(DecodeError::UnsupportedEncoding),
+    /// Encode `text`, `strategy` is only used for `COMPOUND_TEXT`
+    ///
+    /// Characters the charset doesn't have are replaced with `?`.
+    pub fn encode(self, text: &str, strategy: EncodeStrategy) -> Vec<u8> {
+        let encoding = match self {
+            Self::CompoundText => return utf8_to_compound_text_with(text, strategy),
+            Self::Utf8String => return text.as_bytes().to_vec(),
+            Self::Charset(encoding) => encoding,
+        };
 
-                    // KR
-                    Some(0x43) => Err(DecodeError::UnsupportedEncoding),
+        let mut encoder = encoding.new_encoder();
+        let mut ret = Vec::with_capacity(text.len());
+        let mut text = text;
 
-                    _ => Err(DecodeError::InvalidEncoding),
-                },
-                // Invalid encode
-                _ => Err(DecodeError::InvalidEncoding),
+        loop {
+            let (result, read) =
+                encoder.encode_from_utf8_to_vec_without_replacement(text, &mut ret, true);
+            text = &text[read..];
+
+            match result {
+                EncoderResult::InputEmpty => return ret,
+                EncoderResult::OutputFull => ret.reserve(
+                    encoder
+                        .max_buffer_length_from_utf8_without_replacement(text.len())
+                        .unwrap_or_default()
+                        .max(4),
+                ),
+                EncoderResult::Unmappable(_) => ret.push(b'?'),
             }
         }
-        // unescaped string
-        Some(_) => Ok(String::from_utf8(bytes.to_vec())?),
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> Result<String, DecodeError> {
+        match self {
+            Self::CompoundText => compound_text_to_utf8(bytes),
+            Self::Utf8String => Ok(String::from_utf8(bytes.to_vec())?),
+            Self::Charset(encoding) => encoding
+                .decode_without_bom_handling_and_without_replacement(bytes)
+                .map(|text| text.into_owned())
+                .ok_or(DecodeError::InvalidEncoding),
+        }
+    }
+
+    /// Decode `bytes`, invalid sequences are replaced with U+FFFD
+    pub fn decode_lossy(self, bytes: &[u8]) -> String {
+        match self {
+            Self::CompoundText => compound_text_to_utf8_lossy(bytes),
+            Self::Utf8String => String::from_utf8_lossy(bytes).into_owned(),
+            Self::Charset(encoding) => encoding.decode_without_bom_handling(bytes).0.into_owned(),
+        }
     }
 }
 
+/// Charset names of glibc locales which aren't WHATWG labels
+fn locale_charset(charset: &str) -> Option<&'static Encoding> {
+    let encoding = match charset.to_ascii_lowercase().replace('-', "").as_str() {
+        "eucjp" => encoding_rs::EUC_JP,
+        "euckr" => encoding_rs::EUC_KR,
+        "euccn" | "gb2312" => encoding_rs::GBK,
+        "big5hkscs" => encoding_rs::BIG5,
+        "sjis" | "shiftjis" => encoding_rs::SHIFT_JIS,
+        _ => return None,
+    };
+
+    Some(encoding)
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -179,4 +999,143 @@ mod tests {
         const COMP: &[u8] = &[27, 36, 40, 66, 69, 108, 53, 126];
         assert_eq!(crate::compound_text_to_utf8(COMP).unwrap(), UTF8);
     }
+
+    #[test]
+    fn legacy_encode() {
+        use crate::{CjkCharset, EncodeStrategy};
+
+        const UTF8: &str = "é東京가\u{1F600}a";
+        let comp =
+            crate::utf8_to_compound_text_with(UTF8, EncodeStrategy::Legacy(CjkCharset::Jis0208));
+        assert_eq!(
+            comp,
+            [
+                0xE9, 0x1B, 0x24, 0x29, 0x42, 0xC5, 0xEC, 0xB5, 0xFE, 0x1B, 0x24, 0x29, 0x43, 0xB0,
+                0xA1, 0x1B, 0x25, 0x47, 0xF0, 0x9F, 0x98, 0x80, 0x1B, 0x25, 0x40, b'a',
+            ]
+        );
+        assert_eq!(crate::compound_text_to_utf8(&comp).unwrap(), UTF8);
+
+        // 中 is in every set so the preferred one wins
+        assert_eq!(
+            crate::utf8_to_compound_text_with("中", EncodeStrategy::Legacy(CjkCharset::Gb2312)),
+            [0x1B, 0x24, 0x29, 0x41, 0xD6, 0xD0]
+        );
+    }
+
+    #[test]
+    fn mixed_segments() {
+        // latin1 right half is designated by default
+        assert_eq!(crate::compound_text_to_utf8(b"caf\xe9").unwrap(), "café");
+
+        const COMP: &[u8] = &[
+            b'a', 0x1B, 0x2D, 0x4C, 0xB0, // ISO 8859-5 `А`
+            0x1B, 0x24, 0x29, 0x43, 0xB0, 0xA1, // KSC5601 `가` in GR
+            0x1B, 0x24, 0x28, 0x41, 0x56, 0x50, // GB2312 `中` in GL
+            0x1B, 0x28, 0x42, b'b', // back to ASCII
+            0x1B, 0x29, 0x49, 0xB1, // JIS X 0201 katakana `ｱ`
+            0x1B, 0x25, 0x47, 0xF0, 0x9F, 0x98, 0x80, 0x1B, 0x25, 0x40, // utf8 segment
+            b'\n',
+        ];
+        assert_eq!(
+            crate::compound_text_to_utf8(COMP).unwrap(),
+            "aА가中bｱ\u{1F600}\n"
+        );
+    }
+
+    #[test]
+    fn extended_segment() {
+        const COMP: &[u8] = &[
+            b'x', 0x1B, 0x25, 0x2F, 0x32, 0x80, 0x89, b'b', b'i', b'g', b'5', b'-', b'0', 0x02,
+            0xA4, 0xA4, 0x1B, 0x2D, 0x41, 0xE9,
+        ];
+        assert_eq!(crate::compound_text_to_utf8(COMP).unwrap(), "x中é");
+    }
+
+    #[test]
+    fn text_encoding() {
+        use crate::{EncodeStrategy, TextEncoding};
+
+        let euc_jp = TextEncoding::Charset(encoding_rs::EUC_JP);
+
+        assert_eq!(
+            TextEncoding::from_name("COMPOUND_TEXT"),
+            Some(TextEncoding::CompoundText)
+        );
+        assert_eq!(
+            TextEncoding::from_name("UTF8_STRING"),
+            Some(TextEncoding::Utf8String)
+        );
+        assert_eq!(TextEncoding::from_name("ja_JP.eucJP"), Some(euc_jp));
+        assert_eq!(TextEncoding::from_name("EUC-JP"), Some(euc_jp));
+        assert_eq!(TextEncoding::from_name("foo"), None);
+        assert_eq!(TextEncoding::from_info(&euc_jp.info()), Some(euc_jp));
+        assert_eq!(TextEncoding::from_info("EUC-JP;foo=bar"), Some(euc_jp));
+        assert_eq!(TextEncoding::from_info("foo;EUC-JP"), None);
+
+        let bytes = euc_jp.encode("東京가", EncodeStrategy::Utf8);
+        assert_eq!(bytes, [0xC5, 0xEC, 0xB5, 0xFE, b'?']);
+        assert_eq!(euc_jp.decode(&bytes).unwrap(), "東京?");
+        assert_eq!(euc_jp.decode_lossy(&[0xC5]), "\u{FFFD}");
+    }
+
+    #[test]
+    fn lossy() {
+        const COMP: &[u8] = &[
+            b'a', 0x1B, 0x24, 0x28, 0x47, 0x21, 0x21, 0x21, 0x21, // unsupported CNS 11643
+            0x1B, 0x28, 0x42, b'b', 0x1B, 0x23, 0x40, b'c', // invalid escape
+            0x1B, 0x25, 0x47, b'd', 0xFF, 0x1B, 0x25, 0x40, // invalid utf8
+            0x1B, 0x24, 0x29, 0x42, 0xC5, 0xEC, 0xB5, // truncated JIS X 0208
+        ];
+        assert_eq!(
+            crate::compound_text_to_utf8_lossy(COMP),
+            "a\u{FFFD}\u{FFFD}b\u{FFFD}cd\u{FFFD}東\u{FFFD}"
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(
+            crate::compound_text_to_utf8(&[0x1B, 0x24, 0x28, 0x47, 0x21, 0x21]),
+            Err(crate::DecodeError::UnsupportedEncoding)
+        ));
+        assert!(matches!(
+            crate::compound_text_to_utf8(&[0x1B, 0x24, 0x28, 0x42, 0x21]),
+            Err(crate::DecodeError::InvalidEncoding)
+        ));
+        assert!(matches!(
+            crate::compound_text_to_utf8(&[0x1B, 0x25, 0x47, 0xFF, 0x1B, 0x25, 0x40]),
+            Err(crate::DecodeError::InvalidEncoding)
+        ));
+    }
+
+    #[test]
+    fn streaming() {
+        const COMP: &[u8] = &[
+            b'a', 0x1B, 0x24, 0x29, 0x43, 0xB0, 0xA1, // KSC5601 `가` in GR
+            0x1B, 0x25, 0x47, 0xF0, 0x9F, 0x98, 0x80, 0x1B, 0x25, 0x40, // utf8 segment
+            0x1B, 0x25, 0x2F, 0x32, 0x80, 0x89, b'b', b'i', b'g', b'5', b'-', b'0', 0x02, 0xA4,
+            0xA4, // extended segment
+            0x9B, 0x31, 0x5D, b'b', // directionality
+        ];
+
+        for chunk in 1..COMP.len() {
+            let mut decoder = crate::Decoder::new();
+            let mut out = alloc::string::String::new();
+
+            for bytes in COMP.chunks(chunk) {
+                decoder.decode_into(bytes, &mut out).unwrap();
+            }
+            decoder.finish(&mut out).unwrap();
+
+            assert_eq!(out, "a가\u{1F600}中b");
+        }
+
+        let mut out = alloc::string::String::new();
+        crate::CText::new("가나다").encode_into(&mut out).unwrap();
+        assert_eq!(
+            crate::compound_text_to_utf8(out.as_bytes()).unwrap(),
+            "가나다"
+        );
+    }
 }
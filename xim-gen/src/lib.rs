@@ -289,6 +289,21 @@ impl XimFormat {
         writeln!(out, "}}")?;
         // fn name
         writeln!(out, "}}")?;
+
+        for id in ["input_method_id", "input_context_id"] {
+            writeln!(out, "pub fn {}(&self) -> Option<u16> {{", id)?;
+            writeln!(out, "match self {{")?;
+            for (name, req) in self.requests.iter() {
+                if req.body.iter().any(|field| field.name == id) {
+                    writeln!(out, "Request::{} {{ {}, .. }} => Some(*{}),", name, id, id)?;
+                }
+            }
+            writeln!(out, "_ => None,")?;
+            // match
+            writeln!(out, "}}")?;
+            // fn id
+            writeln!(out, "}}")?;
+        }
         // impl Request
         writeln!(out, "}}")?;
 
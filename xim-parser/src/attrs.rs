@@ -1,4 +1,9 @@
-use crate::{Attr, AttrType, AttributeName};
+use alloc::vec::Vec;
+
+use crate::{
+    Attr, AttrType, Attribute, AttributeName, Endian, FontSet, HotKeyTriggers, InputStyleList,
+    Point, ReadError, Rectangle, XimWrite,
+};
 
 macro_rules! define_attrs {
     ($(($name:ident, $attr_name:expr, $ty:expr),)+) => {
@@ -44,5 +49,86 @@ define_attrs! {
     (BACKGROUND_PIXMAP, AttributeName::BackgroundPixmap, AttrType::Long),
     (SPOT_LOCATION, AttributeName::SpotLocation, AttrType::XPoint),
     (LINE_SPACE, AttributeName::LineSpace, AttrType::Long),
+    (CURSOR, AttributeName::Cursor, AttrType::Long),
+    (RESOURCE_NAME, AttributeName::ResourceName, AttrType::Char),
+    (RESOURCE_CLASS, AttributeName::ResourceClass, AttrType::Char),
+    (PREEDIT_STATE, AttributeName::PreeditState, AttrType::Long),
+    (RESET_STATE, AttributeName::ResetState, AttrType::Long),
+    (HOT_KEY, AttributeName::HotKey, AttrType::HotkeyTriggers),
+    (HOT_KEY_STATE, AttributeName::HotKeyState, AttrType::Long),
+    // Values lists has no type in the spec, they are sent as `ValuesList` bytes
+    (QUERY_IM_VALUES_LIST, AttributeName::QueryIMValuesList, AttrType::Char),
+    (QUERY_IC_VALUES_LIST, AttributeName::QueryICValuesList, AttrType::Char),
+    (DESTROY_CALLBACK, AttributeName::DestroyCallback, AttrType::Long),
+    (VISIBLE_POSITION, AttributeName::VisiblePosition, AttrType::Long),
     (SEPARATOR_OF_NESTED_LIST, AttributeName::SeparatorofNestedList, AttrType::Separator),
 }
+
+/// Value of an attribute decoded by its [`AttrType`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AttributeValue {
+    Separator,
+    Byte(u8),
+    Word(u16),
+    Long(u32),
+    Char(Vec<u8>),
+    Window(u32),
+    Style(InputStyleList),
+    Rectangle(Rectangle),
+    Point(Point),
+    FontSet(FontSet),
+    HotKeyTriggers(HotKeyTriggers),
+    NestedList(Vec<(AttributeName, AttributeValue)>),
+    /// Value of a type that has no typed representation like `StringConversion`
+    Raw(Vec<u8>),
+}
+
+impl AttributeValue {
+    /// Decode `value` of type `ty`, `lookup` finds attributes inside nested lists by id
+    pub fn read<F>(
+        ty: AttrType,
+        value: &[u8],
+        endian: Endian,
+        lookup: &F,
+    ) -> Result<Self, ReadError>
+    where
+        F: Fn(u16) -> Option<Attr>,
+    {
+        Ok(match ty {
+            AttrType::Separator => Self::Separator,
+            AttrType::Byte => Self::Byte(crate::read_with_endian(value, endian)?),
+            AttrType::Word => Self::Word(crate::read_with_endian(value, endian)?),
+            AttrType::Long | AttrType::PreeditState | AttrType::ResetState => {
+                Self::Long(crate::read_with_endian(value, endian)?)
+            }
+            AttrType::Char => Self::Char(value.to_vec()),
+            AttrType::Window => Self::Window(crate::read_with_endian(value, endian)?),
+            AttrType::Style => Self::Style(crate::read_with_endian(value, endian)?),
+            AttrType::XRectangle => Self::Rectangle(crate::read_with_endian(value, endian)?),
+            AttrType::XPoint => Self::Point(crate::read_with_endian(value, endian)?),
+            AttrType::XFontSet => Self::FontSet(crate::read_with_endian(value, endian)?),
+            AttrType::HotkeyTriggers => {
+                Self::HotKeyTriggers(crate::read_with_endian(value, endian)?)
+            }
+            AttrType::StringConversion => Self::Raw(value.to_vec()),
+            AttrType::NestedList => {
+                let mut out = Vec::new();
+                let mut b = value;
+
+                while !b.is_empty() {
+                    let attr: Attribute = crate::read_with_endian(b, endian)?;
+                    b = &b[attr.size().min(b.len())..];
+
+                    // Separators and unknown ids carry no value
+                    if let Some(Attr { ty, name, .. }) = lookup(attr.id) {
+                        if ty != AttrType::Separator {
+                            out.push((name, Self::read(ty, &attr.value, endian, lookup)?));
+                        }
+                    }
+                }
+
+                Self::NestedList(out)
+            }
+        })
+    }
+}
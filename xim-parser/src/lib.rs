@@ -22,25 +22,32 @@ mod parser;
 pub use parser::*;
 
 pub fn write_extend_vec(f: impl XimWrite, out: &mut Vec<u8>) {
+    write_extend_vec_with_endian(f, out, Endian::NATIVE)
+}
+
+pub fn write_extend_vec_with_endian(f: impl XimWrite, out: &mut Vec<u8>, endian: Endian) {
     let from = out.len();
     out.extend(core::iter::repeat(0).take(f.size()));
-    f.write(&mut Writer::new(&mut out[from..]));
+    f.write(&mut Writer::with_endian(&mut out[from..], endian));
 }
 
 pub fn write_to_vec(f: impl XimWrite) -> Vec<u8> {
+    write_to_vec_with_endian(f, Endian::NATIVE)
+}
+
+pub fn write_to_vec_with_endian(f: impl XimWrite, endian: Endian) -> Vec<u8> {
     let mut out: Vec<u8> = core::iter::repeat(0).take(f.size()).collect();
-    f.write(&mut Writer::new(&mut out));
+    f.write(&mut Writer::with_endian(&mut out, endian));
     out
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{parser::*, write_to_vec};
+    use crate::{attrs::AttributeValue, parser::*, write_to_vec, write_to_vec_with_endian};
     use alloc::vec;
     use alloc::vec::Vec;
     use pretty_assertions::assert_eq;
 
-    #[cfg(target_endian = "little")]
     #[test]
     fn read_connect_req() {
         let req: Request = read(b"\x01\x00\x00\x00\x6c\x00\x00\x00\x00\x00\x00\x00").unwrap();
@@ -48,7 +55,7 @@ mod tests {
         assert_eq!(
             req,
             Request::Connect {
-                endian: Endian::Native,
+                endian: Endian::Little,
                 client_auth_protocol_names: vec![],
                 client_minor_protocol_version: 0,
                 client_major_protocol_version: 0,
@@ -56,6 +63,50 @@ mod tests {
         );
     }
 
+    #[test]
+    fn read_big_endian_connect_req() {
+        let req: Request = read(b"\x01\x00\x00\x02\x42\x00\x00\x01\x00\x00\x00\x00").unwrap();
+
+        assert_eq!(
+            req,
+            Request::Connect {
+                endian: Endian::Big,
+                client_auth_protocol_names: vec![],
+                client_minor_protocol_version: 0,
+                client_major_protocol_version: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn big_endian_set_event_mask() {
+        let req = Request::SetEventMask {
+            input_method_id: 2,
+            input_context_id: 1,
+            forward_event_mask: 3,
+            synchronous_event_mask: 4294967292,
+        };
+        let out = write_to_vec_with_endian(&req, Endian::Big);
+        assert_eq!(
+            out,
+            [37, 0, 0, 3, 0, 2, 0, 1, 0, 0, 0, 3, 255, 255, 255, 252]
+        );
+        assert_eq!(req, read_with_endian::<Request>(&out, Endian::Big).unwrap());
+    }
+
+    #[test]
+    fn big_endian_spot_attr() {
+        let value = [0, 4, 0, 4, 0, 3, 255, 254];
+
+        let attr = read_with_endian::<Attribute>(&value, Endian::Big).unwrap();
+
+        assert_eq!(attr.id, 4);
+        assert_eq!(
+            read_with_endian::<Point>(&attr.value, Endian::Big).unwrap(),
+            Point { x: 3, y: -2 }
+        );
+    }
+
     #[test]
     fn read_open() {
         let req = read::<Request>(&[
@@ -111,6 +162,123 @@ mod tests {
         write_to_vec(req);
     }
 
+    #[test]
+    fn commit_sync_flags() {
+        for syncronous in [false, true] {
+            let datas = [
+                CommitData::Keysym {
+                    keysym: 0xff08,
+                    syncronous,
+                },
+                CommitData::Chars {
+                    commited: xim_ctext::utf8_to_compound_text("맘"),
+                    syncronous,
+                },
+                CommitData::Both {
+                    keysym: 0xff08,
+                    commited: xim_ctext::utf8_to_compound_text("맘"),
+                    syncronous,
+                },
+            ];
+
+            for data in datas {
+                let req = Request::Commit {
+                    input_method_id: 1,
+                    input_context_id: 1,
+                    data,
+                };
+                let out = write_to_vec(&req);
+                assert_eq!(out.len(), req.size());
+                assert_eq!(req, read::<Request>(&out).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn status_draw() {
+        let req = Request::StatusDraw {
+            input_method_id: 1,
+            input_context_id: 2,
+            content: StatusContent::Text(StatusTextContent {
+                status: PreeditDrawStatus::empty(),
+                status_string: xim_ctext::utf8_to_compound_text("한"),
+                feedbacks: vec![Feedback::Reverse],
+            }),
+        };
+        let out = write_to_vec(&req);
+        assert_eq!(out.len(), req.size());
+        assert_eq!(req, read::<Request>(&out).unwrap());
+    }
+
+    #[test]
+    fn hot_key_triggers() {
+        let triggers = HotKeyTriggers {
+            triggers: vec![
+                (
+                    TriggerKey {
+                        keysym: 0x20,
+                        modifier: 4,
+                        modifier_mask: 4,
+                    },
+                    HotKeyState::On,
+                ),
+                (
+                    TriggerKey {
+                        keysym: 0xff2a,
+                        modifier: 0,
+                        modifier_mask: 0,
+                    },
+                    HotKeyState::Off,
+                ),
+            ],
+        };
+        let out = write_to_vec(&triggers);
+        assert_eq!(out.len(), triggers.size());
+        assert_eq!(triggers, read::<HotKeyTriggers>(&out).unwrap());
+    }
+
+    #[test]
+    fn values_list() {
+        let list = ValuesList {
+            names: vec!["queryInputStyle".into(), "resourceName".into()],
+        };
+        let out = write_to_vec(&list);
+        assert_eq!(out.len(), list.size());
+        assert_eq!(out.len() % 4, 0);
+        assert_eq!(list, read::<ValuesList>(&out).unwrap());
+    }
+
+    #[test]
+    fn nested_attribute_value() {
+        let spot = Attribute {
+            id: 15,
+            value: write_to_vec_with_endian(Point { x: 3, y: -1 }, Endian::Big),
+        };
+        let line_space = Attribute {
+            id: 16,
+            value: write_to_vec_with_endian(12u32, Endian::Big),
+        };
+        let mut value = write_to_vec_with_endian(&spot, Endian::Big);
+        value.extend(write_to_vec_with_endian(&line_space, Endian::Big));
+
+        let attrs = match open_reply_value() {
+            Request::OpenReply { ic_attrs, .. } => ic_attrs,
+            _ => unreachable!(),
+        };
+        let lookup = |id| attrs.iter().find(|attr| attr.id == id).cloned();
+
+        assert_eq!(
+            AttributeValue::read(AttrType::NestedList, &value, Endian::Big, &lookup).unwrap(),
+            AttributeValue::NestedList(vec![
+                (
+                    AttributeName::SpotLocation,
+                    AttributeValue::Point(Point { x: 3, y: -1 })
+                ),
+                (AttributeName::LineSpace, AttributeValue::Long(12)),
+            ])
+        );
+    }
+
     #[test]
     fn set_event_mask() {
         let req = Request::SetEventMask {
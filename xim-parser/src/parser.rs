@@ -13,25 +13,49 @@ pub fn read<T>(b: &[u8]) -> Result<T, ReadError>
 where
     T: XimRead,
 {
-    T::read(&mut Reader::new(b))
+    read_with_endian(b, Endian::NATIVE)
+}
+
+pub fn read_with_endian<T>(b: &[u8], endian: Endian) -> Result<T, ReadError>
+where
+    T: XimRead,
+{
+    T::read(&mut Reader::with_endian(b, endian))
 }
 
 pub fn write<T>(val: T, out: &mut [u8])
 where
     T: XimWrite,
 {
-    val.write(&mut Writer::new(out));
+    write_with_endian(val, out, Endian::NATIVE);
+}
+
+pub fn write_with_endian<T>(val: T, out: &mut [u8], endian: Endian)
+where
+    T: XimWrite,
+{
+    val.write(&mut Writer::with_endian(out, endian));
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+/// Byte order of a XIM connection, negotiated by the client in `Connect`
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 #[repr(u8)]
 pub enum Endian {
+    Big = 0x42,
+    Little = 0x6c,
+}
+
+impl Endian {
     #[cfg(target_endian = "little")]
-    Native = 0x6c,
+    pub const NATIVE: Self = Self::Little;
     #[cfg(target_endian = "big")]
-    Native = 0x42,
-    // Big = 0x42,
-    // Little = 0x6c,
+    pub const NATIVE: Self = Self::Big;
+}
+
+impl Default for Endian {
+    fn default() -> Self {
+        Self::NATIVE
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -98,7 +122,6 @@ pub enum ReadError {
     EndOfStream,
     InvalidData(&'static str, String),
     Utf8Error(alloc::string::FromUtf8Error),
-    NotNativeEndian,
 }
 
 impl From<alloc::string::FromUtf8Error> for ReadError {
@@ -113,7 +136,6 @@ impl fmt::Display for ReadError {
             Self::EndOfStream => write!(f, "End of Stream"),
             Self::InvalidData(name, reason) => write!(f, "Invalid Data {}: {}", name, reason),
             Self::Utf8Error(e) => write!(f, "Not a Utf8 text {}", e),
-            Self::NotNativeEndian => write!(f, "Not a native endian"),
         }
     }
 }
@@ -135,16 +157,27 @@ fn with_pad4(len: usize) -> usize {
 pub struct Reader<'b> {
     bytes: &'b [u8],
     start: usize,
+    endian: Endian,
 }
 
 impl<'b> Reader<'b> {
     pub fn new(bytes: &'b [u8]) -> Self {
+        Self::with_endian(bytes, Endian::NATIVE)
+    }
+
+    pub fn with_endian(bytes: &'b [u8], endian: Endian) -> Self {
         Self {
             bytes,
             start: bytes.as_ptr() as usize,
+            endian,
         }
     }
 
+    #[inline(always)]
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
     fn ptr_offset(&self) -> usize {
         self.bytes.as_ptr() as usize - self.start
     }
@@ -175,22 +208,34 @@ impl<'b> Reader<'b> {
 
     pub fn i16(&mut self) -> Result<i16, ReadError> {
         let bytes = self.consume(2)?.try_into().unwrap();
-        Ok(i16::from_ne_bytes(bytes))
+        Ok(match self.endian {
+            Endian::Big => i16::from_be_bytes(bytes),
+            Endian::Little => i16::from_le_bytes(bytes),
+        })
     }
 
     pub fn u16(&mut self) -> Result<u16, ReadError> {
         let bytes = self.consume(2)?.try_into().unwrap();
-        Ok(u16::from_ne_bytes(bytes))
+        Ok(match self.endian {
+            Endian::Big => u16::from_be_bytes(bytes),
+            Endian::Little => u16::from_le_bytes(bytes),
+        })
     }
 
     pub fn u32(&mut self) -> Result<u32, ReadError> {
         let bytes = self.consume(4)?.try_into().unwrap();
-        Ok(u32::from_ne_bytes(bytes))
+        Ok(match self.endian {
+            Endian::Big => u32::from_be_bytes(bytes),
+            Endian::Little => u32::from_le_bytes(bytes),
+        })
     }
 
     pub fn i32(&mut self) -> Result<i32, ReadError> {
         let bytes = self.consume(4)?.try_into().unwrap();
-        Ok(i32::from_ne_bytes(bytes))
+        Ok(match self.endian {
+            Endian::Big => i32::from_be_bytes(bytes),
+            Endian::Little => i32::from_le_bytes(bytes),
+        })
     }
 
     pub fn consume(&mut self, len: usize) -> Result<&'b [u8], ReadError> {
@@ -207,11 +252,25 @@ impl<'b> Reader<'b> {
 pub struct Writer<'b> {
     out: &'b mut [u8],
     idx: usize,
+    endian: Endian,
 }
 
 impl<'b> Writer<'b> {
     pub fn new(out: &'b mut [u8]) -> Self {
-        Self { out, idx: 0 }
+        Self::with_endian(out, Endian::NATIVE)
+    }
+
+    pub fn with_endian(out: &'b mut [u8], endian: Endian) -> Self {
+        Self {
+            out,
+            idx: 0,
+            endian,
+        }
+    }
+
+    #[inline(always)]
+    pub fn endian(&self) -> Endian {
+        self.endian
     }
 
     pub fn write_u8(&mut self, b: u8) {
@@ -241,7 +300,7 @@ pub trait XimWrite {
     fn size(&self) -> usize;
 }
 
-impl<'a, T> XimWrite for &'a T
+impl<T> XimWrite for &T
 where
     T: XimWrite,
 {
@@ -259,11 +318,16 @@ impl XimRead for Endian {
     fn read(reader: &mut Reader) -> Result<Self, ReadError> {
         let n = u8::read(reader)?;
 
-        if n == Endian::Native as u8 {
-            Ok(Self::Native)
-        } else {
-            Err(ReadError::NotNativeEndian)
-        }
+        let endian = match n {
+            0x42 => Self::Big,
+            0x6c => Self::Little,
+            _ => return Err(reader.invalid_data("Endian", n)),
+        };
+
+        // The rest of the message is encoded with this byte order
+        reader.endian = endian;
+
+        Ok(endian)
     }
 }
 
@@ -324,7 +388,7 @@ impl XimRead for CommitData {
                 reader.pad4()?;
                 Ok(Self::Chars {
                     commited: bytes.to_vec(),
-                    syncronous: ty == 5,
+                    syncronous: ty == 3,
                 })
             }
             4 | 5 => {
@@ -332,7 +396,7 @@ impl XimRead for CommitData {
                 let keysym = reader.u32()?;
                 Ok(Self::Keysym {
                     keysym,
-                    syncronous: ty == 3,
+                    syncronous: ty == 5,
                 })
             }
             6 | 7 => {
@@ -405,8 +469,8 @@ impl XimRead for HotKeyTriggers {
             out.push((TriggerKey::read(reader)?, HotKeyState::Off));
         }
 
-        for _ in 0..n {
-            out[n].1 = HotKeyState::read(reader)?;
+        for (_, state) in out.iter_mut() {
+            *state = HotKeyState::read(reader)?;
         }
 
         Ok(Self { triggers: out })
@@ -427,7 +491,7 @@ impl XimWrite for HotKeyTriggers {
     }
 
     fn size(&self) -> usize {
-        self.triggers.len() * 8 + 4
+        self.triggers.len() * 16 + 4
     }
 }
 
@@ -473,7 +537,10 @@ macro_rules! impl_int {
 
         impl XimWrite for $ty {
             fn write(&self, writer: &mut Writer) {
-                writer.write(&self.to_ne_bytes())
+                match writer.endian {
+                    Endian::Big => writer.write(&self.to_be_bytes()),
+                    Endian::Little => writer.write(&self.to_le_bytes()),
+                }
             }
 
             fn size(&self) -> usize {
@@ -1058,7 +1125,7 @@ impl XimWrite for Rectangle {
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct StatusTextContent {
     pub status: PreeditDrawStatus,
-    pub status_string: String,
+    pub status_string: Vec<u8>,
     pub feedbacks: Vec<Feedback>,
 }
 impl XimRead for StatusTextContent {
@@ -1068,7 +1135,7 @@ impl XimRead for StatusTextContent {
             status_string: {
                 let inner = {
                     let len = u16::read(reader)?;
-                    String::from_utf8(reader.consume(len as usize)?.to_vec())?
+                    reader.consume(len as usize)?.to_vec()
                 };
                 reader.pad4()?;
                 inner
@@ -1090,7 +1157,7 @@ impl XimWrite for StatusTextContent {
     fn write(&self, writer: &mut Writer) {
         self.status.write(writer);
         (self.status_string.len() as u16).write(writer);
-        writer.write(self.status_string.as_bytes());
+        writer.write(&self.status_string);
         writer.write_pad4();
         ((self.feedbacks.iter().map(|e| e.size()).sum::<usize>() + 2 + 2 - 2 - 2) as u16)
             .write(writer);
@@ -1102,7 +1169,7 @@ impl XimWrite for StatusTextContent {
     fn size(&self) -> usize {
         let mut content_size = 0;
         content_size += self.status.size();
-        content_size += with_pad4(self.status_string.len() + 2 + 0 - 0);
+        content_size += with_pad4(self.status_string.len() + 2 - 0);
         content_size += self.feedbacks.iter().map(|e| e.size()).sum::<usize>() + 2 + 2;
         content_size
     }
@@ -1137,6 +1204,49 @@ impl XimWrite for TriggerKey {
     }
 }
 #[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ValuesList {
+    pub names: Vec<String>,
+}
+impl XimRead for ValuesList {
+    fn read(reader: &mut Reader) -> Result<Self, ReadError> {
+        Ok(Self {
+            names: {
+                let inner = {
+                    let mut out = Vec::new();
+                    let len = u16::read(reader)? as usize;
+                    let end = reader.cursor() - len;
+                    while reader.cursor() > end {
+                        out.push({
+                            let len = u8::read(reader)?;
+                            String::from_utf8(reader.consume(len as usize)?.to_vec())?
+                        });
+                    }
+                    out
+                };
+                reader.pad4()?;
+                inner
+            },
+        })
+    }
+}
+impl XimWrite for ValuesList {
+    fn write(&self, writer: &mut Writer) {
+        ((self.names.iter().map(|e| e.len() + 1 + 0).sum::<usize>() + 0 + 2 - 2 - 0) as u16)
+            .write(writer);
+        for elem in self.names.iter() {
+            (elem.len() as u8).write(writer);
+            writer.write(elem.as_bytes());
+        }
+        writer.write_pad4();
+    }
+    fn size(&self) -> usize {
+        let mut content_size = 0;
+        content_size +=
+            with_pad4(self.names.iter().map(|e| e.len() + 1 + 0).sum::<usize>() + 0 + 2 - 0);
+        content_size
+    }
+}
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct XEvent {
     pub response_type: u8,
     pub detail: u8,
@@ -1653,6 +1763,235 @@ impl Request {
             Request::UnsetIcFocus { .. } => "UnsetIcFocus",
         }
     }
+    pub fn input_method_id(&self) -> Option<u16> {
+        match self {
+            Request::Close {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::CloseReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::Commit {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::CreateIc {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::CreateIcReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::DestroyIc {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::DestroyIcReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::EncodingNegotiation {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::EncodingNegotiationReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::Error {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::ForwardEvent {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::Geometry {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::GetIcValues {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::GetIcValuesReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::GetImValues {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::GetImValuesReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::OpenReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::PreeditCaret {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::PreeditCaretReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::PreeditDone {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::PreeditDraw {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::PreeditStart {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::PreeditStartReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::PreeditState {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::QueryExtension {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::QueryExtensionReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::RegisterTriggerKeys {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::ResetIc {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::ResetIcReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::SetEventMask {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::SetIcFocus {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::SetIcValues {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::SetIcValuesReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::SetImValues {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::SetImValuesReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::StatusDone {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::StatusDraw {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::StatusStart {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::Sync {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::SyncReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::TriggerNotify {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::TriggerNotifyReply {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            Request::UnsetIcFocus {
+                input_method_id, ..
+            } => Some(*input_method_id),
+            _ => None,
+        }
+    }
+    pub fn input_context_id(&self) -> Option<u16> {
+        match self {
+            Request::Commit {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::CreateIcReply {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::DestroyIc {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::DestroyIcReply {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::Error {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::ForwardEvent {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::Geometry {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::GetIcValues {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::GetIcValuesReply {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::PreeditCaret {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::PreeditCaretReply {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::PreeditDone {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::PreeditDraw {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::PreeditStart {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::PreeditStartReply {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::PreeditState {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::ResetIc {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::ResetIcReply {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::SetEventMask {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::SetIcFocus {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::SetIcValues {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::SetIcValuesReply {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::StatusDone {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::StatusDraw {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::StatusStart {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::Sync {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::SyncReply {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::TriggerNotify {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::TriggerNotifyReply {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            Request::UnsetIcFocus {
+                input_context_id, ..
+            } => Some(*input_context_id),
+            _ => None,
+        }
+    }
 }
 impl XimRead for Request {
     fn read(reader: &mut Reader) -> Result<Self, ReadError> {